@@ -1,11 +1,146 @@
 #[macro_use]
 extern crate criterion;
 
-use criterion::Criterion;
+use std::collections::HashMap;
+use std::path::PathBuf;
 
-fn dummy_bench(c: &mut Criterion) {
-    c.bench_function("dummy", |b| b.iter(|| 2 + 2));
+use criterion::{black_box, Criterion};
+
+use zws::request::Method;
+use zws::router::{Lookup, Router};
+
+const ROUTES: &[&str] = &[
+    "GET /",
+    "GET /hello",
+    "GET /user/:fname/:lname/:age",
+    "GET /api/v1/users",
+    "POST /api/v1/users",
+    "GET /api/v1/users/:id",
+    "GET /api/v1/users/:id/posts",
+    "GET /api/v1/posts/:id/comments",
+];
+
+const PATHS: &[&str] = &[
+    "/hello",
+    "/user/jane/doe/42",
+    "/api/v1/users/1234/posts",
+    "/css/site/style.css",
+];
+
+/// LegacyAction mirrors the Action used by the HashMap router, where path
+/// is the static prefix of the route and params maps names to indexes.
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct LegacyAction {
+    method: Method,
+    path: String,
+    params: Option<Vec<(String, usize)>>,
+}
+
+impl LegacyAction {
+    fn parse(s: &str) -> LegacyAction {
+        let mut parts = s.split(' ');
+        let method = parts.next().unwrap().parse().unwrap();
+        let route = parts.next().unwrap();
+        let mut path = String::new();
+        let mut params = Vec::new();
+        for (index, part) in route.split('/').enumerate().skip(1) {
+            if let Some(name) = part.strip_prefix(':') {
+                params.push((name.to_string(), index));
+            } else if params.is_empty() && !part.is_empty() {
+                path.push('/');
+                path.push_str(part);
+            }
+        }
+        if path.is_empty() {
+            path.push('/');
+        }
+        LegacyAction {
+            method,
+            path,
+            params: if params.is_empty() {
+                None
+            } else {
+                Some(params)
+            },
+        }
+    }
+}
+
+/// legacy_lookup reproduces the per-request work of the HashMap router:
+/// collecting the actions, matching them by popping path segments, extracting
+/// params and then looking up the handler again the same way.
+fn legacy_lookup(router: &HashMap<LegacyAction, usize>, method: Method, path: &str) -> usize {
+    let actions: Vec<LegacyAction> = router.keys().cloned().collect();
+
+    let mut action = LegacyAction {
+        method,
+        path: path.to_string(),
+        params: None,
+    };
+    let mut action_path = PathBuf::from(path);
+    'WHILE: loop {
+        for a in &actions {
+            if a.method == method && a.path == action.path {
+                action = a.clone();
+                break 'WHILE;
+            }
+        }
+        if !action_path.pop() {
+            break;
+        }
+        action.path = action_path.to_string_lossy().to_string();
+    }
+
+    let mut params = HashMap::new();
+    if let Some(names) = &action.params {
+        let parts: Vec<&str> = path.split('/').collect();
+        for (name, index) in names {
+            if *index < parts.len() {
+                params.insert(name.to_string(), parts[*index].to_string());
+            }
+        }
+    }
+    black_box(&params);
+
+    let mut path = PathBuf::from(&action.path);
+    loop {
+        if let Some(h) = router.get(&action) {
+            return *h;
+        }
+        if !path.pop() {
+            return 0;
+        }
+        action.path = path.to_string_lossy().to_string();
+    }
+}
+
+fn router_bench(c: &mut Criterion) {
+    let mut legacy = HashMap::new();
+    let mut router = Router::new();
+    for (value, route) in ROUTES.iter().enumerate() {
+        let action: zws::Action = route.parse().unwrap();
+        router.insert(action.method, &action.path, value).unwrap();
+        legacy.insert(LegacyAction::parse(route), value);
+    }
+
+    c.bench_function("router: hashmap prefix walk", move |b| {
+        b.iter(|| {
+            for path in PATHS {
+                black_box(legacy_lookup(&legacy, Method::GET, path));
+            }
+        })
+    });
+
+    c.bench_function("router: radix tree", move |b| {
+        b.iter(|| {
+            for path in PATHS {
+                if let Lookup::Found(m) = router.lookup(Method::GET, path) {
+                    black_box((m.value, m.params.len()));
+                }
+            }
+        })
+    });
 }
 
-criterion_group!(benches, dummy_bench);
+criterion_group!(benches, router_bench);
 criterion_main!(benches);
@@ -1,27 +1,77 @@
 use std::env;
+use std::process;
+use std::time::Duration;
 
 use docopt::Docopt;
-use num_cpus;
 
 use zws::handlers::StaticFile;
-use zws::{Handler, Request, Response, Server};
+use zws::{
+    ClientVerify, Handler, HttpError, Mode, Request, Response, Server, TlsProfile, TlsVersion,
+};
 
 fn main() -> zws::Result<()> {
-    const USAGE: &'static str = "
-Usage: zws [-h] [-c CERT] [-k KEY] [-s SOCKET] [-t THREADS] [-w DIR]
+    const USAGE: &str = "
+Usage: zws [-h] [-c CERT]... [-k KEY]... [--client-ca FILE] [--client-verify MODE] [--tls-profile PROFILE] [--tls-min VERSION] [--tls-max VERSION] [--ciphers LIST] [--ciphersuites LIST] [--curves LIST] [--session-tickets SECS] [--key-log FILE] [-s SOCKET]... [-r SOCKET]... [--acme-dir DIR] [-t THREADS] [-w DIR] [--plaintext]
 
 Options:
     -h, --help
         Show this usage screen.
 
     -c CERT, --cert CERT
-        Path to PEM certificate file. [default: tls/dev/cert.pem]
+        Path to PEM certificate file. Repeat, with a -k for each, to serve
+        several, chosen by the server name the client asks for (SNI). The
+        first is the default. [default: tls/dev/cert.pem]
 
     -k KEY, --key KEY
-        Path to PEM key file. [default: tls/dev/key.pem]
+        Path to PEM key file of the certificate in the same position.
+        [default: tls/dev/key.pem]
+
+    --client-ca FILE
+        Path to PEM file of the CAs client certificates are verified against.
+
+    --client-verify MODE
+        Whether to ask clients for a certificate: none, optional, or required
+        to connect. Defaults to required with --client-ca, else none.
+
+    --tls-profile PROFILE
+        TLS versions and ciphers to start from, after Mozilla's server side
+        TLS recommendations: modern (TLS 1.3 only), intermediate, or custom
+        (intermediate with all TLS versions and the OpenSSL default ciphers).
+        [default: intermediate]
+
+    --tls-min VERSION
+        Oldest TLS version to accept: 1.0, 1.1, 1.2 or 1.3.
+
+    --tls-max VERSION
+        Newest TLS version to accept: 1.0, 1.1, 1.2 or 1.3.
+
+    --ciphers LIST
+        OpenSSL cipher list for TLS 1.2 and below.
+
+    --ciphersuites LIST
+        TLS 1.3 cipher suites, separated by colons.
+
+    --curves LIST
+        Key exchange groups, separated by colons, such as X25519:P-256.
+
+    --session-tickets SECS
+        Rotate the keys of TLS session tickets every SECS seconds, 0 to
+        disable session tickets. [default: 3600]
+
+    --key-log FILE
+        Append TLS secrets to FILE for Wireshark to decrypt the traffic, for
+        debugging only.
         
     -s SOCKET, --socket SOCKET
-        TCP socket to listen on. [default: 127.0.0.1:8443]
+        Socket to listen on, host:port or unix:PATH for a Unix domain socket.
+        Repeat to listen on several. [default: 127.0.0.1:8443]
+
+    -r SOCKET, --redirect SOCKET
+        TCP socket to listen on for plain HTTP, redirecting to HTTPS. Repeat
+        to listen on several.
+
+    --acme-dir DIR
+        Path to ACME HTTP-01 challenge files, served on the redirect socket.
 
     -t THREADS, --threads THREADS
         Number of threads for worker pool request handling.
@@ -29,11 +79,15 @@ Options:
 
     -w DIR, --webroot DIR
         Path to root of file serving area. [default: webroot]
+
+    --plaintext
+        Serve cleartext HTTP/2 (h2c) and HTTP/1.1 without TLS on the -s
+        sockets. The certificate and key are not used.
 ";
 
     let argv = env::args();
     let args = Docopt::new(USAGE)
-        .and_then(|d| d.argv(argv.into_iter()).parse())
+        .and_then(|d| d.argv(argv).parse())
         .unwrap_or_else(|e| e.exit());
 
     let mut threads: usize = args.get_str("--threads").parse().unwrap_or(0);
@@ -41,31 +95,116 @@ Options:
         threads = num_cpus::get();
     }
 
-    Server::new(
-        args.get_str("--cert"),
-        args.get_str("--key"),
-        args.get_str("--socket"),
-        threads,
-    )?
-    .add_handler("GET /hello", StringHandler::new("Hello"))?
-    .add_handler("GET /", StaticFile::with_cache(args.get_str("--webroot"))?)?
-    .add_handler_func("GET /user/:fname/:lname/:age", greeter_func)?
-    .run()
+    let mode = if args.get_bool("--plaintext") {
+        Mode::Plaintext
+    } else {
+        Mode::Tls
+    };
+    let mut builder = Server::builder();
+    #[cfg(unix)]
+    {
+        builder = builder.graceful_upgrade(true);
+    }
+    for socket in args.get_vec("--socket") {
+        builder = builder.listen(socket, mode);
+    }
+    for socket in args.get_vec("--redirect") {
+        builder = builder.http_redirect(socket);
+    }
+    if !args.get_str("--acme-dir").is_empty() {
+        builder = builder.acme_challenges(args.get_str("--acme-dir"));
+    }
+
+    let (certs, keys) = (args.get_vec("--cert"), args.get_vec("--key"));
+    if certs.len() != keys.len() {
+        exit("Give a key for each certificate: -c and -k go in pairs.");
+    }
+    for (cert, key) in certs.into_iter().zip(keys) {
+        builder = builder.tls(cert, key);
+    }
+    let client_ca = args.get_str("--client-ca");
+    let client_verify = match args.get_str("--client-verify") {
+        "" if client_ca.is_empty() => ClientVerify::None,
+        "" => ClientVerify::Required,
+        "none" => ClientVerify::None,
+        "optional" => ClientVerify::Optional,
+        "required" => ClientVerify::Required,
+        other => exit(&format!("Unknown --client-verify mode: {}.", other)),
+    };
+    if !client_ca.is_empty() {
+        builder = builder.client_ca(client_ca);
+    }
+    builder = builder.client_verify(client_verify);
+
+    builder = builder.tls_profile(match args.get_str("--tls-profile") {
+        "modern" => TlsProfile::Modern,
+        "intermediate" => TlsProfile::Intermediate,
+        "custom" => TlsProfile::Custom,
+        other => exit(&format!("Unknown --tls-profile: {}.", other)),
+    });
+    if !args.get_str("--tls-min").is_empty() {
+        builder = builder.min_tls_version(tls_version(args.get_str("--tls-min")));
+    }
+    if !args.get_str("--tls-max").is_empty() {
+        builder = builder.max_tls_version(tls_version(args.get_str("--tls-max")));
+    }
+    if !args.get_str("--ciphers").is_empty() {
+        builder = builder.ciphers(args.get_str("--ciphers"));
+    }
+    if !args.get_str("--ciphersuites").is_empty() {
+        builder = builder.ciphersuites(args.get_str("--ciphersuites"));
+    }
+    if !args.get_str("--curves").is_empty() {
+        builder = builder.curves(args.get_str("--curves"));
+    }
+    let rotation = match args.get_str("--session-tickets").parse() {
+        Ok(0) => None,
+        Ok(secs) => Some(Duration::from_secs(secs)),
+        Err(_) => exit("--session-tickets takes a number of seconds."),
+    };
+    builder = builder.session_tickets(rotation);
+    let key_log = args.get_str("--key-log");
+    if !key_log.is_empty() {
+        builder = builder.key_log(key_log);
+    }
+
+    builder
+        .reload_tls(true)
+        .threads(threads)
+        .handler("GET /hello", StringHandler::new("Hello"))?
+        .handler("GET /", StaticFile::with_cache(args.get_str("--webroot"))?)?
+        .try_handler_func("GET /user/:fname/:lname/:age{u32}", greeter_func)?
+        .name("user")?
+        .build()?
+        .run()
 }
 
-fn greeter_func(req: Request, mut resp: Response) -> Response {
-    if req.has_params() {
-        let fname = req.param("fname");
-        let lname = req.param("lname");
-        let age = req.param("age");
-        resp.set_body(format!(
-            "Hello {} {}. You are {} years old!",
-            fname, lname, age
-        ));
-    } else {
-        resp.set_body("Hello stranger!");
+/// exit prints msg and exits with a failure status.
+fn exit(msg: &str) -> ! {
+    eprintln!("{}", msg);
+    process::exit(1);
+}
+
+/// tls_version parses a TLS version option.
+fn tls_version(version: &str) -> TlsVersion {
+    match version {
+        "1.0" => TlsVersion::Tls1_0,
+        "1.1" => TlsVersion::Tls1_1,
+        "1.2" => TlsVersion::Tls1_2,
+        "1.3" => TlsVersion::Tls1_3,
+        other => exit(&format!("Unknown TLS version: {}.", other)),
     }
-    resp
+}
+
+fn greeter_func(req: Request, mut resp: Response) -> Result<Response, HttpError> {
+    let age: u32 = req.param_as("age")?;
+    resp.set_body(format!(
+        "Hello {} {}. You are {} years old!",
+        req.param("fname"),
+        req.param("lname"),
+        age
+    ));
+    Ok(resp)
 }
 
 struct StringHandler {
@@ -0,0 +1,547 @@
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use openssl::ssl::{HandshakeError, MidHandshakeSslStream, SslAcceptor, SslStream};
+
+use crate::h1;
+use crate::h2::{self, Settings, StreamId};
+use crate::listener::Socket;
+use crate::request::{Message, TlsInfo};
+use crate::response::{Body, Chunks, Response};
+use crate::tls;
+
+/// BODY_LOW is the amount of a streamed body buffered below which its next
+/// chunk is produced.
+const BODY_LOW: usize = 64 << 10;
+
+/// Produced is what producing the next chunk of a streamed body gave: the
+/// chunk, with the Chunks to produce the rest, the end of the body, or a
+/// failure, as when the iterator panicked.
+pub(crate) enum Produced {
+    Chunk(Chunks, Vec<u8>),
+    End,
+    Failed,
+}
+
+/// Session is the protocol state of a connection.
+enum Session {
+    H1(h1::Session),
+    H2(h2::Session),
+}
+
+impl Session {
+    /// recv handles bytes received, switching a cleartext HTTP/1.1 Session
+    /// to HTTP/2 when the client upgrades or starts with the HTTP/2 preface.
+    fn recv(
+        &mut self,
+        bytes: &[u8],
+        requests: &mut VecDeque<Message>,
+        settings: Settings,
+        max_body_size: usize,
+    ) -> Result<(), h2::Error> {
+        let h1::Upgrade {
+            output,
+            input,
+            request,
+        } = match self {
+            Session::H1(session) => match session.recv(bytes, requests) {
+                Some(upgrade) => upgrade,
+                None => return Ok(()),
+            },
+            Session::H2(session) => return session.recv(bytes, requests),
+        };
+
+        debug!("switching to h2c");
+        let mut session = h2_session(settings, max_body_size);
+        let result = match request {
+            Some((msg, http2_settings)) => {
+                let result = session.upgrade(output, &http2_settings);
+                requests.push_back(msg);
+                result
+            }
+            None => Ok(()),
+        }
+        .and_then(|_| session.recv(&input, requests));
+        *self = Session::H2(session);
+        result
+    }
+
+    /// respond queues a Response, returning the chunks of a streamed body,
+    /// to be sent with send_data.
+    fn respond(&mut self, resp: Response) -> Option<(StreamId, Chunks)> {
+        let (stream_id, headers, body) = resp.into_parts();
+        match (self, body) {
+            (Session::H1(session), Body::Full(body)) => session.respond(stream_id, &headers, body),
+            (Session::H2(session), Body::Full(body)) => session.respond(stream_id, &headers, body),
+            (session, Body::Chunks(chunks)) => {
+                match session {
+                    Session::H1(session) => session.respond_stream(stream_id, &headers),
+                    Session::H2(session) => session.respond_stream(stream_id, &headers),
+                }
+                return Some((stream_id, chunks));
+            }
+        }
+        None
+    }
+
+    fn send_data(&mut self, stream_id: StreamId, data: &[u8], end: bool) {
+        match self {
+            Session::H1(session) => session.send_data(stream_id, data, end),
+            Session::H2(session) => session.send_data(stream_id, data, end),
+        }
+    }
+
+    fn abort(&mut self, stream_id: StreamId) {
+        match self {
+            Session::H1(session) => session.abort(stream_id),
+            Session::H2(session) => session.abort(stream_id),
+        }
+    }
+
+    fn buffered(&self, stream_id: StreamId) -> Option<usize> {
+        match self {
+            Session::H1(session) => session.buffered(stream_id),
+            Session::H2(session) => session.buffered(stream_id),
+        }
+    }
+
+    fn write_to<W: Write>(&mut self, w: &mut W) -> io::Result<()> {
+        match self {
+            Session::H1(session) => session.write_to(w),
+            Session::H2(session) => session.write_to(w),
+        }
+    }
+
+    fn shutdown(&mut self) {
+        match self {
+            Session::H1(session) => session.shutdown(),
+            Session::H2(session) => session.shutdown(),
+        }
+    }
+
+    /// is_receiving tells if part of a request was received, but not all of
+    /// it.
+    fn is_receiving(&self) -> bool {
+        match self {
+            Session::H1(session) => session.is_receiving(),
+            Session::H2(session) => session.is_receiving(),
+        }
+    }
+
+    /// is_closed tells if the connection is done once its output is written.
+    fn is_closed(&self) -> bool {
+        match self {
+            Session::H1(session) => session.is_closed(),
+            Session::H2(session) => session.is_closed(),
+        }
+    }
+}
+
+/// h1_session starts an HTTP/1.1 Session.
+fn h1_session(cleartext: bool, max_body_size: usize) -> h1::Session {
+    let mut session = h1::Session::new(cleartext);
+    session.set_max_body_size(max_body_size);
+    session
+}
+
+/// h2_session starts an HTTP/2 Session.
+fn h2_session(settings: Settings, max_body_size: usize) -> h2::Session {
+    let mut session = h2::Session::new(settings);
+    session.set_max_body_size(max_body_size);
+    session
+}
+
+/// Transport is the byte stream of a connection: TLS, or the plain Socket
+/// for cleartext HTTP.
+enum Transport {
+    Handshake(MidHandshakeSslStream<Socket>),
+    Tls(SslStream<Socket>),
+    Plain(Socket),
+    Failed,
+}
+
+impl Transport {
+    /// session starts the Session for the protocol of the Transport: the one
+    /// negotiated with ALPN, HTTP/1.1 unless the client chose h2, or for
+    /// a plain Socket, HTTP/1.1 until the client switches to h2c.
+    fn session(&self, settings: Settings, max_body_size: usize) -> Option<Session> {
+        match self {
+            Transport::Tls(stream) => match stream.ssl().selected_alpn_protocol() {
+                Some(b"h2") => Some(Session::H2(h2_session(settings, max_body_size))),
+                _ => Some(Session::H1(h1_session(false, max_body_size))),
+            },
+            Transport::Plain(_) => Some(Session::H1(h1_session(true, max_body_size))),
+            _ => None,
+        }
+    }
+
+    /// tls_info describes the TLS session once the handshake is done.
+    fn tls_info(&self) -> Option<TlsInfo> {
+        match self {
+            Transport::Tls(stream) => Some(tls::info(stream.ssl())),
+            _ => None,
+        }
+    }
+
+    /// shutdown closes the connection, sending a TLS close_notify first.
+    fn shutdown(&mut self) {
+        match self {
+            Transport::Tls(stream) => {
+                let _ = stream.shutdown();
+            }
+            Transport::Plain(stream) => {
+                let _ = stream.shutdown();
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Read for Transport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Transport::Tls(stream) => stream.read(buf),
+            Transport::Plain(stream) => stream.read(buf),
+            _ => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+impl Write for Transport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Transport::Tls(stream) => stream.write(buf),
+            Transport::Plain(stream) => stream.write(buf),
+            _ => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Progress is a Write telling if any bytes were written through it.
+struct Progress<'a, W> {
+    w: &'a mut W,
+    written: bool,
+}
+
+impl<'a, W: Write> Write for Progress<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.w.write(buf)?;
+        self.written |= n > 0;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.w.flush()
+    }
+}
+
+/// Timeouts bound how long a connection may go without making progress,
+/// so that stalled clients do not hold on to it forever.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Timeouts {
+    /// handshake is how long the TLS handshake may take.
+    pub(crate) handshake: Duration,
+    /// read is how long a request may take to arrive once it started.
+    pub(crate) read: Duration,
+    /// idle is how long a connection may go without receiving or sending
+    /// anything while no request is being handled.
+    pub(crate) idle: Duration,
+}
+
+/// Conn is a non-blocking HTTP/2 or HTTP/1.1 connection, over TLS or a
+/// plain TCP or Unix domain Socket, driven by the Server's event loop
+/// whenever its socket is ready.
+pub(crate) struct Conn {
+    /// serial tells this connection apart from later ones reusing its token.
+    pub(crate) serial: u64,
+    /// redirect tells if the connection came in on a Redirect listener.
+    pub(crate) redirect: bool,
+    transport: Transport,
+    settings: Settings,
+    /// max_body_size is the largest request body accepted.
+    max_body_size: usize,
+    /// session is set once the handshake is done.
+    session: Option<Session>,
+    /// tls is shared by the requests received over TLS.
+    tls: Option<Arc<TlsInfo>>,
+    /// pending holds the requests waiting for a handler.
+    pending: VecDeque<Message>,
+    /// max_pending is the number of pending requests past which reading
+    /// stops, until handlers catch up.
+    max_pending: usize,
+    /// paused tells if reading stopped because of max_pending.
+    paused: bool,
+    /// in_flight is the number of requests being handled.
+    in_flight: usize,
+    /// bodies are the streamed response bodies being sent, with their
+    /// Chunks, or None while their next chunk is being produced.
+    bodies: Vec<(StreamId, Option<Chunks>)>,
+    /// closing tells if shutdown was called.
+    closing: bool,
+    /// accepted is when the connection was accepted.
+    accepted: Instant,
+    /// active is when bytes were last received or sent.
+    active: Instant,
+    /// receiving is when the request being received started arriving.
+    receiving: Option<Instant>,
+}
+
+impl Conn {
+    /// new starts the TLS handshake of an accepted Socket, or with no
+    /// acceptor, serves it in cleartext. Reading stops while max_pending
+    /// requests wait for a handler, and request bodies are limited to
+    /// max_body_size.
+    pub(crate) fn new(
+        acceptor: Option<&SslAcceptor>,
+        stream: Socket,
+        serial: u64,
+        settings: Settings,
+        max_pending: usize,
+        max_body_size: usize,
+    ) -> io::Result<Conn> {
+        let transport = match acceptor {
+            Some(acceptor) => match acceptor.accept(stream) {
+                Ok(stream) => Transport::Tls(stream),
+                Err(HandshakeError::WouldBlock(mid)) => Transport::Handshake(mid),
+                Err(e) => return Err(tls_error(e)),
+            },
+            None => Transport::Plain(stream),
+        };
+
+        Ok(Conn {
+            serial,
+            redirect: false,
+            transport,
+            settings,
+            max_body_size,
+            session: None,
+            tls: None,
+            pending: VecDeque::new(),
+            max_pending: max_pending.max(1),
+            paused: false,
+            in_flight: 0,
+            bodies: Vec::new(),
+            closing: false,
+            accepted: Instant::now(),
+            active: Instant::now(),
+            receiving: None,
+        })
+    }
+
+    /// ready advances the handshake, reads all available data and handles
+    /// it with the Session. Requests received in full are queued for
+    /// next_request, and reading pauses while too many are. An
+    /// UnexpectedEof error means the client closed the connection.
+    pub(crate) fn ready(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        if let Transport::Handshake(_) = self.transport {
+            let mid = match mem::replace(&mut self.transport, Transport::Failed) {
+                Transport::Handshake(mid) => mid,
+                _ => unreachable!(),
+            };
+            match mid.handshake() {
+                Ok(stream) => self.transport = Transport::Tls(stream),
+                Err(HandshakeError::WouldBlock(mid)) => {
+                    self.transport = Transport::Handshake(mid);
+                    return Ok(());
+                }
+                Err(e) => return Err(tls_error(e)),
+            }
+        }
+
+        if self.session.is_none() {
+            self.session = self.transport.session(self.settings, self.max_body_size);
+            self.tls = self.transport.tls_info().map(Arc::new);
+            if let (true, Some(session)) = (self.closing, self.session.as_mut()) {
+                session.shutdown();
+            }
+        }
+        let session = match self.session.as_mut() {
+            Some(session) => session,
+            None => return Ok(()),
+        };
+        self.paused = false;
+        loop {
+            if self.pending.len() >= self.max_pending {
+                // The event loop calls again once handlers catch up.
+                self.paused = true;
+                return Ok(());
+            }
+            let n = match self.transport.read(buf) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            if let Err(e) = session.recv(
+                &buf[..n],
+                &mut self.pending,
+                self.settings,
+                self.max_body_size,
+            ) {
+                // Let the client know why with the GOAWAY the session queued.
+                let _ = session.write_to(&mut self.transport);
+                return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+            }
+            let now = Instant::now();
+            self.active = now;
+            self.receiving = match self.receiving {
+                _ if !session.is_receiving() => None,
+                Some(since) => Some(since),
+                None => Some(now),
+            };
+        }
+    }
+
+    /// is_paused tells if reading stopped with too many pending requests,
+    /// which ready should resume once some are handled.
+    pub(crate) fn is_paused(&self) -> bool {
+        self.paused && self.pending.len() < self.max_pending
+    }
+
+    /// is_idle tells if no request is waiting for, or being handled by, a
+    /// handler, and no response body is being streamed.
+    pub(crate) fn is_idle(&self) -> bool {
+        self.in_flight == 0 && self.pending.is_empty() && self.bodies.is_empty()
+    }
+
+    /// expired tells what timed out, if anything, as of now.
+    pub(crate) fn expired(&self, now: Instant, timeouts: &Timeouts) -> Option<&'static str> {
+        let elapsed = |since: Instant| now.saturating_duration_since(since);
+        if let Transport::Handshake(_) = self.transport {
+            return Some("TLS handshake").filter(|_| elapsed(self.accepted) >= timeouts.handshake);
+        }
+        match self.receiving {
+            Some(since) => Some("request").filter(|_| elapsed(since) >= timeouts.read),
+            None if self.is_idle() => {
+                Some("idle").filter(|_| elapsed(self.active) >= timeouts.idle)
+            }
+            None => None,
+        }
+    }
+
+    /// next_request returns the next request to handle, unless concurrency
+    /// requests are being handled already.
+    pub(crate) fn next_request(&mut self, concurrency: usize) -> Option<Message> {
+        if self.in_flight >= concurrency {
+            return None;
+        }
+        let mut req = self.pending.pop_front()?;
+        req.tls = self.tls.clone();
+        self.in_flight += 1;
+        Some(req)
+    }
+
+    /// respond queues the Response of a request returned by next_request.
+    /// The chunks of a streamed body are sent as produce hands them out to
+    /// be produced.
+    pub(crate) fn respond(&mut self, resp: Response) {
+        self.in_flight -= 1;
+        self.active = Instant::now();
+        let body = self
+            .session
+            .as_mut()
+            .and_then(|session| session.respond(resp));
+        if let Some((stream_id, chunks)) = body {
+            self.bodies.push((stream_id, Some(chunks)));
+        }
+    }
+
+    /// produce returns the Chunks of the streamed bodies whose next chunk
+    /// should be produced, as less than BODY_LOW of them is buffered. Each
+    /// comes back with produced. Bodies whose stream was reset are dropped.
+    pub(crate) fn produce(&mut self) -> Vec<(StreamId, Chunks)> {
+        let session = match self.session.as_ref() {
+            Some(session) => session,
+            None => return Vec::new(),
+        };
+        let mut produce = Vec::new();
+        self.bodies.retain_mut(|(stream_id, chunks)| {
+            match session.buffered(*stream_id) {
+                Some(buffered) if buffered < BODY_LOW => {
+                    if let Some(chunks) = chunks.take() {
+                        produce.push((*stream_id, chunks));
+                    }
+                }
+                Some(_) => {}
+                None => return false,
+            }
+            true
+        });
+        produce
+    }
+
+    /// produced sends what producing the next chunk of the streamed body of
+    /// a stream gave. A failed body resets its stream, or closes an HTTP/1.1
+    /// connection, rather than end as if it was complete.
+    pub(crate) fn produced(&mut self, stream_id: StreamId, produced: Produced) {
+        let (session, i) = match (
+            self.session.as_mut(),
+            self.bodies.iter().position(|(id, _)| *id == stream_id),
+        ) {
+            (Some(session), Some(i)) => (session, i),
+            _ => return,
+        };
+        match produced {
+            Produced::Chunk(chunks, chunk) => {
+                if !chunk.is_empty() {
+                    session.send_data(stream_id, &chunk, false);
+                }
+                self.bodies[i].1 = Some(chunks);
+                return;
+            }
+            Produced::End => session.send_data(stream_id, &[], true),
+            Produced::Failed => session.abort(stream_id),
+        }
+        self.bodies.remove(i);
+    }
+
+    /// shutdown closes the connection gracefully: HTTP/2 clients get a
+    /// GOAWAY and no new stream is accepted, and HTTP/1.1 connections close
+    /// after the responses to the requests received. flush fails with
+    /// ConnectionAborted once the connection is done.
+    pub(crate) fn shutdown(&mut self) {
+        self.closing = true;
+        if let Some(session) = self.session.as_mut() {
+            session.shutdown();
+        }
+    }
+
+    /// flush writes the buffered output until the socket would block. A
+    /// ConnectionAborted error means the connection is done and should be
+    /// closed, as after an HTTP/1.1 request with Connection: close.
+    pub(crate) fn flush(&mut self) -> io::Result<()> {
+        let session = match self.session.as_mut() {
+            Some(session) => session,
+            None => return Ok(()),
+        };
+        let mut progress = Progress {
+            w: &mut self.transport,
+            written: false,
+        };
+        session.write_to(&mut progress)?;
+        if progress.written {
+            self.active = Instant::now();
+        }
+        if session.is_closed() {
+            self.transport.shutdown();
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "connection closed after response",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// tls_error converts a failed handshake to an io::Error.
+fn tls_error(e: HandshakeError<Socket>) -> io::Error {
+    io::Error::other(format!("error in TLS accept: {}", e))
+}
@@ -1,13 +1,21 @@
+use std::fmt::Write;
 use std::{error, fmt, io, result};
 
 use openssl::error::ErrorStack as SslErrorStack;
 
+use crate::response::Response;
+
 pub type Result<T> = result::Result<T, ServerError>;
 
 #[derive(Debug)]
 pub enum ServerError {
     ParseAction(String),
     BadRequest,
+    MissingHeader(String),
+    NotImplemented(String),
+    MissingParam(String),
+    UnknownRoute(String),
+    InvalidParam(String, String),
     Io(io::Error),
     Ssl(SslErrorStack),
 }
@@ -17,6 +25,15 @@ impl fmt::Display for ServerError {
         match self {
             ServerError::ParseAction(msg) => write!(f, "Error parsing Action: {}", msg),
             ServerError::BadRequest => write!(f, "Bad request"),
+            ServerError::MissingHeader(name) => write!(f, "Missing request header: {}", name),
+            ServerError::NotImplemented(method) => {
+                write!(f, "Request method not implemented: {}", method)
+            }
+            ServerError::MissingParam(name) => write!(f, "Missing route parameter: {}", name),
+            ServerError::UnknownRoute(name) => write!(f, "Unknown route name: {}", name),
+            ServerError::InvalidParam(name, value) => {
+                write!(f, "Invalid value for route parameter {}: {}", name, value)
+            }
             ServerError::Io(ref err) => write!(f, "Io error: {}", err),
             ServerError::Ssl(ref err) => write!(f, "SSL error: {}", err),
         }
@@ -28,12 +45,38 @@ impl error::Error for ServerError {
         match *self {
             ServerError::ParseAction(_) => None,
             ServerError::BadRequest => None,
+            ServerError::MissingHeader(_) => None,
+            ServerError::NotImplemented(_) => None,
+            ServerError::MissingParam(_) => None,
+            ServerError::UnknownRoute(_) => None,
+            ServerError::InvalidParam(_, _) => None,
             ServerError::Io(ref err) => Some(err),
             ServerError::Ssl(ref err) => Some(err),
         }
     }
 }
 
+impl ServerError {
+    /// status returns the HTTP status code the error maps to.
+    pub fn status(&self) -> u16 {
+        match self {
+            ServerError::BadRequest => 400,
+            ServerError::MissingHeader(_) => 400,
+            ServerError::InvalidParam(_, _) => 400,
+            ServerError::NotImplemented(_) => 501,
+            ServerError::Io(err) => match err.kind() {
+                io::ErrorKind::NotFound => 404,
+                io::ErrorKind::PermissionDenied => 403,
+                _ => 500,
+            },
+            ServerError::ParseAction(_)
+            | ServerError::MissingParam(_)
+            | ServerError::UnknownRoute(_)
+            | ServerError::Ssl(_) => 500,
+        }
+    }
+}
+
 impl From<io::Error> for ServerError {
     fn from(err: io::Error) -> ServerError {
         ServerError::Io(err)
@@ -45,3 +88,276 @@ impl From<SslErrorStack> for ServerError {
         ServerError::Ssl(err)
     }
 }
+
+/// HttpError is an error with the HTTP status it maps to. Fallible handlers
+/// return errors convertible into HttpError, which the Server's ErrorRenderer
+/// turns into a Response.
+#[derive(Clone, Debug)]
+pub struct HttpError {
+    /// status is the HTTP status code.
+    pub status: u16,
+    /// detail explains the error to the client, and may be empty.
+    pub detail: String,
+    /// cause is logged by the server, but never sent to the client.
+    pub cause: Option<String>,
+}
+
+impl HttpError {
+    pub fn new<T: Into<String>>(status: u16, detail: T) -> HttpError {
+        HttpError {
+            status,
+            detail: detail.into(),
+            cause: None,
+        }
+    }
+
+    pub fn bad_request<T: Into<String>>(detail: T) -> HttpError {
+        HttpError::new(400, detail)
+    }
+
+    pub fn not_found<T: Into<String>>(detail: T) -> HttpError {
+        HttpError::new(404, detail)
+    }
+
+    pub fn internal<T: Into<String>>(cause: T) -> HttpError {
+        HttpError {
+            status: 500,
+            detail: String::new(),
+            cause: Some(cause.into()),
+        }
+    }
+
+    /// title returns the reason phrase of the status code.
+    pub fn title(&self) -> &'static str {
+        reason(self.status)
+    }
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.status, self.title())?;
+        if !self.detail.is_empty() {
+            write!(f, ": {}", self.detail)?;
+        }
+        if let Some(cause) = &self.cause {
+            write!(f, " ({})", cause)?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for HttpError {}
+
+/// Server errors mapping to 5xx statuses keep their message out of detail.
+impl From<ServerError> for HttpError {
+    fn from(err: ServerError) -> HttpError {
+        let status = err.status();
+        if status >= 500 {
+            HttpError {
+                status,
+                detail: String::new(),
+                cause: Some(err.to_string()),
+            }
+        } else {
+            HttpError {
+                status,
+                detail: err.to_string(),
+                cause: None,
+            }
+        }
+    }
+}
+
+impl From<io::Error> for HttpError {
+    fn from(err: io::Error) -> HttpError {
+        ServerError::Io(err).into()
+    }
+}
+
+/// reason returns the reason phrase for an HTTP status code.
+pub fn reason(status: u16) -> &'static str {
+    match status {
+        100 => "Continue",
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        406 => "Not Acceptable",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        410 => "Gone",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        414 => "URI Too Long",
+        415 => "Unsupported Media Type",
+        422 => "Unprocessable Entity",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        505 => "HTTP Version Not Supported",
+        _ if status < 200 => "Informational",
+        _ if status < 300 => "Success",
+        _ if status < 400 => "Redirection",
+        _ if status < 500 => "Client Error",
+        _ => "Server Error",
+    }
+}
+
+/// ErrorRenderer turns an HttpError into a Response.
+pub trait ErrorRenderer: Send + Sync + 'static {
+    fn render(&self, err: &HttpError, resp: Response) -> Response;
+}
+
+/// PlainText renders errors as text/plain. It is the default ErrorRenderer.
+pub struct PlainText;
+
+impl ErrorRenderer for PlainText {
+    fn render(&self, err: &HttpError, mut resp: Response) -> Response {
+        resp.add_header(":status", &err.status.to_string());
+        resp.add_header("content-type", "text/plain; charset=utf-8");
+        if err.detail.is_empty() {
+            resp.set_body(format!("{}\n", err.title()));
+        } else {
+            resp.set_body(format!("{}: {}\n", err.title(), err.detail));
+        }
+        resp
+    }
+}
+
+/// ProblemJson renders errors as application/problem+json, per RFC 7807.
+pub struct ProblemJson;
+
+impl ErrorRenderer for ProblemJson {
+    fn render(&self, err: &HttpError, mut resp: Response) -> Response {
+        let mut body = String::from("{\"type\":\"about:blank\",\"title\":");
+        json_string(&mut body, err.title());
+        let _ = write!(body, ",\"status\":{}", err.status);
+        if !err.detail.is_empty() {
+            body.push_str(",\"detail\":");
+            json_string(&mut body, &err.detail);
+        }
+        body.push('}');
+
+        resp.add_header(":status", &err.status.to_string());
+        resp.add_header("content-type", "application/problem+json");
+        resp.set_body(body);
+        resp
+    }
+}
+
+/// Html renders errors as a minimal text/html page.
+pub struct Html;
+
+impl ErrorRenderer for Html {
+    fn render(&self, err: &HttpError, mut resp: Response) -> Response {
+        let title = format!("{} {}", err.status, err.title());
+        let mut body = String::from("<!DOCTYPE html>\n<html><head><title>");
+        body.push_str(&title);
+        body.push_str("</title></head><body><h1>");
+        body.push_str(&title);
+        body.push_str("</h1>");
+        if !err.detail.is_empty() {
+            body.push_str("<p>");
+            html_escape(&mut body, &err.detail);
+            body.push_str("</p>");
+        }
+        body.push_str("</body></html>\n");
+
+        resp.add_header(":status", &err.status.to_string());
+        resp.add_header("content-type", "text/html; charset=utf-8");
+        resp.set_body(body);
+        resp
+    }
+}
+
+/// json_string appends s to out as a quoted and escaped JSON string.
+fn json_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// html_escape appends s to out, escaping HTML special characters.
+fn html_escape(out: &mut String, s: &str) {
+    for c in s.chars() {
+        match c {
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '&' => out.push_str("&amp;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str;
+
+    use super::*;
+
+    #[test]
+    fn problem_json() {
+        let err: HttpError = ServerError::InvalidParam("age".into(), "x\"y".into()).into();
+        let resp = ProblemJson.render(&err, Response::new(1));
+        assert_eq!(resp.header(":status"), Some("400"));
+        assert_eq!(
+            str::from_utf8(resp.body()).unwrap(),
+            r#"{"type":"about:blank","title":"Bad Request","status":400,"detail":"Invalid value for route parameter age: x\"y"}"#
+        );
+
+        let err: HttpError = ServerError::UnknownRoute("secret".into()).into();
+        assert_eq!(err.status, 500);
+        assert!(err.detail.is_empty());
+    }
+
+    #[test]
+    fn html() {
+        let err = HttpError::new(404, "<script>alert(\"x\" & 'y')</script>");
+        let resp = Html.render(&err, Response::new(1));
+        assert_eq!(resp.header(":status"), Some("404"));
+        assert_eq!(
+            resp.header("content-type"),
+            Some("text/html; charset=utf-8")
+        );
+        let body = str::from_utf8(resp.body()).unwrap();
+        assert!(body.contains("<title>404 Not Found</title>"));
+        assert!(body.contains(
+            "<p>&lt;script&gt;alert(&quot;x&quot; &amp; &#39;y&#39;)&lt;/script&gt;</p>"
+        ));
+        assert!(!body.contains("<script>"));
+
+        let resp = Html.render(&HttpError::new(500, ""), Response::new(1));
+        assert!(!str::from_utf8(resp.body()).unwrap().contains("<p>"));
+    }
+}
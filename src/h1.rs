@@ -0,0 +1,952 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{self, Write};
+use std::mem;
+use std::str;
+
+use crate::error::reason;
+use crate::h2::{Header, StreamId, PREFACE};
+use crate::request::Message;
+use crate::BuildHasher;
+
+/// MAX_HEAD is the largest request line and header section accepted.
+const MAX_HEAD: usize = 64 << 10;
+
+/// MAX_LINE is the longest chunk size or trailer line accepted.
+const MAX_LINE: usize = 4096;
+
+/// CONTINUE is the interim response to Expect: 100-continue.
+const CONTINUE: &[u8] = b"HTTP/1.1 100 Continue\r\n\r\n";
+
+/// SWITCHING is the response accepting an Upgrade: h2c request.
+const SWITCHING: &[u8] =
+    b"HTTP/1.1 101 Switching Protocols\r\nconnection: Upgrade\r\nupgrade: h2c\r\n\r\n";
+
+/// Chunk is the position in a chunked body, RFC 7230 section 4.1.
+enum Chunk {
+    Size,
+    Data(usize),
+    DataEnd,
+    Trailers,
+}
+
+/// Body is the framing of a request body being received.
+enum Body {
+    Length(usize),
+    Chunked(Chunk),
+}
+
+/// Partial is a request whose head is received but not its whole body.
+struct Partial {
+    msg: Message,
+    body: Body,
+    close: bool,
+    http10: bool,
+    /// h2c holds the decoded HTTP2-Settings of a request to upgrade to h2c.
+    h2c: Option<Vec<u8>>,
+}
+
+/// Upgrade is a cleartext connection switching to HTTP/2, RFC 7540
+/// section 3.2 and 3.4.
+pub(crate) struct Upgrade {
+    /// output is what is left to write, ending with the 101 response to an
+    /// upgrade request.
+    pub(crate) output: Vec<u8>,
+    /// input is what was received past the upgrade request, or from the
+    /// start of the HTTP/2 preface.
+    pub(crate) input: Vec<u8>,
+    /// request is the upgrade request, as stream 1, and its decoded
+    /// HTTP2-Settings, or None for a client with prior knowledge.
+    pub(crate) request: Option<(Message, Vec<u8>)>,
+}
+
+/// Session is the server side of an HTTP/1.1 connection, RFC 7230. It
+/// parses the bytes received into the same Messages as HTTP/2, numbering
+/// them as streams, and writes the responses back in request order however
+/// they complete, so pipelined requests can be handled concurrently.
+pub(crate) struct Session {
+    /// cleartext tells if the connection is not over TLS, where requests are
+    /// http and may switch to h2c.
+    cleartext: bool,
+    input: Vec<u8>,
+    partial: Option<Partial>,
+    /// next_id is the stream id of the next request.
+    next_id: StreamId,
+    /// next_response is the stream id of the next response to write.
+    next_response: StreamId,
+    /// done holds the responses waiting for an earlier one, and whether
+    /// they are complete or have more of a streamed body to come.
+    done: HashMap<StreamId, (Vec<u8>, bool), BuildHasher>,
+    /// streams maps the streamed responses being sent to whether their body
+    /// is chunked, rather than delimited by content-length or the end of the
+    /// connection.
+    streams: HashMap<StreamId, bool, BuildHasher>,
+    /// http10 and head hold the HTTP/1.0 and HEAD requests yet to be
+    /// answered.
+    http10: HashSet<StreamId, BuildHasher>,
+    head: HashSet<StreamId, BuildHasher>,
+    /// last is the request after which the connection closes, if any.
+    last: Option<StreamId>,
+    output: Vec<u8>,
+    written: usize,
+    /// max_body_size is the largest request body accepted.
+    max_body_size: usize,
+}
+
+impl Session {
+    pub(crate) fn new(cleartext: bool) -> Session {
+        Session {
+            cleartext,
+            input: Vec::new(),
+            partial: None,
+            next_id: 1,
+            next_response: 1,
+            done: HashMap::default(),
+            streams: HashMap::default(),
+            http10: HashSet::default(),
+            head: HashSet::default(),
+            last: None,
+            output: Vec::new(),
+            written: 0,
+            max_body_size: usize::MAX,
+        }
+    }
+
+    /// set_max_body_size limits the size of request bodies. A larger one is
+    /// answered with 413 Payload Too Large, closing the connection.
+    pub(crate) fn set_max_body_size(&mut self, max_body_size: usize) {
+        self.max_body_size = max_body_size;
+    }
+
+    /// is_closed tells if the response to the last request of the
+    /// connection was written, after which it should be closed.
+    pub(crate) fn is_closed(&self) -> bool {
+        self.last.is_some_and(|last| self.next_response > last) && self.written == self.output.len()
+    }
+
+    /// is_receiving tells if part of a request was received, but not all of
+    /// it.
+    pub(crate) fn is_receiving(&self) -> bool {
+        self.partial.is_some() || !self.input.is_empty()
+    }
+
+    /// recv handles bytes received from the client, adding the requests it
+    /// completes to requests. A malformed request is answered with an error
+    /// and ends the connection. On a cleartext connection, it returns the
+    /// Upgrade to hand over to an HTTP/2 session, if the client asks for it.
+    pub(crate) fn recv(
+        &mut self,
+        bytes: &[u8],
+        requests: &mut VecDeque<Message>,
+    ) -> Option<Upgrade> {
+        if self.last.is_some() && self.partial.is_none() {
+            return None;
+        }
+        self.input.extend_from_slice(bytes);
+        let mut input = mem::take(&mut self.input);
+        let mut pos = 0;
+        match self.parse(&input, &mut pos, requests) {
+            Ok(Some(mut upgrade)) => {
+                upgrade.output = self.output.split_off(self.written);
+                upgrade.input = input.split_off(pos);
+                return Some(upgrade);
+            }
+            Ok(None) => {}
+            Err(status) => {
+                debug!("malformed HTTP/1.1 request: {}", status);
+                self.fail(status);
+            }
+        }
+        if self.last.is_none() || self.partial.is_some() {
+            input.drain(..pos);
+            self.input = input;
+        }
+        None
+    }
+
+    /// shutdown stops taking requests. The connection closes after the
+    /// responses to those received, including one whose body is still being
+    /// received.
+    pub(crate) fn shutdown(&mut self) {
+        if self.last.is_none() {
+            self.last = Some(self.next_id - 1);
+        }
+    }
+
+    /// respond queues the response of a request, with the content-length of
+    /// body unless headers has one or the request is HEAD, whose response
+    /// has its body stripped.
+    pub(crate) fn respond(&mut self, stream_id: StreamId, headers: &[Header], body: Vec<u8>) {
+        self.http10.remove(&stream_id);
+        let head = self.head.remove(&stream_id);
+        let mut out = Vec::with_capacity(256 + body.len());
+        self.head(&mut out, stream_id, headers, |out, content_length| {
+            if !content_length && !head {
+                let _ = write!(out, "content-length: {}\r\n", body.len());
+            }
+        });
+        out.extend_from_slice(&body);
+        self.queue(stream_id, &out, true);
+    }
+
+    /// respond_stream queues the head of the response of a request, whose
+    /// body follows with send_data. It is chunked, unless headers has a
+    /// content-length, or the request is HTTP/1.0 and the connection closes
+    /// after the body instead. The response to a HEAD request has the same
+    /// head, but no body is sent.
+    pub(crate) fn respond_stream(&mut self, stream_id: StreamId, headers: &[Header]) {
+        let http10 = self.http10.remove(&stream_id);
+        let head = self.head.remove(&stream_id);
+        if http10 && !headers.iter().any(|(name, _)| name == b"content-length") {
+            self.last = Some(self.last.map_or(stream_id, |last| last.min(stream_id)));
+        }
+        let mut out = Vec::with_capacity(256);
+        let mut chunked = false;
+        self.head(&mut out, stream_id, headers, |out, content_length| {
+            if !content_length && !http10 {
+                out.extend_from_slice(b"transfer-encoding: chunked\r\n");
+                chunked = true;
+            }
+        });
+        if !head {
+            self.streams.insert(stream_id, chunked);
+        }
+        self.queue(stream_id, &out, head);
+    }
+
+    /// send_data queues part of the body of a response started with
+    /// respond_stream, end telling if it is the last.
+    pub(crate) fn send_data(&mut self, stream_id: StreamId, data: &[u8], end: bool) {
+        let chunked = match self.streams.get(&stream_id) {
+            Some(&chunked) => chunked,
+            None => return,
+        };
+        let mut out = Vec::with_capacity(data.len() + 16);
+        if chunked && !data.is_empty() {
+            let _ = write!(out, "{:x}\r\n", data.len());
+            out.extend_from_slice(data);
+            out.extend_from_slice(b"\r\n");
+        } else {
+            out.extend_from_slice(data);
+        }
+        if end {
+            self.streams.remove(&stream_id);
+            if chunked {
+                out.extend_from_slice(b"0\r\n\r\n");
+            }
+        }
+        self.queue(stream_id, &out, end);
+    }
+
+    /// abort ends a streamed response whose body failed without ending its
+    /// body, closing the connection after it, so the client doesn't take the
+    /// part sent for all of it.
+    pub(crate) fn abort(&mut self, stream_id: StreamId) {
+        if self.streams.remove(&stream_id).is_some() {
+            self.last = Some(self.last.map_or(stream_id, |last| last.min(stream_id)));
+            self.queue(stream_id, &[], true);
+        }
+    }
+
+    /// buffered returns how much of the response to a request is queued but
+    /// not written yet, or None once its streamed body is complete.
+    pub(crate) fn buffered(&self, stream_id: StreamId) -> Option<usize> {
+        if !self.streams.contains_key(&stream_id) {
+            return None;
+        }
+        if stream_id == self.next_response {
+            return Some(self.output.len() - self.written);
+        }
+        Some(self.done.get(&stream_id).map_or(0, |(out, _)| out.len()))
+    }
+
+    /// head writes the status line and headers of a response to out, with
+    /// those framing the body added by framing, which is told if headers
+    /// has a content-length.
+    fn head<F>(&self, out: &mut Vec<u8>, stream_id: StreamId, headers: &[Header], framing: F)
+    where
+        F: FnOnce(&mut Vec<u8>, bool),
+    {
+        let close = self.last == Some(stream_id);
+        let status = headers
+            .iter()
+            .find(|(name, _)| name == b":status")
+            .and_then(|(_, value)| str::from_utf8(value).ok()?.parse().ok())
+            .unwrap_or(200);
+        let _ = write!(out, "HTTP/1.1 {} {}\r\n", status, reason(status));
+
+        let mut content_length = false;
+        for (name, value) in headers {
+            if name.starts_with(b":") {
+                continue;
+            }
+            content_length |= name == b"content-length";
+            out.extend_from_slice(name);
+            out.extend_from_slice(b": ");
+            out.extend_from_slice(value);
+            out.extend_from_slice(b"\r\n");
+        }
+        if status >= 200 && status != 204 && status != 304 {
+            framing(out, content_length);
+        }
+        if close {
+            out.extend_from_slice(b"connection: close\r\n");
+        }
+        out.extend_from_slice(b"\r\n");
+    }
+
+    /// queue adds out to the response of a request, complete or not, which
+    /// is written once the responses before it are complete.
+    fn queue(&mut self, stream_id: StreamId, out: &[u8], complete: bool) {
+        let entry = self.done.entry(stream_id).or_default();
+        entry.0.extend_from_slice(out);
+        entry.1 = complete;
+        while let Some((out, complete)) = self.done.get_mut(&self.next_response) {
+            self.output.append(out);
+            if !*complete {
+                break;
+            }
+            self.done.remove(&self.next_response);
+            self.next_response += 1;
+        }
+    }
+
+    /// write_to writes the queued output to w until it would block.
+    pub(crate) fn write_to<W: Write>(&mut self, w: &mut W) -> io::Result<()> {
+        while self.written < self.output.len() {
+            match w.write(&self.output[self.written..]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => self.written += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        if self.written == self.output.len() {
+            self.output.clear();
+            self.written = 0;
+        } else if self.written * 2 >= self.output.len() {
+            self.output.drain(..self.written);
+            self.written = 0;
+        }
+        Ok(())
+    }
+
+    /// fail answers a malformed request with status, closing the connection
+    /// after the responses to the requests before it.
+    fn fail(&mut self, status: u16) {
+        // A request failing in its body answers in place of it.
+        let id = match self.partial.take() {
+            Some(partial) => partial.msg.stream_id,
+            None => {
+                self.next_id += 1;
+                self.next_id - 1
+            }
+        };
+        self.last = Some(id);
+        self.input = Vec::new();
+        let headers = [
+            (b":status".to_vec(), status.to_string().into_bytes()),
+            (
+                b"content-type".to_vec(),
+                b"text/plain; charset=utf-8".to_vec(),
+            ),
+        ];
+        self.respond(id, &headers, format!("{}\n", reason(status)).into_bytes());
+    }
+
+    fn parse(
+        &mut self,
+        input: &[u8],
+        pos: &mut usize,
+        requests: &mut VecDeque<Message>,
+    ) -> Result<Option<Upgrade>, u16> {
+        let upgrade = |request| Upgrade {
+            output: Vec::new(),
+            input: Vec::new(),
+            request,
+        };
+        loop {
+            if self.partial.is_none() {
+                let rest = &input[*pos..];
+                if self.cleartext && self.next_id == 1 {
+                    if rest.starts_with(PREFACE) {
+                        return Ok(Some(upgrade(None)));
+                    }
+                    if PREFACE.starts_with(rest) {
+                        return Ok(None);
+                    }
+                }
+                // Ignore empty lines before a request line, RFC 7230 section 3.5.
+                while input[*pos..].starts_with(b"\r\n") {
+                    *pos += 2;
+                }
+                let rest = &input[*pos..];
+                let end = match find(rest, b"\r\n\r\n") {
+                    Some(end) if end + 4 > MAX_HEAD => return Err(431),
+                    Some(end) => end,
+                    None if rest.len() > MAX_HEAD => return Err(431),
+                    None => return Ok(None),
+                };
+                let scheme: &[u8] = if self.cleartext { b"http" } else { b"https" };
+                let (mut partial, expect) = parse_head(&rest[..end], scheme)?;
+                *pos += end + 4;
+                partial.msg.stream_id = self.next_id;
+                self.next_id += 1;
+                if let Body::Length(len) = partial.body {
+                    if len > self.max_body_size {
+                        self.partial = Some(partial);
+                        return Err(413);
+                    }
+                }
+                // Only ask for the body once the responses before are out,
+                // which keeps the interim response in order.
+                if expect && self.next_response == partial.msg.stream_id && *pos == input.len() {
+                    self.output.extend_from_slice(CONTINUE);
+                }
+                self.partial = Some(partial);
+            }
+
+            let partial = self.partial.as_mut().unwrap();
+            if !read_body(partial, input, pos, self.max_body_size)? {
+                return Ok(None);
+            }
+            let mut partial = self.partial.take().unwrap();
+            // Upgrade only when no response is pending, which would have to
+            // go out over HTTP/1.1 after the 101.
+            if let (true, true, Some(settings)) = (
+                self.cleartext && self.last.is_none(),
+                self.next_response == partial.msg.stream_id,
+                partial.h2c.take(),
+            ) {
+                self.output.extend_from_slice(SWITCHING);
+                partial.msg.stream_id = 1;
+                return Ok(Some(upgrade(Some((partial.msg, settings)))));
+            }
+            if partial.close {
+                self.last = Some(partial.msg.stream_id);
+            }
+            if partial.http10 {
+                self.http10.insert(partial.msg.stream_id);
+            }
+            if partial.msg.headers[0].1 == b"HEAD" {
+                self.head.insert(partial.msg.stream_id);
+            }
+            requests.push_back(partial.msg);
+            if self.last.is_some() {
+                return Ok(None);
+            }
+        }
+    }
+}
+
+/// read_body reads the body of a request from input, telling if it is
+/// complete. A chunked body growing past max_body_size fails with 413.
+fn read_body(
+    partial: &mut Partial,
+    input: &[u8],
+    pos: &mut usize,
+    max_body_size: usize,
+) -> Result<bool, u16> {
+    loop {
+        let rest = &input[*pos..];
+        match &mut partial.body {
+            Body::Length(0) => return Ok(true),
+            Body::Length(remaining) => {
+                let n = rest.len().min(*remaining);
+                partial.msg.body.extend_from_slice(&rest[..n]);
+                *pos += n;
+                *remaining -= n;
+                if *remaining > 0 {
+                    return Ok(false);
+                }
+            }
+            Body::Chunked(chunk) => match chunk {
+                Chunk::Size => {
+                    let line = match line(rest)? {
+                        Some(line) => line,
+                        None => return Ok(false),
+                    };
+                    *pos += line.len() + 2;
+                    let size = line.split(|&b| b == b';').next().unwrap_or(b"");
+                    let size = str::from_utf8(size)
+                        .ok()
+                        .map(str::trim)
+                        .filter(|size| size.bytes().all(|b| b.is_ascii_hexdigit()))
+                        .and_then(|size| usize::from_str_radix(size, 16).ok())
+                        .ok_or(400u16)?;
+                    if size > max_body_size - partial.msg.body.len() {
+                        return Err(413);
+                    }
+                    *chunk = if size == 0 {
+                        Chunk::Trailers
+                    } else {
+                        Chunk::Data(size)
+                    };
+                }
+                Chunk::Data(remaining) => {
+                    let n = rest.len().min(*remaining);
+                    partial.msg.body.extend_from_slice(&rest[..n]);
+                    *pos += n;
+                    *remaining -= n;
+                    if *remaining > 0 {
+                        return Ok(false);
+                    }
+                    *chunk = Chunk::DataEnd;
+                }
+                Chunk::DataEnd => {
+                    if rest.len() < 2 {
+                        return Ok(false);
+                    }
+                    if &rest[..2] != b"\r\n" {
+                        return Err(400);
+                    }
+                    *pos += 2;
+                    *chunk = Chunk::Size;
+                }
+                Chunk::Trailers => {
+                    // Trailers are dropped: no handler can see them in time.
+                    let line = match line(rest)? {
+                        Some(line) => line,
+                        None => return Ok(false),
+                    };
+                    *pos += line.len() + 2;
+                    if line.is_empty() {
+                        return Ok(true);
+                    }
+                }
+            },
+        }
+    }
+}
+
+/// line returns the line at the start of buf, without its CRLF, or None
+/// while it is incomplete.
+fn line(buf: &[u8]) -> Result<Option<&[u8]>, u16> {
+    match find(buf, b"\r\n") {
+        Some(end) => Ok(Some(&buf[..end])),
+        None if buf.len() > MAX_LINE => Err(400),
+        None => Ok(None),
+    }
+}
+
+fn find(buf: &[u8], needle: &[u8]) -> Option<usize> {
+    buf.windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// has_token tells if a comma-separated header value lists token.
+fn has_token(value: &str, token: &str) -> bool {
+    value
+        .split(',')
+        .any(|t| t.trim().eq_ignore_ascii_case(token))
+}
+
+/// base64url decodes unpadded base64url, as used by HTTP2-Settings.
+fn base64url(s: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let (mut acc, mut bits) = (0u32, 0);
+    for c in s.trim_end_matches('=').bytes() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'-' => 62,
+            b'_' => 63,
+            _ => return None,
+        };
+        acc = acc << 6 | value as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+            acc &= (1 << bits) - 1;
+        }
+    }
+    Some(out)
+}
+
+/// parse_head parses a request line and header section into a Partial
+/// request with HTTP/2 style headers, also telling whether the client
+/// expects 100 Continue.
+fn parse_head(head: &[u8], scheme: &[u8]) -> Result<(Partial, bool), u16> {
+    let head = str::from_utf8(head).map_err(|_| 400u16)?;
+    let mut lines = head.split("\r\n");
+    let request_line = lines.next().unwrap_or("");
+    let mut parts = request_line.split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version), None)
+            if !method.is_empty() && !target.is_empty() =>
+        {
+            (method, target, version)
+        }
+        _ => return Err(400),
+    };
+    let http10 = match version {
+        "HTTP/1.1" => false,
+        "HTTP/1.0" => true,
+        _ if version.starts_with("HTTP/") => return Err(505),
+        _ => return Err(400),
+    };
+
+    // Absolute-form targets carry the authority, RFC 7230 section 5.3.2.
+    let (mut authority, path) = match target.find("://") {
+        Some(i) if !target.starts_with('/') => {
+            let rest = &target[i + 3..];
+            match rest.find('/') {
+                Some(j) => (Some(&rest[..j]), &rest[j..]),
+                None => (Some(rest), "/"),
+            }
+        }
+        _ => (None, target),
+    };
+
+    let mut headers = Vec::new();
+    let mut host = 0;
+    let mut length = None;
+    let mut chunked = false;
+    let mut connection = String::new();
+    let mut expect = false;
+    let mut upgrade = false;
+    let mut settings = None;
+    for line in lines {
+        let colon = line.find(':').ok_or(400u16)?;
+        let (name, value) = (&line[..colon], line[colon + 1..].trim());
+        if name.is_empty() || name.contains(|c: char| c.is_ascii_whitespace()) {
+            return Err(400);
+        }
+        let name = name.to_ascii_lowercase();
+        match name.as_str() {
+            "host" => {
+                host += 1;
+                authority = authority.or(Some(value));
+                continue;
+            }
+            "content-length" => {
+                // parse would take a sign too.
+                if !value.bytes().all(|b| b.is_ascii_digit()) {
+                    return Err(400);
+                }
+                let len = value.parse::<usize>().map_err(|_| 400u16)?;
+                if length.is_some_and(|length| length != len) {
+                    return Err(400);
+                }
+                length = Some(len);
+            }
+            "transfer-encoding" => {
+                if !value.eq_ignore_ascii_case("chunked") || chunked {
+                    return Err(501);
+                }
+                chunked = true;
+                continue;
+            }
+            "connection" => {
+                connection.push_str(value);
+                connection.push(',');
+                continue;
+            }
+            "expect" => {
+                expect = value.eq_ignore_ascii_case("100-continue");
+                continue;
+            }
+            "upgrade" => {
+                upgrade = has_token(value, "h2c");
+                continue;
+            }
+            "http2-settings" => {
+                settings = base64url(value).filter(|s| s.len() % 6 == 0);
+                continue;
+            }
+            "keep-alive" | "proxy-connection" => continue,
+            _ => {}
+        }
+        headers.push((name.into_bytes(), value.as_bytes().to_vec()));
+    }
+    if (!http10 && host != 1) || host > 1 || (chunked && length.is_some()) {
+        return Err(400);
+    }
+
+    let mut pseudo = vec![
+        (b":method".to_vec(), method.as_bytes().to_vec()),
+        (b":scheme".to_vec(), scheme.to_vec()),
+        (b":path".to_vec(), path.as_bytes().to_vec()),
+    ];
+    if let Some(authority) = authority {
+        pseudo.push((b":authority".to_vec(), authority.as_bytes().to_vec()));
+    }
+    pseudo.append(&mut headers);
+
+    let body = if chunked {
+        Body::Chunked(Chunk::Size)
+    } else {
+        Body::Length(length.unwrap_or(0))
+    };
+    let close = if http10 {
+        !has_token(&connection, "keep-alive")
+    } else {
+        has_token(&connection, "close")
+    };
+    let partial = Partial {
+        msg: Message {
+            stream_id: 0,
+            headers: pseudo,
+            body: Vec::new(),
+            tls: None,
+        },
+        body,
+        close,
+        http10,
+        h2c: settings.filter(|_| upgrade),
+    };
+    Ok((partial, expect && !http10))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header<'a>(msg: &'a Message, name: &str) -> Option<&'a [u8]> {
+        msg.headers
+            .iter()
+            .find(|(n, _)| n == name.as_bytes())
+            .map(|(_, v)| v.as_slice())
+    }
+
+    fn ok(session: &mut Session, id: StreamId, body: &str) {
+        let headers = [(b":status".to_vec(), b"200".to_vec())];
+        session.respond(id, &headers, body.as_bytes().to_vec());
+    }
+
+    fn output(session: &mut Session) -> String {
+        let mut out = Vec::new();
+        session.write_to(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn pipelining_and_keep_alive() {
+        let mut session = Session::new(false);
+        let mut requests = VecDeque::new();
+        let input = b"GET /a?x=1 HTTP/1.1\r\nHost: zws\r\nAccept: */*\r\n\r\n\
+            POST /b HTTP/1.1\r\nHost: zws\r\nTransfer-Encoding: chunked\r\n\r\n\
+            5;ext=1\r\nhello\r\n6\r\n world\r\n0\r\nx-trailer: 1\r\n\r\n\
+            PUT /c HTTP/1.1\r\nHost: zws\r\nContent-Length: 3\r\nConnection: close\r\n\r\nabc\
+            GET /ignored HTTP/1.1\r\n";
+        // fed in small pieces to exercise partial heads and bodies
+        for piece in input.chunks(7) {
+            session.recv(piece, &mut requests);
+        }
+        assert_eq!(requests.len(), 3);
+        assert_eq!(header(&requests[0], ":path"), Some(&b"/a?x=1"[..]));
+        assert_eq!(header(&requests[0], ":authority"), Some(&b"zws"[..]));
+        assert_eq!(header(&requests[0], "accept"), Some(&b"*/*"[..]));
+        assert_eq!(requests[1].body, b"hello world");
+        assert_eq!(header(&requests[1], "transfer-encoding"), None);
+        assert_eq!(requests[2].body, b"abc");
+        let ids: Vec<StreamId> = requests.iter().map(|msg| msg.stream_id).collect();
+        assert_eq!(ids, [1, 2, 3]);
+
+        // responses are written in request order
+        ok(&mut session, 3, "c");
+        ok(&mut session, 2, "b");
+        assert_eq!(output(&mut session), "");
+        ok(&mut session, 1, "a");
+        assert_eq!(
+            output(&mut session),
+            "HTTP/1.1 200 OK\r\ncontent-length: 1\r\n\r\na\
+             HTTP/1.1 200 OK\r\ncontent-length: 1\r\n\r\nb\
+             HTTP/1.1 200 OK\r\ncontent-length: 1\r\nconnection: close\r\n\r\nc"
+        );
+        assert!(session.is_closed());
+    }
+
+    #[test]
+    fn shutdown() {
+        let mut session = Session::new(false);
+        session.shutdown();
+        assert!(session.is_closed());
+
+        // a request whose body is being received is still served
+        let mut session = Session::new(false);
+        let mut requests = VecDeque::new();
+        session.recv(b"GET /a HTTP/1.1\r\nHost: zws\r\n\r\n", &mut requests);
+        session.recv(
+            b"POST /b HTTP/1.1\r\nHost: zws\r\nContent-Length: 4\r\n\r\nab",
+            &mut requests,
+        );
+        session.shutdown();
+        session.recv(b"cdGET /c HTTP/1.1\r\nHost: zws\r\n\r\n", &mut requests);
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[1].body, b"abcd");
+
+        ok(&mut session, 1, "a");
+        assert!(!session.is_closed());
+        ok(&mut session, 2, "b");
+        assert!(output(&mut session).ends_with("connection: close\r\n\r\nb"));
+        assert!(session.is_closed());
+    }
+
+    #[test]
+    fn streamed_responses() {
+        let mut session = Session::new(false);
+        let mut requests = VecDeque::new();
+        session.recv(
+            b"GET /a HTTP/1.1\r\nHost: zws\r\n\r\n\
+              GET /b HTTP/1.1\r\nHost: zws\r\n\r\n\
+              GET /c HTTP/1.0\r\n\r\n",
+            &mut requests,
+        );
+        assert_eq!(requests.len(), 3);
+        let status = [(b":status".to_vec(), b"200".to_vec())];
+
+        // a streamed response holds back the ones after it until it ends
+        session.respond_stream(1, &status);
+        ok(&mut session, 2, "b");
+        session.send_data(1, b"hello", false);
+        assert_eq!(session.buffered(1), Some(57));
+        assert_eq!(
+            output(&mut session),
+            "HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\n\r\n5\r\nhello\r\n"
+        );
+        session.send_data(1, b" world", true);
+        assert_eq!(session.buffered(1), None);
+        assert_eq!(
+            output(&mut session),
+            "6\r\n world\r\n0\r\n\r\n\
+             HTTP/1.1 200 OK\r\ncontent-length: 1\r\n\r\nb"
+        );
+
+        // HTTP/1.0 clients get the body until the connection closes
+        session.respond_stream(3, &status);
+        session.send_data(3, b"c", false);
+        session.send_data(3, b"", true);
+        assert_eq!(
+            output(&mut session),
+            "HTTP/1.1 200 OK\r\nconnection: close\r\n\r\nc"
+        );
+        assert!(session.is_closed());
+    }
+
+    #[test]
+    fn body_limit() {
+        let cases: &[&[u8]] = &[
+            b"POST / HTTP/1.1\r\nHost: zws\r\nContent-Length: 5\r\n\r\nhello",
+            b"POST / HTTP/1.1\r\nHost: zws\r\nExpect: 100-continue\r\nContent-Length: 5\r\n\r\n",
+            b"POST / HTTP/1.1\r\nHost: zws\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n2\r\n",
+        ];
+        for input in cases {
+            let mut session = Session::new(false);
+            session.set_max_body_size(4);
+            let mut requests = VecDeque::new();
+            session.recv(input, &mut requests);
+            assert!(requests.is_empty());
+            let out = output(&mut session);
+            assert!(out.starts_with("HTTP/1.1 413 Payload Too Large"), "{}", out);
+            assert!(session.is_closed());
+        }
+
+        let mut session = Session::new(false);
+        session.set_max_body_size(4);
+        let mut requests = VecDeque::new();
+        session.recv(
+            b"POST / HTTP/1.1\r\nHost: zws\r\nTransfer-Encoding: chunked\r\n\r\n\
+              3\r\nabc\r\n1\r\nd\r\n0\r\n\r\n",
+            &mut requests,
+        );
+        assert_eq!(requests[0].body, b"abcd");
+    }
+
+    #[test]
+    fn malformed_requests() {
+        let cases: &[(&[u8], &str)] = &[
+            (b"GET / HTTP/1.1\r\n\r\n", "400 Bad Request"),
+            (b"GET / HTTP/2.0\r\nHost: zws\r\n\r\n", "505 HTTP Version"),
+            (
+                b"POST / HTTP/1.1\r\nHost: zws\r\nTransfer-Encoding: gzip\r\n\r\n",
+                "501 Not Implemented",
+            ),
+            (
+                b"POST / HTTP/1.1\r\nHost: zws\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\n",
+                "400 Bad Request",
+            ),
+            (
+                b"POST / HTTP/1.1\r\nHost: zws\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n",
+                "400 Bad Request",
+            ),
+            (b"GET / HTTP/1.1\r\n folded\r\n\r\n", "400 Bad Request"),
+            (
+                b"POST / HTTP/1.1\r\nHost: zws\r\nContent-Length: +1\r\n\r\na",
+                "400 Bad Request",
+            ),
+            (
+                b"POST / HTTP/1.1\r\nHost: zws\r\nTransfer-Encoding: chunked\r\n\r\n+1\r\na\r\n",
+                "400 Bad Request",
+            ),
+        ];
+        for (input, status) in cases {
+            let mut session = Session::new(false);
+            let mut requests = VecDeque::new();
+            session.recv(input, &mut requests);
+            assert!(requests.is_empty());
+            let out = output(&mut session);
+            assert!(out.starts_with(&format!("HTTP/1.1 {}", status)), "{}", out);
+            assert!(out.contains("connection: close\r\n"));
+            assert!(session.is_closed());
+        }
+    }
+
+    #[test]
+    fn http10_and_expect() {
+        let mut session = Session::new(false);
+        let mut requests = VecDeque::new();
+        session.recv(b"GET / HTTP/1.0\r\n\r\n", &mut requests);
+        assert_eq!(requests.len(), 1);
+        ok(&mut session, 1, "");
+        assert!(output(&mut session).contains("connection: close"));
+
+        let mut session = Session::new(false);
+        requests.clear();
+        session.recv(
+            b"POST / HTTP/1.1\r\nHost: zws\r\nExpect: 100-continue\r\nContent-Length: 2\r\n\r\n",
+            &mut requests,
+        );
+        assert_eq!(output(&mut session), "HTTP/1.1 100 Continue\r\n\r\n");
+        session.recv(b"ok", &mut requests);
+        assert_eq!(requests[0].body, b"ok");
+    }
+
+    #[test]
+    fn h2c() {
+        // prior knowledge: the preface is handed over, even split
+        let mut session = Session::new(true);
+        let mut requests = VecDeque::new();
+        assert!(session.recv(&PREFACE[..5], &mut requests).is_none());
+        let upgrade = session.recv(&PREFACE[5..], &mut requests).unwrap();
+        assert_eq!(upgrade.input, PREFACE);
+        assert!(upgrade.request.is_none());
+
+        // Upgrade: h2c, with an empty request and SETTINGS_MAX_FRAME_SIZE
+        let mut session = Session::new(true);
+        let upgrade = session
+            .recv(
+                b"GET /up HTTP/1.1\r\nHost: zws\r\nConnection: Upgrade, HTTP2-Settings\r\n\
+                  Upgrade: h2c\r\nHTTP2-Settings: AAUAAEAA\r\n\r\nPRI",
+                &mut requests,
+            )
+            .unwrap();
+        assert!(requests.is_empty());
+        assert_eq!(upgrade.output, SWITCHING);
+        assert_eq!(upgrade.input, b"PRI");
+        let (msg, settings) = upgrade.request.unwrap();
+        assert_eq!((msg.stream_id, settings), (1, vec![0, 5, 0, 0, 0x40, 0]));
+        assert_eq!(header(&msg, ":scheme"), Some(&b"http"[..]));
+
+        // no upgrade over TLS
+        let mut session = Session::new(false);
+        let upgrade = session.recv(
+            b"GET / HTTP/1.1\r\nHost: zws\r\nUpgrade: h2c\r\nHTTP2-Settings: \r\n\r\n",
+            &mut requests,
+        );
+        assert!(upgrade.is_none());
+        assert_eq!(requests.len(), 1);
+    }
+}
@@ -0,0 +1,432 @@
+use crate::h2::{Error, ErrorCode, StreamId};
+
+/// HEADER_LEN is the length of a frame header.
+pub(crate) const HEADER_LEN: usize = 9;
+
+pub(crate) const DATA: u8 = 0x0;
+pub(crate) const HEADERS: u8 = 0x1;
+pub(crate) const PRIORITY: u8 = 0x2;
+pub(crate) const RST_STREAM: u8 = 0x3;
+pub(crate) const SETTINGS: u8 = 0x4;
+pub(crate) const PUSH_PROMISE: u8 = 0x5;
+pub(crate) const PING: u8 = 0x6;
+pub(crate) const GOAWAY: u8 = 0x7;
+pub(crate) const WINDOW_UPDATE: u8 = 0x8;
+pub(crate) const CONTINUATION: u8 = 0x9;
+
+pub(crate) const END_STREAM: u8 = 0x1;
+pub(crate) const ACK: u8 = 0x1;
+pub(crate) const END_HEADERS: u8 = 0x4;
+pub(crate) const PADDED: u8 = 0x8;
+pub(crate) const PRIORITY_FLAG: u8 = 0x20;
+
+pub(crate) const SETTINGS_HEADER_TABLE_SIZE: u16 = 0x1;
+pub(crate) const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+pub(crate) const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+pub(crate) const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+pub(crate) const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+pub(crate) const SETTINGS_MAX_HEADER_LIST_SIZE: u16 = 0x6;
+
+/// Head is a parsed frame header.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Head {
+    pub(crate) len: usize,
+    pub(crate) kind: u8,
+    pub(crate) flags: u8,
+    pub(crate) stream_id: StreamId,
+}
+
+impl Head {
+    /// parse parses the frame header at the start of buf, which holds at
+    /// least HEADER_LEN bytes.
+    pub(crate) fn parse(buf: &[u8]) -> Head {
+        Head {
+            len: (buf[0] as usize) << 16 | (buf[1] as usize) << 8 | buf[2] as usize,
+            kind: buf[3],
+            flags: buf[4],
+            stream_id: read_u32(&buf[5..]) & 0x7fff_ffff,
+        }
+    }
+
+    /// write appends the frame header to out.
+    pub(crate) fn write(self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&(self.len as u32).to_be_bytes()[1..]);
+        out.push(self.kind);
+        out.push(self.flags);
+        out.extend_from_slice(&self.stream_id.to_be_bytes());
+    }
+
+    fn has(self, flag: u8) -> bool {
+        self.flags & flag != 0
+    }
+}
+
+/// Priority is the priority information of a HEADERS or PRIORITY frame.
+/// zws does not prioritize streams, but checks it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Priority {
+    pub(crate) dependency: StreamId,
+}
+
+/// Frame is a frame received from a client.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Frame<'a> {
+    Data {
+        end_stream: bool,
+        data: &'a [u8],
+    },
+    Headers {
+        end_stream: bool,
+        end_headers: bool,
+        priority: Option<Priority>,
+        block: &'a [u8],
+    },
+    Priority(Priority),
+    RstStream(u32),
+    Settings {
+        ack: bool,
+        params: Vec<(u16, u32)>,
+    },
+    PushPromise,
+    Ping {
+        ack: bool,
+        data: [u8; 8],
+    },
+    GoAway {
+        last_stream_id: StreamId,
+        code: u32,
+    },
+    WindowUpdate(u32),
+    Continuation {
+        end_headers: bool,
+        block: &'a [u8],
+    },
+    Unknown,
+}
+
+impl<'a> Frame<'a> {
+    /// parse parses the payload of a frame, checking its stream and length
+    /// as RFC 7540 section 6 requires.
+    pub(crate) fn parse(head: Head, payload: &'a [u8]) -> Result<Frame<'a>, Error> {
+        let id = head.stream_id;
+        let on_stream = |stream: bool| match (stream, id == 0) {
+            (true, true) => Err(Error::conn(
+                ErrorCode::ProtocolError,
+                "frame requires a stream",
+            )),
+            (false, false) => Err(Error::conn(
+                ErrorCode::ProtocolError,
+                "frame not allowed on a stream",
+            )),
+            _ => Ok(()),
+        };
+
+        let frame = match head.kind {
+            DATA => {
+                on_stream(true)?;
+                Frame::Data {
+                    end_stream: head.has(END_STREAM),
+                    data: unpad(head, payload)?,
+                }
+            }
+            HEADERS => {
+                on_stream(true)?;
+                let mut block = unpad(head, payload)?;
+                let mut priority = None;
+                if head.has(PRIORITY_FLAG) {
+                    if block.len() < 5 {
+                        return Err(Error::conn(ErrorCode::FrameSizeError, "short HEADERS"));
+                    }
+                    priority = Some(Priority {
+                        dependency: read_u32(block) & 0x7fff_ffff,
+                    });
+                    block = &block[5..];
+                }
+                Frame::Headers {
+                    end_stream: head.has(END_STREAM),
+                    end_headers: head.has(END_HEADERS),
+                    priority,
+                    block,
+                }
+            }
+            PRIORITY => {
+                on_stream(true)?;
+                if payload.len() != 5 {
+                    return Err(Error::stream(
+                        id,
+                        ErrorCode::FrameSizeError,
+                        "PRIORITY length is not 5",
+                    ));
+                }
+                Frame::Priority(Priority {
+                    dependency: read_u32(payload) & 0x7fff_ffff,
+                })
+            }
+            RST_STREAM => {
+                on_stream(true)?;
+                if payload.len() != 4 {
+                    return Err(Error::conn(
+                        ErrorCode::FrameSizeError,
+                        "RST_STREAM length is not 4",
+                    ));
+                }
+                Frame::RstStream(read_u32(payload))
+            }
+            SETTINGS => {
+                on_stream(false)?;
+                if head.has(ACK) && !payload.is_empty() {
+                    return Err(Error::conn(
+                        ErrorCode::FrameSizeError,
+                        "SETTINGS ack with a payload",
+                    ));
+                }
+                if !payload.len().is_multiple_of(6) {
+                    return Err(Error::conn(
+                        ErrorCode::FrameSizeError,
+                        "SETTINGS length is not a multiple of 6",
+                    ));
+                }
+                let params = payload
+                    .chunks(6)
+                    .map(|p| (u16::from_be_bytes([p[0], p[1]]), read_u32(&p[2..])))
+                    .collect();
+                Frame::Settings {
+                    ack: head.has(ACK),
+                    params,
+                }
+            }
+            PUSH_PROMISE => Frame::PushPromise,
+            PING => {
+                on_stream(false)?;
+                if payload.len() != 8 {
+                    return Err(Error::conn(
+                        ErrorCode::FrameSizeError,
+                        "PING length is not 8",
+                    ));
+                }
+                let mut data = [0; 8];
+                data.copy_from_slice(payload);
+                Frame::Ping {
+                    ack: head.has(ACK),
+                    data,
+                }
+            }
+            GOAWAY => {
+                on_stream(false)?;
+                if payload.len() < 8 {
+                    return Err(Error::conn(ErrorCode::FrameSizeError, "short GOAWAY"));
+                }
+                Frame::GoAway {
+                    last_stream_id: read_u32(payload) & 0x7fff_ffff,
+                    code: read_u32(&payload[4..]),
+                }
+            }
+            WINDOW_UPDATE => {
+                if payload.len() != 4 {
+                    return Err(Error::conn(
+                        ErrorCode::FrameSizeError,
+                        "WINDOW_UPDATE length is not 4",
+                    ));
+                }
+                let increment = read_u32(payload) & 0x7fff_ffff;
+                if increment == 0 {
+                    return Err(Error {
+                        stream_id: id,
+                        code: ErrorCode::ProtocolError,
+                        detail: "WINDOW_UPDATE increment is 0",
+                    });
+                }
+                Frame::WindowUpdate(increment)
+            }
+            CONTINUATION => {
+                on_stream(true)?;
+                Frame::Continuation {
+                    end_headers: head.has(END_HEADERS),
+                    block: payload,
+                }
+            }
+            _ => Frame::Unknown,
+        };
+
+        Ok(frame)
+    }
+}
+
+/// unpad removes the padding of a DATA or HEADERS payload.
+fn unpad(head: Head, payload: &[u8]) -> Result<&[u8], Error> {
+    if !head.has(PADDED) {
+        return Ok(payload);
+    }
+    match payload.split_first() {
+        Some((&pad, rest)) if (pad as usize) <= rest.len() => {
+            Ok(&rest[..rest.len() - pad as usize])
+        }
+        _ => Err(Error::conn(ErrorCode::ProtocolError, "invalid padding")),
+    }
+}
+
+fn read_u32(buf: &[u8]) -> u32 {
+    u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]])
+}
+
+/// write_settings appends a SETTINGS frame to out.
+pub(crate) fn write_settings(out: &mut Vec<u8>, params: &[(u16, u32)]) {
+    Head {
+        len: params.len() * 6,
+        kind: SETTINGS,
+        flags: 0,
+        stream_id: 0,
+    }
+    .write(out);
+    for (id, value) in params {
+        out.extend_from_slice(&id.to_be_bytes());
+        out.extend_from_slice(&value.to_be_bytes());
+    }
+}
+
+/// write_headers appends a header block to out as a HEADERS frame followed
+/// by as many CONTINUATION frames as needed.
+pub(crate) fn write_headers(
+    out: &mut Vec<u8>,
+    stream_id: StreamId,
+    block: &[u8],
+    end_stream: bool,
+    max_frame_size: usize,
+) {
+    let mut chunks = block.chunks(max_frame_size).peekable();
+    let mut kind = HEADERS;
+    let mut flags = if end_stream { END_STREAM } else { 0 };
+    loop {
+        let chunk = chunks.next().unwrap_or(&[]);
+        if chunks.peek().is_none() {
+            flags |= END_HEADERS;
+        }
+        Head {
+            len: chunk.len(),
+            kind,
+            flags,
+            stream_id,
+        }
+        .write(out);
+        out.extend_from_slice(chunk);
+        if flags & END_HEADERS != 0 {
+            return;
+        }
+        kind = CONTINUATION;
+        flags = 0;
+    }
+}
+
+/// write_data appends a DATA frame to out.
+pub(crate) fn write_data(out: &mut Vec<u8>, stream_id: StreamId, data: &[u8], end_stream: bool) {
+    Head {
+        len: data.len(),
+        kind: DATA,
+        flags: if end_stream { END_STREAM } else { 0 },
+        stream_id,
+    }
+    .write(out);
+    out.extend_from_slice(data);
+}
+
+/// write_u32 appends a frame whose payload is a single u32, as RST_STREAM
+/// and WINDOW_UPDATE frames are.
+pub(crate) fn write_u32(out: &mut Vec<u8>, kind: u8, stream_id: StreamId, value: u32) {
+    Head {
+        len: 4,
+        kind,
+        flags: 0,
+        stream_id,
+    }
+    .write(out);
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+/// write_ping appends a PING frame to out.
+pub(crate) fn write_ping(out: &mut Vec<u8>, data: [u8; 8], ack: bool) {
+    Head {
+        len: 8,
+        kind: PING,
+        flags: if ack { ACK } else { 0 },
+        stream_id: 0,
+    }
+    .write(out);
+    out.extend_from_slice(&data);
+}
+
+/// write_goaway appends a GOAWAY frame to out.
+pub(crate) fn write_goaway(out: &mut Vec<u8>, last_stream_id: StreamId, code: ErrorCode) {
+    Head {
+        len: 8,
+        kind: GOAWAY,
+        flags: 0,
+        stream_id: 0,
+    }
+    .write(out);
+    out.extend_from_slice(&last_stream_id.to_be_bytes());
+    out.extend_from_slice(&(code as u32).to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_frames() {
+        let mut out = Vec::new();
+        write_headers(&mut out, 3, &[1, 2, 3, 4, 5], true, 2);
+        let head = Head::parse(&out);
+        assert_eq!(
+            head,
+            Head {
+                len: 2,
+                kind: HEADERS,
+                flags: END_STREAM,
+                stream_id: 3
+            }
+        );
+        let continuations: Vec<Head> = (1..3).map(|i| Head::parse(&out[i * 11..])).collect();
+        assert_eq!(continuations[0].kind, CONTINUATION);
+        assert_eq!(continuations[1].flags, END_HEADERS);
+        assert_eq!(continuations[1].len, 1);
+
+        // a padded HEADERS frame with priority, RFC 7540 section 6.2
+        let head = Head {
+            len: 10,
+            kind: HEADERS,
+            flags: PADDED | PRIORITY_FLAG | END_HEADERS,
+            stream_id: 1,
+        };
+        let payload = [2, 0x80, 0, 0, 0, 16, 0x82, 0x84, 0, 0];
+        assert_eq!(
+            Frame::parse(head, &payload),
+            Ok(Frame::Headers {
+                end_stream: false,
+                end_headers: true,
+                priority: Some(Priority { dependency: 0 }),
+                block: &[0x82, 0x84],
+            })
+        );
+
+        let err = |kind, flags, stream_id, payload: &[u8]| {
+            let head = Head {
+                len: payload.len(),
+                kind,
+                flags,
+                stream_id,
+            };
+            Frame::parse(head, payload).unwrap_err()
+        };
+        assert_eq!(err(DATA, 0, 0, b"x").code, ErrorCode::ProtocolError);
+        assert_eq!(err(DATA, PADDED, 1, &[2, 0]).code, ErrorCode::ProtocolError);
+        assert_eq!(
+            err(SETTINGS, ACK, 0, &[0; 6]).code,
+            ErrorCode::FrameSizeError
+        );
+        assert_eq!(err(SETTINGS, 0, 0, &[0; 5]).code, ErrorCode::FrameSizeError);
+        assert_eq!(err(SETTINGS, 0, 1, &[]).code, ErrorCode::ProtocolError);
+        assert_eq!(err(PING, 0, 0, &[0; 7]).code, ErrorCode::FrameSizeError);
+        assert_eq!(err(PRIORITY, 0, 1, &[0; 4]).stream_id, 1);
+        assert_eq!(err(WINDOW_UPDATE, 0, 5, &[0; 4]).stream_id, 5);
+    }
+}
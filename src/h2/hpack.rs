@@ -0,0 +1,653 @@
+use std::collections::VecDeque;
+use std::fmt;
+
+use crate::h2::huffman;
+use crate::h2::Header;
+
+/// STATIC_TABLE is the HPACK static table of RFC 7541 Appendix A. Index 1 is
+/// its first entry.
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+/// ENTRY_OVERHEAD is the size counted for each entry of the dynamic table on
+/// top of its name and value.
+const ENTRY_OVERHEAD: usize = 32;
+
+/// DecodeError is a malformed header block, a COMPRESSION_ERROR.
+#[derive(Debug, PartialEq)]
+pub struct DecodeError(&'static str);
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "HPACK decoding error: {}", self.0)
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl DecodeError {
+    /// is_too_large reports whether the header list passed the Decoder's
+    /// max_list_size, rather than being malformed.
+    pub fn is_too_large(&self) -> bool {
+        self.0 == LIST_TOO_LARGE
+    }
+}
+
+const LIST_TOO_LARGE: &str = "header list too large";
+
+/// Table is an HPACK dynamic table, newest entry first.
+#[derive(Debug)]
+struct Table {
+    entries: VecDeque<Header>,
+    size: usize,
+    max_size: usize,
+}
+
+impl Table {
+    fn new(max_size: usize) -> Table {
+        Table {
+            entries: VecDeque::new(),
+            size: 0,
+            max_size,
+        }
+    }
+
+    /// get returns the entry at an index of the combined static and
+    /// dynamic tables.
+    fn get(&self, index: usize) -> Option<(&[u8], &[u8])> {
+        match index {
+            0 => None,
+            1..=61 => {
+                let (name, value) = STATIC_TABLE[index - 1];
+                Some((name.as_bytes(), value.as_bytes()))
+            }
+            _ => self
+                .entries
+                .get(index - 62)
+                .map(|(name, value)| (name.as_slice(), value.as_slice())),
+        }
+    }
+
+    /// find returns the index of an entry matching name and value, or else
+    /// of one matching name, preferring the static table.
+    fn find(&self, name: &[u8], value: &[u8]) -> Option<(usize, bool)> {
+        let mut by_name = None;
+        for (i, (n, v)) in STATIC_TABLE.iter().enumerate() {
+            if n.as_bytes() == name {
+                if v.as_bytes() == value {
+                    return Some((i + 1, true));
+                }
+                by_name = by_name.or(Some((i + 1, false)));
+            }
+        }
+        for (i, (n, v)) in self.entries.iter().enumerate() {
+            if n.as_slice() == name {
+                if v.as_slice() == value {
+                    return Some((i + 62, true));
+                }
+                by_name = by_name.or(Some((i + 62, false)));
+            }
+        }
+        by_name
+    }
+
+    /// insert adds an entry, evicting the oldest ones to make room for it.
+    fn insert(&mut self, name: Vec<u8>, value: Vec<u8>) {
+        let size = name.len() + value.len() + ENTRY_OVERHEAD;
+        self.evict(self.max_size.saturating_sub(size));
+        if size <= self.max_size {
+            self.size += size;
+            self.entries.push_front((name, value));
+        }
+    }
+
+    fn set_max_size(&mut self, max_size: usize) {
+        self.max_size = max_size;
+        self.evict(max_size);
+    }
+
+    /// evict removes the oldest entries until the table fits in size.
+    fn evict(&mut self, size: usize) {
+        while self.size > size {
+            match self.entries.pop_back() {
+                Some((name, value)) => self.size -= name.len() + value.len() + ENTRY_OVERHEAD,
+                None => break,
+            }
+        }
+    }
+}
+
+/// Decoder decodes the header blocks of a connection.
+#[derive(Debug)]
+pub struct Decoder {
+    table: Table,
+    /// max_size is the table size allowed by our SETTINGS_HEADER_TABLE_SIZE.
+    max_size: usize,
+    /// max_list_size is the largest header list decoded, as counted for
+    /// SETTINGS_MAX_HEADER_LIST_SIZE.
+    max_list_size: usize,
+}
+
+impl Decoder {
+    /// new returns a Decoder for a dynamic table of up to max_size.
+    pub fn new(max_size: usize) -> Decoder {
+        Decoder {
+            table: Table::new(max_size),
+            max_size,
+            max_list_size: usize::MAX,
+        }
+    }
+
+    /// set_max_list_size limits the size of the header lists decoded, the
+    /// sum of their name and value lengths plus 32 for each header. Small
+    /// blocks referencing large table entries would otherwise decode to
+    /// huge lists.
+    pub fn set_max_list_size(&mut self, max_list_size: usize) {
+        self.max_list_size = max_list_size;
+    }
+
+    /// decode decodes a complete header block. It fails as soon as the
+    /// header list grows past the max_list_size, after which the Decoder is
+    /// out of sync with the encoder.
+    pub fn decode(&mut self, mut block: &[u8]) -> Result<Vec<Header>, DecodeError> {
+        let mut headers = Vec::new();
+        let mut first = true;
+        let mut size = 0;
+        let max_list_size = self.max_list_size;
+        let mut add = |name: &[u8], value: &[u8]| {
+            size += name.len() + value.len() + ENTRY_OVERHEAD;
+            if size > max_list_size {
+                return Err(DecodeError(LIST_TOO_LARGE));
+            }
+            Ok(())
+        };
+
+        while let Some(&b) = block.first() {
+            if b & 0x80 != 0 {
+                // Indexed Header Field
+                let index = decode_int(&mut block, 7)?;
+                match self.table.get(index) {
+                    Some((name, value)) => {
+                        add(name, value)?;
+                        headers.push((name.to_vec(), value.to_vec()));
+                    }
+                    None => return Err(DecodeError("invalid index")),
+                }
+            } else if b & 0xe0 == 0x20 {
+                // Dynamic Table Size Update
+                if !first {
+                    return Err(DecodeError("table size update after a header"));
+                }
+                let size = decode_int(&mut block, 5)?;
+                if size > self.max_size {
+                    return Err(DecodeError("table size update above the limit"));
+                }
+                self.table.set_max_size(size);
+                continue;
+            } else {
+                // Literal Header Field with Incremental Indexing, without
+                // Indexing or Never Indexed
+                let indexing = b & 0x40 != 0;
+                let prefix = if indexing { 6 } else { 4 };
+                let name = match decode_int(&mut block, prefix)? {
+                    0 => decode_string(&mut block)?,
+                    index => match self.table.get(index) {
+                        Some((name, _)) => name.to_vec(),
+                        None => return Err(DecodeError("invalid index")),
+                    },
+                };
+                let value = decode_string(&mut block)?;
+                add(&name, &value)?;
+                if indexing {
+                    self.table.insert(name.clone(), value.clone());
+                }
+                headers.push((name, value));
+            }
+            first = false;
+        }
+
+        Ok(headers)
+    }
+}
+
+/// Encoder encodes the header blocks of a connection. It adds every header
+/// not found in its tables to its dynamic table, and uses Huffman coding
+/// whenever it is shorter.
+#[derive(Debug)]
+pub struct Encoder {
+    table: Table,
+    /// size_update is the table size to announce in the next header block.
+    size_update: Option<usize>,
+    pub(crate) huffman: bool,
+}
+
+impl Encoder {
+    /// new returns an Encoder for a dynamic table of up to max_size.
+    pub fn new(max_size: usize) -> Encoder {
+        Encoder {
+            table: Table::new(max_size),
+            size_update: None,
+            huffman: true,
+        }
+    }
+
+    /// set_max_size changes the size of the dynamic table, as when the peer
+    /// changes its SETTINGS_HEADER_TABLE_SIZE.
+    pub fn set_max_size(&mut self, max_size: usize) {
+        if max_size != self.table.max_size {
+            self.table.set_max_size(max_size);
+            self.size_update = Some(max_size);
+        }
+    }
+
+    /// encode appends the header block of headers to out.
+    pub fn encode(&mut self, headers: &[Header], out: &mut Vec<u8>) {
+        if let Some(size) = self.size_update.take() {
+            encode_int(out, 0x20, 5, size);
+        }
+        for (name, value) in headers {
+            match self.table.find(name, value) {
+                Some((index, true)) => encode_int(out, 0x80, 7, index),
+                Some((index, false)) => {
+                    encode_int(out, 0x40, 6, index);
+                    self.encode_string(out, value);
+                    self.table.insert(name.clone(), value.clone());
+                }
+                None => {
+                    out.push(0x40);
+                    self.encode_string(out, name);
+                    self.encode_string(out, value);
+                    self.table.insert(name.clone(), value.clone());
+                }
+            }
+        }
+    }
+
+    fn encode_string(&self, out: &mut Vec<u8>, s: &[u8]) {
+        let len = huffman::encoded_len(s);
+        if self.huffman && len <= s.len() {
+            encode_int(out, 0x80, 7, len);
+            huffman::encode(s, out);
+        } else {
+            encode_int(out, 0, 7, s.len());
+            out.extend_from_slice(s);
+        }
+    }
+}
+
+/// decode_int decodes an integer with an N-bit prefix, advancing buf.
+fn decode_int(buf: &mut &[u8], prefix: u32) -> Result<usize, DecodeError> {
+    let mask = (1 << prefix) - 1;
+    let (&first, mut rest) = match buf.split_first() {
+        Some(split) => split,
+        None => return Err(DecodeError("truncated integer")),
+    };
+    let mut value = first as usize & mask;
+    if value == mask {
+        let mut shift = 0;
+        loop {
+            let (&b, tail) = match rest.split_first() {
+                Some(split) => split,
+                None => return Err(DecodeError("truncated integer")),
+            };
+            rest = tail;
+            if shift > 28 {
+                return Err(DecodeError("integer overflow"));
+            }
+            value += ((b & 0x7f) as usize) << shift;
+            shift += 7;
+            if b & 0x80 == 0 {
+                break;
+            }
+        }
+    }
+    *buf = rest;
+    Ok(value)
+}
+
+/// encode_int appends value with an N-bit prefix, the other bits of the
+/// first byte set to flags.
+fn encode_int(out: &mut Vec<u8>, flags: u8, prefix: u32, mut value: usize) {
+    let mask = (1 << prefix) - 1;
+    if value < mask {
+        out.push(flags | value as u8);
+        return;
+    }
+    out.push(flags | mask as u8);
+    value -= mask;
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// decode_string decodes a string literal, advancing buf.
+fn decode_string(buf: &mut &[u8]) -> Result<Vec<u8>, DecodeError> {
+    let huffman = buf.first().is_some_and(|b| b & 0x80 != 0);
+    let len = decode_int(buf, 7)?;
+    if len > buf.len() {
+        return Err(DecodeError("truncated string"));
+    }
+    let (s, rest) = buf.split_at(len);
+    *buf = rest;
+    if huffman {
+        let mut out = Vec::with_capacity(len * 8 / 5);
+        huffman::decode(s, &mut out).map_err(|_| DecodeError("invalid Huffman code"))?;
+        Ok(out)
+    } else {
+        Ok(s.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        let s: String = s.split_whitespace().collect();
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn headers(list: &[(&str, &str)]) -> Vec<Header> {
+        list.iter()
+            .map(|(n, v)| (n.as_bytes().to_vec(), v.as_bytes().to_vec()))
+            .collect()
+    }
+
+    /// Block is a header block of an example: its encoding, its headers and
+    /// the dynamic table size after it.
+    type Block<'a> = (&'a str, &'a [(&'a str, &'a str)], usize);
+
+    /// check decodes and encodes each block of an RFC 7541 Appendix C
+    /// example in sequence, checking the dynamic table size after each.
+    fn check(huffman: bool, table_size: usize, blocks: &[Block]) {
+        let mut decoder = Decoder::new(table_size);
+        let mut encoder = Encoder::new(table_size);
+        encoder.huffman = huffman;
+        for (wire, list, size) in blocks {
+            let wire = hex(wire);
+            let list = headers(list);
+            assert_eq!(decoder.decode(&wire).unwrap(), list);
+            assert_eq!(decoder.table.size, *size);
+
+            let mut out = Vec::new();
+            encoder.encode(&list, &mut out);
+            assert_eq!(out, wire);
+            assert_eq!(encoder.table.size, *size);
+        }
+    }
+
+    #[test]
+    fn integers() {
+        // C.1.1, C.1.2 and C.1.3
+        for (prefix, value, wire) in &[(5, 10, "0a"), (5, 1337, "1f9a0a"), (8, 42, "2a")] {
+            let mut out = Vec::new();
+            encode_int(&mut out, 0, *prefix, *value);
+            assert_eq!(out, hex(wire));
+            assert_eq!(decode_int(&mut out.as_slice(), *prefix), Ok(*value));
+        }
+        assert!(decode_int(&mut &hex("1fffffffffff7f")[..], 5).is_err());
+        assert!(decode_int(&mut &hex("1f9a")[..], 5).is_err());
+    }
+
+    #[test]
+    fn literal_fields() {
+        // C.2.1 Literal Header Field with Indexing
+        let mut decoder = Decoder::new(4096);
+        let wire = hex("400a 6375 7374 6f6d 2d6b 6579 0d63 7573 746f 6d2d 6865 6164 6572");
+        assert_eq!(
+            decoder.decode(&wire).unwrap(),
+            headers(&[("custom-key", "custom-header")])
+        );
+        assert_eq!(decoder.table.size, 55);
+
+        // C.2.2 Literal Header Field without Indexing
+        let mut decoder = Decoder::new(4096);
+        let wire = hex("040c 2f73 616d 706c 652f 7061 7468");
+        assert_eq!(
+            decoder.decode(&wire).unwrap(),
+            headers(&[(":path", "/sample/path")])
+        );
+        assert_eq!(decoder.table.size, 0);
+
+        // C.2.3 Literal Header Field Never Indexed
+        let wire = hex("1008 7061 7373 776f 7264 0673 6563 7265 74");
+        assert_eq!(
+            decoder.decode(&wire).unwrap(),
+            headers(&[("password", "secret")])
+        );
+        assert_eq!(decoder.table.size, 0);
+
+        // C.2.4 Indexed Header Field
+        assert_eq!(
+            decoder.decode(&hex("82")).unwrap(),
+            headers(&[(":method", "GET")])
+        );
+    }
+
+    const REQUEST_1: &[(&str, &str)] = &[
+        (":method", "GET"),
+        (":scheme", "http"),
+        (":path", "/"),
+        (":authority", "www.example.com"),
+    ];
+    const REQUEST_2: &[(&str, &str)] = &[
+        (":method", "GET"),
+        (":scheme", "http"),
+        (":path", "/"),
+        (":authority", "www.example.com"),
+        ("cache-control", "no-cache"),
+    ];
+    const REQUEST_3: &[(&str, &str)] = &[
+        (":method", "GET"),
+        (":scheme", "https"),
+        (":path", "/index.html"),
+        (":authority", "www.example.com"),
+        ("custom-key", "custom-value"),
+    ];
+
+    #[test]
+    fn requests() {
+        // C.3 Request Examples without Huffman Coding
+        check(
+            false,
+            4096,
+            &[
+                (
+                    "8286 8441 0f77 7777 2e65 7861 6d70 6c65 2e63 6f6d",
+                    REQUEST_1,
+                    57,
+                ),
+                ("8286 84be 5808 6e6f 2d63 6163 6865", REQUEST_2, 110),
+                (
+                    "8287 85bf 400a 6375 7374 6f6d 2d6b 6579 0c63 7573 746f 6d2d 7661 6c75 65",
+                    REQUEST_3,
+                    164,
+                ),
+            ],
+        );
+
+        // C.4 Request Examples with Huffman Coding
+        check(
+            true,
+            4096,
+            &[
+                ("8286 8441 8cf1 e3c2 e5f2 3a6b a0ab 90f4 ff", REQUEST_1, 57),
+                ("8286 84be 5886 a8eb 1064 9cbf", REQUEST_2, 110),
+                (
+                    "8287 85bf 4088 25a8 49e9 5ba9 7d7f 8925 a849 e95b b8e8 b4bf",
+                    REQUEST_3,
+                    164,
+                ),
+            ],
+        );
+    }
+
+    const RESPONSE_1: &[(&str, &str)] = &[
+        (":status", "302"),
+        ("cache-control", "private"),
+        ("date", "Mon, 21 Oct 2013 20:13:21 GMT"),
+        ("location", "https://www.example.com"),
+    ];
+    const RESPONSE_2: &[(&str, &str)] = &[
+        (":status", "307"),
+        ("cache-control", "private"),
+        ("date", "Mon, 21 Oct 2013 20:13:21 GMT"),
+        ("location", "https://www.example.com"),
+    ];
+    const RESPONSE_3: &[(&str, &str)] = &[
+        (":status", "200"),
+        ("cache-control", "private"),
+        ("date", "Mon, 21 Oct 2013 20:13:22 GMT"),
+        ("location", "https://www.example.com"),
+        ("content-encoding", "gzip"),
+        (
+            "set-cookie",
+            "foo=ASDJKHQKBZXOQWEOPIUAXQWEOIU; max-age=3600; version=1",
+        ),
+    ];
+
+    #[test]
+    fn responses() {
+        // C.5 Response Examples without Huffman Coding, with evictions from
+        // a 256 byte table
+        check(
+            false,
+            256,
+            &[
+                (
+                    "4803 3330 3258 0770 7269 7661 7465 611d 4d6f 6e2c 2032 3120 4f63 7420
+                     3230 3133 2032 303a 3133 3a32 3120 474d 546e 1768 7474 7073 3a2f 2f77
+                     7777 2e65 7861 6d70 6c65 2e63 6f6d",
+                    RESPONSE_1,
+                    222,
+                ),
+                ("4803 3330 37c1 c0bf", RESPONSE_2, 222),
+                (
+                    "88c1 611d 4d6f 6e2c 2032 3120 4f63 7420 3230 3133 2032 303a 3133 3a32
+                     3220 474d 54c0 5a04 677a 6970 7738 666f 6f3d 4153 444a 4b48 514b 425a
+                     584f 5157 454f 5049 5541 5851 5745 4f49 553b 206d 6178 2d61 6765 3d33
+                     3630 303b 2076 6572 7369 6f6e 3d31",
+                    RESPONSE_3,
+                    215,
+                ),
+            ],
+        );
+
+        // C.6 Response Examples with Huffman Coding
+        check(
+            true,
+            256,
+            &[
+                (
+                    "4882 6402 5885 aec3 771a 4b61 96d0 7abe 9410 54d4 44a8 2005 9504 0b81
+                     66e0 82a6 2d1b ff6e 919d 29ad 1718 63c7 8f0b 97c8 e9ae 82ae 43d3",
+                    RESPONSE_1,
+                    222,
+                ),
+                ("4883 640e ffc1 c0bf", RESPONSE_2, 222),
+                (
+                    "88c1 6196 d07a be94 1054 d444 a820 0595 040b 8166 e084 a62d 1bff c05a
+                     839b d9ab 77ad 94e7 821d d7f2 e6c7 b335 dfdf cd5b 3960 d5af 2708 7f36
+                     72c1 ab27 0fb5 291f 9587 3160 65c0 03ed 4ee5 b106 3d50 07",
+                    RESPONSE_3,
+                    215,
+                ),
+            ],
+        );
+    }
+
+    #[test]
+    fn decode_errors() {
+        let mut decoder = Decoder::new(4096);
+        // index 0, an index past the tables and a truncated string
+        assert!(decoder.decode(&hex("80")).is_err());
+        assert!(decoder.decode(&hex("ff00")).is_err());
+        assert!(decoder.decode(&hex("4005 6162")).is_err());
+        // a table size update above the limit, or after a header
+        assert!(decoder.decode(&hex("3fe21f")).is_err());
+        assert!(decoder.decode(&hex("8220")).is_err());
+        assert!(decoder.decode(&hex("2082")).is_ok());
+    }
+
+    #[test]
+    fn max_list_size() {
+        // A 4000 byte entry added to the table, then referenced repeatedly.
+        let mut decoder = Decoder::new(4096);
+        decoder.set_max_list_size(16 << 10);
+        let mut block = hex("4001 78");
+        encode_int(&mut block, 0, 7, 3998);
+        block.extend_from_slice(&[b'y'; 3998]);
+        assert_eq!(decoder.decode(&block).unwrap()[0].1.len(), 3998);
+
+        let block = vec![0xbe; 4];
+        assert_eq!(decoder.decode(&block).unwrap().len(), 4);
+        let block = vec![0xbe; 64 << 10];
+        assert!(decoder.decode(&block).unwrap_err().is_too_large());
+    }
+}
@@ -0,0 +1,119 @@
+use std::fmt;
+
+mod frame;
+pub mod hpack;
+mod huffman;
+mod session;
+
+pub use self::session::Session;
+
+/// StreamId identifies an HTTP/2 stream of a connection.
+pub type StreamId = u32;
+
+/// Header is a header name and value, as received or sent.
+pub type Header = (Vec<u8>, Vec<u8>);
+
+/// PREFACE is the HTTP/2 client connection preface.
+pub(crate) const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// MAX_WINDOW is the largest flow control window.
+const MAX_WINDOW: i64 = (1 << 31) - 1;
+
+/// Settings are the HTTP/2 settings the Server advertises to its clients.
+#[derive(Clone, Copy, Debug)]
+pub struct Settings {
+    /// header_table_size is the size of the HPACK table used to decode
+    /// request headers.
+    pub header_table_size: u32,
+    /// max_concurrent_streams is the number of requests a client may have
+    /// open at the same time.
+    pub max_concurrent_streams: u32,
+    /// initial_window_size is the number of request body bytes a client may
+    /// send on a stream before it has to wait for a WINDOW_UPDATE. The same
+    /// window is used for the whole connection.
+    pub initial_window_size: u32,
+    /// max_frame_size is the largest frame payload a client may send.
+    pub max_frame_size: u32,
+    /// max_header_list_size is the largest header block, encoded or not, a
+    /// client may send.
+    pub max_header_list_size: u32,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            header_table_size: 4096,
+            max_concurrent_streams: 128,
+            initial_window_size: 1 << 20,
+            max_frame_size: 16_384,
+            max_header_list_size: 64 << 10,
+        }
+    }
+}
+
+/// ErrorCode is the reason of a RST_STREAM or GOAWAY, RFC 7540 section 7.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorCode {
+    NoError = 0x0,
+    ProtocolError = 0x1,
+    InternalError = 0x2,
+    FlowControlError = 0x3,
+    SettingsTimeout = 0x4,
+    StreamClosed = 0x5,
+    FrameSizeError = 0x6,
+    RefusedStream = 0x7,
+    Cancel = 0x8,
+    CompressionError = 0x9,
+    ConnectError = 0xa,
+    EnhanceYourCalm = 0xb,
+    InadequateSecurity = 0xc,
+    Http11Required = 0xd,
+}
+
+/// Error is an HTTP/2 protocol error. A stream_id of 0 makes it a connection
+/// error, which ends the connection with a GOAWAY. Other errors only reset
+/// their stream.
+#[derive(Debug, PartialEq)]
+pub struct Error {
+    pub stream_id: StreamId,
+    pub code: ErrorCode,
+    pub detail: &'static str,
+}
+
+impl Error {
+    pub(crate) fn conn(code: ErrorCode, detail: &'static str) -> Error {
+        Error {
+            stream_id: 0,
+            code,
+            detail,
+        }
+    }
+
+    pub(crate) fn stream(stream_id: StreamId, code: ErrorCode, detail: &'static str) -> Error {
+        Error {
+            stream_id,
+            code,
+            detail,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.stream_id == 0 {
+            write!(
+                f,
+                "HTTP/2 connection error {:?}: {}",
+                self.code, self.detail
+            )
+        } else {
+            write!(
+                f,
+                "HTTP/2 stream {} error {:?}: {}",
+                self.stream_id, self.code, self.detail
+            )
+        }
+    }
+}
+
+impl std::error::Error for Error {}
@@ -0,0 +1,1258 @@
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Write};
+use std::mem;
+use std::str;
+
+use crate::h2::frame::{self, Frame, Head, HEADER_LEN};
+use crate::h2::hpack::{Decoder, Encoder};
+use crate::h2::{Error, ErrorCode, Header, Settings, StreamId, MAX_WINDOW, PREFACE};
+use crate::request::Message;
+use crate::BuildHasher;
+
+/// DEFAULT_WINDOW is the initial flow control window of RFC 7540.
+const DEFAULT_WINDOW: i64 = 65_535;
+
+/// MIN_FRAME_SIZE and MAX_FRAME_SIZE bound SETTINGS_MAX_FRAME_SIZE.
+const MIN_FRAME_SIZE: u32 = 16_384;
+const MAX_FRAME_SIZE: u32 = (1 << 24) - 1;
+
+/// MAX_TABLE_SIZE caps the HPACK table used to encode responses, whatever
+/// size clients allow.
+const MAX_TABLE_SIZE: u32 = 4096;
+
+/// OUTPUT_LOW is the amount of buffered output below which more response
+/// data is framed, so that large bodies are sent as the client reads them
+/// instead of being copied to the output at once.
+const OUTPUT_LOW: usize = 64 << 10;
+
+/// BODY_TOO_LARGE is the reason a stream is reset for a request body past
+/// the max_body_size.
+const BODY_TOO_LARGE: &str = "request body too large";
+
+/// State is the state of an open stream, RFC 7540 section 5.1. Closed
+/// streams are removed from the Session.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    Open,
+    HalfClosedRemote,
+}
+
+struct Stream {
+    state: State,
+    headers: Vec<Header>,
+    body: Vec<u8>,
+    send_window: i64,
+    recv_window: i64,
+    /// data is the response body, sent up to sent.
+    data: Vec<u8>,
+    sent: usize,
+    /// streaming tells if more of a streamed response body is to come.
+    streaming: bool,
+}
+
+/// Block is a header block being received.
+struct Block {
+    stream_id: StreamId,
+    end_stream: bool,
+    /// self_dependent tells if the stream was made to depend on itself.
+    self_dependent: bool,
+    fragment: Vec<u8>,
+}
+
+/// Session is the server side of an HTTP/2 connection, RFC 7540. It parses
+/// the bytes received into requests and frames the responses into bytes to
+/// send, handling stream states, flow control and errors on its own.
+pub struct Session {
+    settings: Settings,
+    preface: bool,
+    /// peer_settings tells if the client's SETTINGS were received.
+    peer_settings: bool,
+    /// max_frame_size and initial_window are the client's settings.
+    max_frame_size: usize,
+    initial_window: i64,
+    decoder: Decoder,
+    encoder: Encoder,
+    streams: HashMap<StreamId, Stream, BuildHasher>,
+    last_stream_id: StreamId,
+    send_window: i64,
+    recv_window: i64,
+    block: Option<Block>,
+    /// sending lists the streams with response data to send, in turn.
+    sending: VecDeque<StreamId>,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    written: usize,
+    goaway: bool,
+    /// closing is the last stream id of the GOAWAY sent by shutdown.
+    closing: Option<StreamId>,
+    /// max_body_size is the largest request body accepted.
+    max_body_size: usize,
+}
+
+impl Session {
+    /// new starts a Session, queuing the server's SETTINGS.
+    pub fn new(mut settings: Settings) -> Session {
+        settings.max_frame_size = settings
+            .max_frame_size
+            .clamp(MIN_FRAME_SIZE, MAX_FRAME_SIZE);
+        settings.initial_window_size = cmp::min(settings.initial_window_size, MAX_WINDOW as u32);
+
+        let mut decoder = Decoder::new(settings.header_table_size as usize);
+        decoder.set_max_list_size(settings.max_header_list_size as usize);
+        let mut session = Session {
+            settings,
+            preface: false,
+            peer_settings: false,
+            max_frame_size: MIN_FRAME_SIZE as usize,
+            initial_window: DEFAULT_WINDOW,
+            decoder,
+            encoder: Encoder::new(MAX_TABLE_SIZE as usize),
+            streams: HashMap::default(),
+            last_stream_id: 0,
+            send_window: DEFAULT_WINDOW,
+            recv_window: DEFAULT_WINDOW,
+            block: None,
+            sending: VecDeque::new(),
+            input: Vec::new(),
+            output: Vec::new(),
+            written: 0,
+            goaway: false,
+            closing: None,
+            max_body_size: usize::MAX,
+        };
+
+        frame::write_settings(
+            &mut session.output,
+            &[
+                (
+                    frame::SETTINGS_HEADER_TABLE_SIZE,
+                    settings.header_table_size,
+                ),
+                (frame::SETTINGS_ENABLE_PUSH, 0),
+                (
+                    frame::SETTINGS_MAX_CONCURRENT_STREAMS,
+                    settings.max_concurrent_streams,
+                ),
+                (
+                    frame::SETTINGS_INITIAL_WINDOW_SIZE,
+                    settings.initial_window_size,
+                ),
+                (frame::SETTINGS_MAX_FRAME_SIZE, settings.max_frame_size),
+                (
+                    frame::SETTINGS_MAX_HEADER_LIST_SIZE,
+                    settings.max_header_list_size,
+                ),
+            ],
+        );
+        // The connection window can only grow with a WINDOW_UPDATE.
+        let increment = settings.initial_window_size as i64 - DEFAULT_WINDOW;
+        if increment > 0 {
+            frame::write_u32(
+                &mut session.output,
+                frame::WINDOW_UPDATE,
+                0,
+                increment as u32,
+            );
+            session.recv_window += increment;
+        }
+
+        session
+    }
+
+    /// upgrade takes over a connection upgraded from HTTP/1.1 with h2c, RFC
+    /// 7540 section 3.2. output is what the HTTP/1.1 session has left to
+    /// write, ending with its 101 response, and settings the decoded
+    /// HTTP2-Settings of the upgrade request, whose response is then sent on
+    /// stream 1.
+    pub(crate) fn upgrade(&mut self, mut output: Vec<u8>, settings: &[u8]) -> Result<(), Error> {
+        let head = Head {
+            len: settings.len(),
+            kind: frame::SETTINGS,
+            flags: 0,
+            stream_id: 0,
+        };
+        if let Frame::Settings { params, .. } = Frame::parse(head, settings)? {
+            self.apply_settings(&params)?;
+        }
+        output.extend_from_slice(&self.output[self.written..]);
+        self.output = output;
+        self.written = 0;
+
+        self.last_stream_id = 1;
+        self.streams.insert(
+            1,
+            Stream {
+                state: State::HalfClosedRemote,
+                headers: Vec::new(),
+                body: Vec::new(),
+                send_window: self.initial_window,
+                recv_window: 0,
+                data: Vec::new(),
+                sent: 0,
+                streaming: false,
+            },
+        );
+        Ok(())
+    }
+
+    /// set_max_body_size limits the size of request bodies. A stream whose
+    /// body, or content-length, is larger is reset with CANCEL.
+    pub fn set_max_body_size(&mut self, max_body_size: usize) {
+        self.max_body_size = max_body_size;
+    }
+
+    /// goaway tells if the client sent a GOAWAY.
+    pub fn goaway(&self) -> bool {
+        self.goaway
+    }
+
+    /// shutdown queues a GOAWAY with the last stream id received. The open
+    /// streams are still served, but new ones are refused, and the Session
+    /// is closed once they are done.
+    pub fn shutdown(&mut self) {
+        if self.closing.is_none() {
+            self.closing = Some(self.last_stream_id);
+            frame::write_goaway(&mut self.output, self.last_stream_id, ErrorCode::NoError);
+        }
+    }
+
+    /// is_closed tells if the Session was shut down, its streams are done
+    /// and its output is written, after which the connection should be
+    /// closed.
+    pub fn is_closed(&self) -> bool {
+        self.closing.is_some() && self.streams.is_empty() && self.written == self.output.len()
+    }
+
+    /// is_receiving tells if part of a frame, header block or request body
+    /// was received, but not all of it.
+    pub fn is_receiving(&self) -> bool {
+        !self.input.is_empty()
+            || self.block.is_some()
+            || self
+                .streams
+                .values()
+                .any(|stream| stream.state == State::Open)
+    }
+
+    /// recv handles bytes received from the client, adding the requests it
+    /// completes to requests. Stream errors reset their stream. On a
+    /// connection error, a GOAWAY is queued and the connection should be
+    /// closed once it is written.
+    pub fn recv(&mut self, bytes: &[u8], requests: &mut VecDeque<Message>) -> Result<(), Error> {
+        self.input.extend_from_slice(bytes);
+        let mut input = mem::take(&mut self.input);
+        let mut pos = 0;
+        let result = self.handle_input(&input, &mut pos, requests);
+        input.drain(..pos);
+        self.input = input;
+
+        if let Err(e) = &result {
+            frame::write_goaway(&mut self.output, self.last_stream_id, e.code);
+        }
+        result
+    }
+
+    /// respond queues the response of a stream. It is dropped if the client
+    /// reset the stream meanwhile.
+    pub fn respond(&mut self, stream_id: StreamId, headers: &[Header], body: Vec<u8>) {
+        let stream = match self.streams.get_mut(&stream_id) {
+            Some(stream) => stream,
+            None => {
+                debug!("stream {} was reset before its response", stream_id);
+                return;
+            }
+        };
+
+        let mut block = Vec::new();
+        self.encoder.encode(headers, &mut block);
+        frame::write_headers(
+            &mut self.output,
+            stream_id,
+            &block,
+            body.is_empty(),
+            self.max_frame_size,
+        );
+        if body.is_empty() {
+            self.streams.remove(&stream_id);
+        } else {
+            stream.data = body;
+            self.sending.push_back(stream_id);
+        }
+    }
+
+    /// respond_stream queues the headers of the response of a stream, whose
+    /// body follows with send_data.
+    pub fn respond_stream(&mut self, stream_id: StreamId, headers: &[Header]) {
+        let stream = match self.streams.get_mut(&stream_id) {
+            Some(stream) => stream,
+            None => {
+                debug!("stream {} was reset before its response", stream_id);
+                return;
+            }
+        };
+
+        let mut block = Vec::new();
+        self.encoder.encode(headers, &mut block);
+        frame::write_headers(
+            &mut self.output,
+            stream_id,
+            &block,
+            false,
+            self.max_frame_size,
+        );
+        stream.streaming = true;
+    }
+
+    /// send_data queues part of the body of a response started with
+    /// respond_stream, end telling if it is the last.
+    pub fn send_data(&mut self, stream_id: StreamId, data: &[u8], end: bool) {
+        let stream = match self.streams.get_mut(&stream_id) {
+            Some(stream) if stream.streaming => stream,
+            _ => return,
+        };
+        stream.data.drain(..stream.sent);
+        stream.sent = 0;
+        stream.data.extend_from_slice(data);
+        stream.streaming = !end;
+        if end && stream.data.is_empty() {
+            frame::write_data(&mut self.output, stream_id, &[], true);
+            self.streams.remove(&stream_id);
+        } else {
+            self.queue(stream_id);
+        }
+    }
+
+    /// abort resets a stream whose streamed response body failed, so the
+    /// client doesn't take the part sent for all of it.
+    pub fn abort(&mut self, stream_id: StreamId) {
+        if self.streams.contains_key(&stream_id) {
+            self.reset(Error::stream(
+                stream_id,
+                ErrorCode::InternalError,
+                "response body failed",
+            ));
+        }
+    }
+
+    /// buffered returns how much of the response body of a stream is queued
+    /// but not sent yet, or None once the stream is closed or reset.
+    pub fn buffered(&self, stream_id: StreamId) -> Option<usize> {
+        let stream = self.streams.get(&stream_id)?;
+        Some(stream.data.len() - stream.sent)
+    }
+
+    /// write_to writes the queued output to w until it would block, framing
+    /// response data as flow control allows.
+    pub fn write_to<W: Write>(&mut self, w: &mut W) -> io::Result<()> {
+        loop {
+            if self.output.len() - self.written < OUTPUT_LOW {
+                if self.written * 2 >= self.output.len() {
+                    self.output.drain(..self.written);
+                    self.written = 0;
+                }
+                self.frame_data();
+            }
+            if self.written == self.output.len() {
+                self.output.clear();
+                self.written = 0;
+                return Ok(());
+            }
+            match w.write(&self.output[self.written..]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => self.written += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// frame_data frames response data, one frame per stream in turn, until
+    /// enough output is buffered or the windows are exhausted.
+    fn frame_data(&mut self) {
+        while self.output.len() - self.written < OUTPUT_LOW && self.send_window > 0 {
+            let stream_id = match self.sending.pop_front() {
+                Some(stream_id) => stream_id,
+                None => return,
+            };
+            let stream = match self.streams.get_mut(&stream_id) {
+                Some(stream) if stream.send_window > 0 && stream.sent < stream.data.len() => stream,
+                // WINDOW_UPDATE or send_data queues the stream again.
+                _ => continue,
+            };
+
+            let window = cmp::min(self.send_window, stream.send_window) as usize;
+            let len = cmp::min(
+                stream.data.len() - stream.sent,
+                cmp::min(window, self.max_frame_size),
+            );
+            let end = stream.sent + len == stream.data.len() && !stream.streaming;
+            let data = &stream.data[stream.sent..stream.sent + len];
+            frame::write_data(&mut self.output, stream_id, data, end);
+            stream.sent += len;
+            stream.send_window -= len as i64;
+            self.send_window -= len as i64;
+            if end {
+                self.streams.remove(&stream_id);
+            } else if stream.sent < stream.data.len() {
+                self.sending.push_back(stream_id);
+            }
+        }
+    }
+
+    /// queue queues a stream with response data to send, unless it is queued.
+    fn queue(&mut self, stream_id: StreamId) {
+        let sending = match self.streams.get(&stream_id) {
+            Some(stream) => stream.sent < stream.data.len() && stream.send_window > 0,
+            None => false,
+        };
+        if sending && !self.sending.contains(&stream_id) {
+            self.sending.push_back(stream_id);
+        }
+    }
+
+    /// reset resets a stream after a stream error.
+    fn reset(&mut self, e: Error) {
+        debug!("{}", e);
+        self.streams.remove(&e.stream_id);
+        frame::write_u32(
+            &mut self.output,
+            frame::RST_STREAM,
+            e.stream_id,
+            e.code as u32,
+        );
+    }
+
+    fn handle_input(
+        &mut self,
+        input: &[u8],
+        pos: &mut usize,
+        requests: &mut VecDeque<Message>,
+    ) -> Result<(), Error> {
+        if !self.preface {
+            if input.len() < PREFACE.len() {
+                return Ok(());
+            }
+            if &input[..PREFACE.len()] != PREFACE {
+                return Err(Error::conn(ErrorCode::ProtocolError, "invalid preface"));
+            }
+            *pos = PREFACE.len();
+            self.preface = true;
+        }
+
+        loop {
+            let rest = &input[*pos..];
+            if rest.len() < HEADER_LEN {
+                return Ok(());
+            }
+            let head = Head::parse(rest);
+            if head.len > self.settings.max_frame_size as usize {
+                return Err(Error::conn(
+                    ErrorCode::FrameSizeError,
+                    "frame larger than SETTINGS_MAX_FRAME_SIZE",
+                ));
+            }
+            if rest.len() < HEADER_LEN + head.len {
+                return Ok(());
+            }
+            *pos += HEADER_LEN + head.len;
+
+            if !self.peer_settings && head.kind != frame::SETTINGS {
+                return Err(Error::conn(
+                    ErrorCode::ProtocolError,
+                    "first frame is not SETTINGS",
+                ));
+            }
+            let payload = &rest[HEADER_LEN..HEADER_LEN + head.len];
+            match self.handle_frame(head, payload, requests) {
+                Err(e) if e.stream_id != 0 => self.reset(e),
+                result => result?,
+            }
+        }
+    }
+
+    fn handle_frame(
+        &mut self,
+        head: Head,
+        payload: &[u8],
+        requests: &mut VecDeque<Message>,
+    ) -> Result<(), Error> {
+        let id = head.stream_id;
+        if let Some(block) = &self.block {
+            if head.kind != frame::CONTINUATION || id != block.stream_id {
+                return Err(Error::conn(
+                    ErrorCode::ProtocolError,
+                    "header block interrupted",
+                ));
+            }
+        }
+
+        match Frame::parse(head, payload)? {
+            Frame::Data { end_stream, data } => {
+                // Flow control counts padding too.
+                let len = head.len as i64;
+                if len > self.recv_window {
+                    return Err(Error::conn(
+                        ErrorCode::FlowControlError,
+                        "DATA exceeds the connection window",
+                    ));
+                }
+                self.recv_window -= len;
+                let result = self.recv_data(id, len, data, end_stream, requests);
+                // The data is consumed, added to a body or dropped with its
+                // stream, unless the connection is failing.
+                if len > 0 && result.as_ref().err().is_none_or(|e| e.stream_id != 0) {
+                    self.recv_window += len;
+                    frame::write_u32(&mut self.output, frame::WINDOW_UPDATE, 0, len as u32);
+                }
+                result?;
+            }
+            Frame::Headers {
+                end_stream,
+                end_headers,
+                priority,
+                block,
+            } => {
+                if id.is_multiple_of(2) {
+                    return Err(Error::conn(
+                        ErrorCode::ProtocolError,
+                        "even stream identifier",
+                    ));
+                }
+                if !self.streams.contains_key(&id) {
+                    if id <= self.last_stream_id {
+                        return Err(Error::conn(
+                            ErrorCode::StreamClosed,
+                            "HEADERS on a closed stream",
+                        ));
+                    }
+                    self.last_stream_id = id;
+                }
+                self.block = Some(Block {
+                    stream_id: id,
+                    end_stream,
+                    self_dependent: priority.is_some_and(|p| p.dependency == id),
+                    fragment: block.to_vec(),
+                });
+                if end_headers {
+                    self.end_headers(requests)?;
+                }
+            }
+            Frame::Continuation { end_headers, block } => {
+                let pending = match self.block.as_mut() {
+                    Some(pending) => pending,
+                    None => {
+                        return Err(Error::conn(
+                            ErrorCode::ProtocolError,
+                            "CONTINUATION without HEADERS",
+                        ))
+                    }
+                };
+                pending.fragment.extend_from_slice(block);
+                if pending.fragment.len() > self.settings.max_header_list_size as usize {
+                    return Err(Error::conn(
+                        ErrorCode::EnhanceYourCalm,
+                        "header block too large",
+                    ));
+                }
+                if end_headers {
+                    self.end_headers(requests)?;
+                }
+            }
+            Frame::Priority(priority) => {
+                if priority.dependency == id {
+                    return Err(Error::stream(
+                        id,
+                        ErrorCode::ProtocolError,
+                        "stream depends on itself",
+                    ));
+                }
+            }
+            Frame::RstStream(_) => {
+                if self.streams.remove(&id).is_none() && id > self.last_stream_id {
+                    return Err(Error::conn(
+                        ErrorCode::ProtocolError,
+                        "RST_STREAM on an idle stream",
+                    ));
+                }
+            }
+            Frame::Settings { ack, params } => {
+                if !ack {
+                    self.apply_settings(&params)?;
+                    frame::Head {
+                        len: 0,
+                        kind: frame::SETTINGS,
+                        flags: frame::ACK,
+                        stream_id: 0,
+                    }
+                    .write(&mut self.output);
+                    self.peer_settings = true;
+                }
+            }
+            Frame::PushPromise => {
+                return Err(Error::conn(
+                    ErrorCode::ProtocolError,
+                    "PUSH_PROMISE from a client",
+                ))
+            }
+            Frame::Ping { ack, data } => {
+                if !ack {
+                    frame::write_ping(&mut self.output, data, true);
+                }
+            }
+            Frame::GoAway { code, .. } => {
+                debug!("client sent GOAWAY with code {}", code);
+                self.goaway = true;
+            }
+            Frame::WindowUpdate(increment) => {
+                let increment = increment as i64;
+                if id == 0 {
+                    self.send_window += increment;
+                    if self.send_window > MAX_WINDOW {
+                        return Err(Error::conn(
+                            ErrorCode::FlowControlError,
+                            "connection window overflow",
+                        ));
+                    }
+                    return Ok(());
+                }
+                match self.streams.get_mut(&id) {
+                    Some(stream) => {
+                        stream.send_window += increment;
+                        if stream.send_window > MAX_WINDOW {
+                            return Err(Error::stream(
+                                id,
+                                ErrorCode::FlowControlError,
+                                "stream window overflow",
+                            ));
+                        }
+                        self.queue(id);
+                    }
+                    None if id > self.last_stream_id => {
+                        return Err(Error::conn(
+                            ErrorCode::ProtocolError,
+                            "WINDOW_UPDATE on an idle stream",
+                        ))
+                    }
+                    None => {}
+                }
+            }
+            Frame::Unknown => {}
+        }
+        Ok(())
+    }
+
+    /// recv_data adds the data of a DATA frame of len bytes, padding
+    /// included, to the body of its stream. The stream window is given back
+    /// once the data is added, until the body is complete.
+    fn recv_data(
+        &mut self,
+        id: StreamId,
+        len: i64,
+        data: &[u8],
+        end_stream: bool,
+        requests: &mut VecDeque<Message>,
+    ) -> Result<(), Error> {
+        let stream = match self.streams.get_mut(&id) {
+            Some(stream) if stream.state == State::Open => stream,
+            Some(_) => {
+                return Err(Error::stream(
+                    id,
+                    ErrorCode::StreamClosed,
+                    "DATA on a half-closed stream",
+                ))
+            }
+            None if id > self.last_stream_id => {
+                return Err(Error::conn(
+                    ErrorCode::ProtocolError,
+                    "DATA on an idle stream",
+                ))
+            }
+            None => {
+                return Err(Error::stream(
+                    id,
+                    ErrorCode::StreamClosed,
+                    "DATA on a closed stream",
+                ))
+            }
+        };
+        if len > stream.recv_window {
+            return Err(Error::stream(
+                id,
+                ErrorCode::FlowControlError,
+                "DATA exceeds the stream window",
+            ));
+        }
+        stream.recv_window -= len;
+        if stream.body.len() + data.len() > self.max_body_size {
+            return Err(Error::stream(id, ErrorCode::Cancel, BODY_TOO_LARGE));
+        }
+        stream.body.extend_from_slice(data);
+        if end_stream {
+            return self.end_stream(id, requests);
+        }
+        if len > 0 {
+            stream.recv_window += len;
+            frame::write_u32(&mut self.output, frame::WINDOW_UPDATE, id, len as u32);
+        }
+        Ok(())
+    }
+
+    /// end_headers handles a complete header block, opening a stream or
+    /// ending it with trailers.
+    fn end_headers(&mut self, requests: &mut VecDeque<Message>) -> Result<(), Error> {
+        let block = self.block.take().unwrap();
+        let id = block.stream_id;
+        // The block is decoded even for a stream about to be reset, to keep
+        // the HPACK table in sync with the client's.
+        let headers = match self.decoder.decode(&block.fragment) {
+            Ok(headers) => headers,
+            Err(e) if e.is_too_large() => {
+                return Err(Error::conn(
+                    ErrorCode::EnhanceYourCalm,
+                    "header list too large",
+                ));
+            }
+            Err(e) => {
+                debug!("{}", e);
+                return Err(Error::conn(
+                    ErrorCode::CompressionError,
+                    "invalid header block",
+                ));
+            }
+        };
+        if block.self_dependent {
+            return Err(Error::stream(
+                id,
+                ErrorCode::ProtocolError,
+                "stream depends on itself",
+            ));
+        }
+
+        match self.streams.get(&id).map(|stream| stream.state) {
+            Some(State::Open) => {
+                if !block.end_stream {
+                    return Err(Error::stream(
+                        id,
+                        ErrorCode::ProtocolError,
+                        "trailers without END_STREAM",
+                    ));
+                }
+                if headers
+                    .iter()
+                    .any(|(name, _)| name.starts_with(b":") || has_uppercase(name))
+                {
+                    return Err(Error::stream(
+                        id,
+                        ErrorCode::ProtocolError,
+                        "malformed trailers",
+                    ));
+                }
+                return self.end_stream(id, requests);
+            }
+            Some(State::HalfClosedRemote) => {
+                return Err(Error::stream(
+                    id,
+                    ErrorCode::StreamClosed,
+                    "HEADERS on a half-closed stream",
+                ))
+            }
+            None => {}
+        }
+
+        if self.closing.is_some() {
+            return Err(Error::stream(
+                id,
+                ErrorCode::RefusedStream,
+                "stream opened after GOAWAY",
+            ));
+        }
+        if self.streams.len() >= self.settings.max_concurrent_streams as usize {
+            return Err(Error::stream(
+                id,
+                ErrorCode::RefusedStream,
+                "too many concurrent streams",
+            ));
+        }
+        if let Err(detail) = check_headers(&headers) {
+            return Err(Error::stream(id, ErrorCode::ProtocolError, detail));
+        }
+        let content_length = headers
+            .iter()
+            .find(|(name, _)| name == b"content-length")
+            .and_then(|(_, value)| str::from_utf8(value).ok()?.parse::<u64>().ok());
+        if content_length.is_some_and(|len| len > self.max_body_size as u64) {
+            return Err(Error::stream(id, ErrorCode::Cancel, BODY_TOO_LARGE));
+        }
+
+        self.streams.insert(
+            id,
+            Stream {
+                state: State::Open,
+                headers,
+                body: Vec::new(),
+                send_window: self.initial_window,
+                recv_window: self.settings.initial_window_size as i64,
+                data: Vec::new(),
+                sent: 0,
+                streaming: false,
+            },
+        );
+        if block.end_stream {
+            self.end_stream(id, requests)?;
+        }
+        Ok(())
+    }
+
+    /// end_stream completes the request of a stream.
+    fn end_stream(&mut self, id: StreamId, requests: &mut VecDeque<Message>) -> Result<(), Error> {
+        let stream = self.streams.get_mut(&id).unwrap();
+        let content_length = stream
+            .headers
+            .iter()
+            .find(|(name, _)| name == b"content-length")
+            .map(|(_, value)| value.as_slice());
+        if let Some(value) = content_length {
+            if value != stream.body.len().to_string().as_bytes() {
+                return Err(Error::stream(
+                    id,
+                    ErrorCode::ProtocolError,
+                    "content-length does not match the body",
+                ));
+            }
+        }
+
+        stream.state = State::HalfClosedRemote;
+        requests.push_back(Message {
+            stream_id: id,
+            headers: mem::take(&mut stream.headers),
+            body: mem::take(&mut stream.body),
+            tls: None,
+        });
+        Ok(())
+    }
+
+    /// apply_settings applies the client's settings, RFC 7540 section 6.5.2.
+    fn apply_settings(&mut self, params: &[(u16, u32)]) -> Result<(), Error> {
+        for &(param, value) in params {
+            match param {
+                frame::SETTINGS_HEADER_TABLE_SIZE => {
+                    self.encoder
+                        .set_max_size(cmp::min(value, MAX_TABLE_SIZE) as usize);
+                }
+                frame::SETTINGS_ENABLE_PUSH if value > 1 => {
+                    return Err(Error::conn(
+                        ErrorCode::ProtocolError,
+                        "invalid SETTINGS_ENABLE_PUSH",
+                    ));
+                }
+                frame::SETTINGS_INITIAL_WINDOW_SIZE => {
+                    if value as i64 > MAX_WINDOW {
+                        return Err(Error::conn(
+                            ErrorCode::FlowControlError,
+                            "invalid SETTINGS_INITIAL_WINDOW_SIZE",
+                        ));
+                    }
+                    let delta = value as i64 - self.initial_window;
+                    self.initial_window = value as i64;
+                    let mut ids = Vec::new();
+                    for (&id, stream) in self.streams.iter_mut() {
+                        stream.send_window += delta;
+                        if stream.send_window > MAX_WINDOW {
+                            return Err(Error::conn(
+                                ErrorCode::FlowControlError,
+                                "stream window overflow",
+                            ));
+                        }
+                        ids.push(id);
+                    }
+                    for id in ids {
+                        self.queue(id);
+                    }
+                }
+                frame::SETTINGS_MAX_FRAME_SIZE => {
+                    if !(MIN_FRAME_SIZE..=MAX_FRAME_SIZE).contains(&value) {
+                        return Err(Error::conn(
+                            ErrorCode::ProtocolError,
+                            "invalid SETTINGS_MAX_FRAME_SIZE",
+                        ));
+                    }
+                    self.max_frame_size = value as usize;
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+fn has_uppercase(name: &[u8]) -> bool {
+    name.iter().any(|b| b.is_ascii_uppercase())
+}
+
+/// check_headers checks that request headers are well-formed, RFC 7540
+/// section 8.1.2.
+fn check_headers(headers: &[Header]) -> Result<(), &'static str> {
+    let mut regular = false;
+    let (mut method, mut scheme, mut path) = (0, 0, 0);
+    let mut connect = false;
+    for (name, value) in headers {
+        if has_uppercase(name) {
+            return Err("uppercase header name");
+        }
+        if name.starts_with(b":") {
+            if regular {
+                return Err("pseudo-header after a regular header");
+            }
+            match name.as_slice() {
+                b":method" => {
+                    method += 1;
+                    connect = value == b"CONNECT";
+                }
+                b":scheme" => scheme += 1,
+                b":path" if value.is_empty() => return Err("empty :path"),
+                b":path" => path += 1,
+                b":authority" => {}
+                _ => return Err("unknown pseudo-header"),
+            }
+        } else {
+            regular = true;
+            match name.as_slice() {
+                b"connection" | b"keep-alive" | b"proxy-connection" | b"transfer-encoding"
+                | b"upgrade" => return Err("connection-specific header"),
+                b"te" if value != b"trailers" => return Err("te other than trailers"),
+                _ => {}
+            }
+        }
+    }
+    if method != 1 || (!connect && (scheme != 1 || path != 1)) {
+        return Err("missing or repeated pseudo-header");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::h2::frame::*;
+
+    /// Client frames the client side of a test connection.
+    struct Client {
+        out: Vec<u8>,
+        encoder: Encoder,
+    }
+
+    impl Client {
+        fn new() -> Client {
+            let mut out = PREFACE.to_vec();
+            write_settings(&mut out, &[(SETTINGS_INITIAL_WINDOW_SIZE, 10)]);
+            Client {
+                out,
+                encoder: Encoder::new(4096),
+            }
+        }
+
+        fn headers(&mut self, id: StreamId, path: &str, end_stream: bool, max: usize) {
+            let headers = vec![
+                (b":method".to_vec(), b"POST".to_vec()),
+                (b":scheme".to_vec(), b"https".to_vec()),
+                (b":path".to_vec(), path.as_bytes().to_vec()),
+            ];
+            let mut block = Vec::new();
+            self.encoder.encode(&headers, &mut block);
+            write_headers(&mut self.out, id, &block, end_stream, max);
+        }
+
+        fn take(&mut self) -> Vec<u8> {
+            mem::take(&mut self.out)
+        }
+    }
+
+    /// frames parses the frames a Session wrote.
+    fn frames(session: &mut Session) -> Vec<(Head, Vec<u8>)> {
+        let mut out = Vec::new();
+        session.write_to(&mut out).unwrap();
+        let mut frames = Vec::new();
+        let mut rest = out.as_slice();
+        while !rest.is_empty() {
+            let head = Head::parse(rest);
+            frames.push((head, rest[HEADER_LEN..HEADER_LEN + head.len].to_vec()));
+            rest = &rest[HEADER_LEN + head.len..];
+        }
+        frames
+    }
+
+    #[test]
+    fn requests_and_flow_control() {
+        let mut session = Session::new(Settings::default());
+        let mut client = Client::new();
+        let mut requests = VecDeque::new();
+
+        // a request split across HEADERS, CONTINUATION and DATA frames, fed
+        // one byte at a time
+        client.headers(1, "/upload", false, 4);
+        write_data(&mut client.out, 1, b"hello", true);
+        for b in client.take() {
+            session.recv(&[b], &mut requests).unwrap();
+        }
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].body, b"hello");
+        assert_eq!(requests[0].headers[2].1, b"/upload");
+
+        let kinds: Vec<u8> = frames(&mut session).iter().map(|(h, _)| h.kind).collect();
+        assert_eq!(kinds, [SETTINGS, WINDOW_UPDATE, SETTINGS, WINDOW_UPDATE]);
+
+        // the client's initial window of 10 bytes holds the response back
+        session.respond(1, &[(b":status".to_vec(), b"200".to_vec())], vec![b'x'; 25]);
+        let sent = frames(&mut session);
+        assert_eq!(sent[0].0.kind, HEADERS);
+        assert_eq!(sent[1].0.len, 10);
+        assert_eq!(sent.len(), 2);
+
+        write_u32(&mut client.out, WINDOW_UPDATE, 1, 100);
+        session.recv(&client.take(), &mut requests).unwrap();
+        let sent = frames(&mut session);
+        assert_eq!(sent.len(), 1);
+        assert_eq!((sent[0].0.len, sent[0].0.flags), (15, END_STREAM));
+        assert!(session.streams.is_empty());
+
+        // PING is acknowledged with the same data
+        write_ping(&mut client.out, *b"zws ping", false);
+        session.recv(&client.take(), &mut requests).unwrap();
+        let sent = frames(&mut session);
+        assert_eq!((sent[0].0.kind, sent[0].0.flags), (PING, ACK));
+        assert_eq!(sent[0].1, b"zws ping");
+    }
+
+    #[test]
+    fn stream_errors() {
+        let mut session = Session::new(Settings::default());
+        let mut client = Client::new();
+        let mut requests = VecDeque::new();
+        session.recv(&client.take(), &mut requests).unwrap();
+        frames(&mut session);
+
+        // a connection-specific header makes a request malformed
+        let mut block = Vec::new();
+        client.encoder.encode(
+            &[
+                (b":method".to_vec(), b"GET".to_vec()),
+                (b":scheme".to_vec(), b"https".to_vec()),
+                (b":path".to_vec(), b"/".to_vec()),
+                (b"connection".to_vec(), b"close".to_vec()),
+            ],
+            &mut block,
+        );
+        write_headers(&mut client.out, 1, &block, true, 16_384);
+        // DATA on the half-closed stream 3
+        client.headers(3, "/", true, 16_384);
+        write_data(&mut client.out, 3, b"late", true);
+        session.recv(&client.take(), &mut requests).unwrap();
+
+        let resets: Vec<(StreamId, Vec<u8>)> = frames(&mut session)
+            .into_iter()
+            .filter(|(h, _)| h.kind == RST_STREAM)
+            .map(|(h, p)| (h.stream_id, p))
+            .collect();
+        assert_eq!(
+            resets,
+            [
+                (1, vec![0, 0, 0, ErrorCode::ProtocolError as u8]),
+                (3, vec![0, 0, 0, ErrorCode::StreamClosed as u8]),
+            ]
+        );
+        assert_eq!(requests.len(), 1);
+        // the response to the reset stream 3 is dropped
+        session.respond(3, &[(b":status".to_vec(), b"200".to_vec())], Vec::new());
+        assert!(frames(&mut session).is_empty());
+    }
+
+    #[test]
+    fn body_limits() {
+        let mut session = Session::new(Settings::default());
+        session.set_max_body_size(8);
+        let mut client = Client::new();
+        let mut requests = VecDeque::new();
+        session.recv(&client.take(), &mut requests).unwrap();
+        frames(&mut session);
+        let window = session.recv_window;
+
+        // the windows are given back once the data is added to the body
+        client.headers(1, "/", false, 16_384);
+        write_data(&mut client.out, 1, b"hello", false);
+        session.recv(&client.take(), &mut requests).unwrap();
+        let updates: Vec<(StreamId, Vec<u8>)> = frames(&mut session)
+            .into_iter()
+            .map(|(h, p)| (h.stream_id, p))
+            .collect();
+        assert_eq!(updates, [(1, vec![0, 0, 0, 5]), (0, vec![0, 0, 0, 5])]);
+        assert_eq!(session.recv_window, window);
+
+        // a body past the limit resets its stream, but the connection
+        // window is still given back
+        write_data(&mut client.out, 1, b"world", true);
+        // so is a content-length past the limit, before any DATA
+        let mut block = Vec::new();
+        client.encoder.encode(
+            &[
+                (b":method".to_vec(), b"POST".to_vec()),
+                (b":scheme".to_vec(), b"https".to_vec()),
+                (b":path".to_vec(), b"/".to_vec()),
+                (b"content-length".to_vec(), b"100".to_vec()),
+            ],
+            &mut block,
+        );
+        write_headers(&mut client.out, 3, &block, false, 16_384);
+        session.recv(&client.take(), &mut requests).unwrap();
+        let sent: Vec<(u8, StreamId, Vec<u8>)> = frames(&mut session)
+            .into_iter()
+            .map(|(h, p)| (h.kind, h.stream_id, p))
+            .collect();
+        assert_eq!(
+            sent,
+            [
+                (WINDOW_UPDATE, 0, vec![0, 0, 0, 5]),
+                (RST_STREAM, 1, vec![0, 0, 0, ErrorCode::Cancel as u8]),
+                (RST_STREAM, 3, vec![0, 0, 0, ErrorCode::Cancel as u8]),
+            ]
+        );
+        assert_eq!(session.recv_window, window);
+        assert!(requests.is_empty());
+        assert!(session.streams.is_empty());
+    }
+
+    #[test]
+    fn streamed_response() {
+        let mut session = Session::new(Settings::default());
+        let mut client = Client::new();
+        let mut requests = VecDeque::new();
+        client.headers(1, "/", true, 16_384);
+        session.recv(&client.take(), &mut requests).unwrap();
+        frames(&mut session);
+
+        // the client's initial window of 10 bytes holds the data back
+        session.respond_stream(1, &[(b":status".to_vec(), b"200".to_vec())]);
+        session.send_data(1, b"hello", false);
+        session.send_data(1, b" world", false);
+        let sent: Vec<(u8, u8, usize)> = frames(&mut session)
+            .into_iter()
+            .map(|(h, _)| (h.kind, h.flags, h.len))
+            .collect();
+        assert_eq!(sent[1..], [(DATA, 0, 10)]);
+        assert_eq!(session.buffered(1), Some(1));
+
+        write_u32(&mut client.out, WINDOW_UPDATE, 1, 100);
+        session.recv(&client.take(), &mut requests).unwrap();
+        session.send_data(1, b"", true);
+        let sent: Vec<(u8, u8, usize)> = frames(&mut session)
+            .into_iter()
+            .map(|(h, _)| (h.kind, h.flags, h.len))
+            .collect();
+        assert_eq!(sent, [(DATA, END_STREAM, 1)]);
+        assert_eq!(session.buffered(1), None);
+
+        // a failed body resets its stream
+        client.headers(3, "/", true, 16_384);
+        session.recv(&client.take(), &mut requests).unwrap();
+        session.respond_stream(3, &[(b":status".to_vec(), b"200".to_vec())]);
+        session.abort(3);
+        let sent = frames(&mut session);
+        let (h, payload) = sent.last().unwrap();
+        assert_eq!((h.kind, h.stream_id), (RST_STREAM, 3));
+        assert_eq!(payload[..], [0, 0, 0, ErrorCode::InternalError as u8]);
+        assert_eq!(session.buffered(3), None);
+    }
+
+    #[test]
+    fn connection_errors() {
+        let cases: Vec<(Vec<u8>, ErrorCode)> = vec![
+            // HEADERS interrupted by another frame
+            {
+                let mut out = Vec::new();
+                Head {
+                    len: 1,
+                    kind: HEADERS,
+                    flags: 0,
+                    stream_id: 1,
+                }
+                .write(&mut out);
+                out.push(0x82);
+                write_ping(&mut out, [0; 8], false);
+                (out, ErrorCode::ProtocolError)
+            },
+            // an even stream identifier
+            {
+                let mut client = Client::new();
+                client.out.clear();
+                client.headers(2, "/", true, 16_384);
+                (client.take(), ErrorCode::ProtocolError)
+            },
+            // a frame above SETTINGS_MAX_FRAME_SIZE
+            {
+                let mut out = Vec::new();
+                write_data(&mut out, 1, &[0; 16_385], true);
+                (out, ErrorCode::FrameSizeError)
+            },
+            // an invalid header block
+            {
+                let mut out = Vec::new();
+                write_headers(&mut out, 1, &[0xff, 0xff], true, 16_384);
+                (out, ErrorCode::CompressionError)
+            },
+            // a window above 2^31-1
+            {
+                let mut out = Vec::new();
+                write_u32(&mut out, WINDOW_UPDATE, 0, 0x7fff_ffff);
+                (out, ErrorCode::FlowControlError)
+            },
+        ];
+
+        for (bytes, code) in cases {
+            let mut session = Session::new(Settings::default());
+            let mut input = Client::new().take();
+            input.extend(bytes);
+            let err = session.recv(&input, &mut VecDeque::new()).unwrap_err();
+            assert_eq!(err.code, code, "{}", err);
+            let (head, payload) = frames(&mut session).pop().unwrap();
+            assert_eq!(head.kind, GOAWAY);
+            assert_eq!(payload[7], code as u8);
+        }
+
+        let mut session = Session::new(Settings::default());
+        let err = session
+            .recv(b"GET / HTTP/1.1\r\nHost: zws\r\n\r\n", &mut VecDeque::new())
+            .unwrap_err();
+        assert_eq!(err.code, ErrorCode::ProtocolError);
+    }
+
+    #[test]
+    fn graceful_shutdown() {
+        let mut session = Session::new(Settings::default());
+        let mut client = Client::new();
+        let mut requests = VecDeque::new();
+        client.headers(1, "/", true, 16384);
+        client.headers(3, "/", true, 16384);
+        session.recv(&client.take(), &mut requests).unwrap();
+        frames(&mut session);
+
+        session.shutdown();
+        let sent = frames(&mut session);
+        assert_eq!(sent[0].0.kind, GOAWAY);
+        assert_eq!(&sent[0].1[..8], [0, 0, 0, 3, 0, 0, 0, 0]);
+        assert!(!session.is_closed());
+
+        // streams opened after the GOAWAY are refused
+        client.headers(5, "/", true, 16384);
+        session.recv(&client.take(), &mut requests).unwrap();
+        let sent = frames(&mut session);
+        assert_eq!((sent[0].0.kind, sent[0].0.stream_id), (RST_STREAM, 5));
+        assert_eq!(sent[0].1, (ErrorCode::RefusedStream as u32).to_be_bytes());
+
+        session.respond(1, &[(b":status".to_vec(), b"200".to_vec())], Vec::new());
+        session.respond(3, &[(b":status".to_vec(), b"200".to_vec())], b"ok".to_vec());
+        assert!(!session.is_closed());
+        assert_eq!(frames(&mut session).len(), 3);
+        assert!(session.is_closed());
+    }
+}
@@ -1,19 +1,19 @@
 use std::collections::HashMap;
-use std::hash::BuildHasherDefault;
+#[cfg(feature = "async")]
+use std::future::Future;
 use std::path::Path;
+#[cfg(feature = "async")]
+use std::pin::Pin;
 use std::str;
 use std::sync::{mpsc, Arc, RwLock};
-use std::{fs, io, thread, time};
+use std::{fs, io, result, thread, time};
 
 use notify::{RecommendedWatcher, RecursiveMode, Watcher};
-use seahash::SeaHasher;
 
-use crate::error::Result;
+use crate::error::{HttpError, Result};
 use crate::request::Request;
 use crate::response::Response;
-
-/// BuildHasher lets us use SeaHasher with HashMap.
-type BuildHasher = BuildHasherDefault<SeaHasher>;
+use crate::BuildHasher;
 
 // Handler is a type that produces a Response for a given Request. The handle
 // method consumes the handler.
@@ -21,6 +21,54 @@ pub trait Handler: Send + Sync + 'static {
     fn handle(&self, req: Request, resp: Response) -> Response;
 }
 
+/// TryHandler is a fallible Handler. Its errors are turned into a Response by
+/// the Server's ErrorRenderer.
+pub trait TryHandler: Send + Sync + 'static {
+    type Error: Into<HttpError>;
+
+    fn try_handle(&self, req: Request, resp: Response) -> result::Result<Response, Self::Error>;
+}
+
+/// BoxFuture is the Future returned by an AsyncHandler.
+#[cfg(feature = "async")]
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// AsyncHandler is a Handler that awaits, instead of blocking a thread, while
+/// it waits on databases or other services. It is available with the async
+/// feature, which runs handlers on an async runtime. Synchronous Handlers run
+/// on the runtime's blocking threads, so they work unchanged.
+///
+/// Middleware is synchronous: an AsyncHandler wrapped by Middleware blocks a
+/// blocking thread until it is done, unwrapped ones do not.
+#[cfg(feature = "async")]
+pub trait AsyncHandler: Send + Sync + 'static {
+    fn handle<'a>(&'a self, req: Request<'a>, resp: Response) -> BoxFuture<'a, Response>;
+}
+
+/// Fallible adapts a TryHandler into a Handler.
+pub(crate) struct Fallible<H: TryHandler>(pub(crate) H);
+
+impl<H: TryHandler> Handler for Fallible<H> {
+    fn handle(&self, req: Request, resp: Response) -> Response {
+        let renderer = req.renderer;
+        let stream_id = req.stream_id;
+        let route = req.route;
+
+        match self.0.try_handle(req, resp) {
+            Ok(resp) => resp,
+            Err(e) => {
+                let err = e.into();
+                if err.status >= 500 {
+                    error!("handler for {} failed: {}", route, err);
+                } else {
+                    debug!("handler for {} failed: {}", route, err);
+                }
+                renderer.render(&err, Response::new(stream_id))
+            }
+        }
+    }
+}
+
 type Cache = Arc<RwLock<HashMap<String, Response, BuildHasher>>>;
 
 pub struct StaticFile {
@@ -54,7 +102,7 @@ impl StaticFile {
 impl Handler for StaticFile {
     fn handle(&self, req: Request, _resp: Response) -> Response {
         debug!("FileHandler: path is {}", &req.path);
-        let filename = format!("{}{}", self.webroot, &req.path);
+        let filename = format!("{}{}", self.webroot, req.relative_path());
         debug!("FileHandler: filename is {}", &filename);
 
         let mut response: Response;
@@ -67,14 +115,14 @@ impl Handler for StaticFile {
             } else {
                 debug!("StaticFile: cache miss for {}", &filename);
                 drop(read_guard);
-                let (resp, err) = file_response(&self.webroot, &filename);
+                let (resp, err) = file_response(&req.path, &filename);
                 response = resp.clone();
                 if !err {
                     cache.write().unwrap().insert(filename.clone(), resp);
                 }
             }
         } else {
-            response = file_response(&self.webroot, &filename).0;
+            response = file_response(&req.path, &filename).0;
         }
 
         response.stream_id(req.stream_id);
@@ -94,16 +142,16 @@ fn watch_fs(cache: Cache, webroot: &str) -> notify::Result<()> {
 
     // Add a path to be watched. All files and directories at that path and
     // below will be monitored for changes.
-    watcher.watch(&webroot, RecursiveMode::Recursive)?;
+    watcher.watch(webroot, RecursiveMode::Recursive)?;
 
     // This is a simple loop, but you may want to use more complex logic here,
     // for example to handle I/O.
-    let webroot_len = Path::new(&webroot)
+    let webroot_len = Path::new(webroot)
         .canonicalize()
         .unwrap()
         .to_string_lossy()
         .len()
-        - &webroot.len();
+        - webroot.len();
     loop {
         match rx.recv() {
             Ok(event) => match event {
@@ -124,12 +172,12 @@ fn watch_fs(cache: Cache, webroot: &str) -> notify::Result<()> {
     }
 }
 
-/// file_response produces a response for the given filename.
-fn file_response(webroot: &str, filename: &str) -> (Response, bool) {
+/// file_response produces a response for the given filename, requested as
+/// req_path.
+fn file_response(req_path: &str, filename: &str) -> (Response, bool) {
     let path = Path::new(&filename);
     if path.is_dir() {
-        let webroot_len = webroot.len() + 1;
-        let redirect = format!("{}/index.html", &filename[webroot_len..]);
+        let redirect = format!("{}/index.html", req_path.trim_end_matches('/'));
         debug!(
             "file_response: redirecting dir request without trailing slash to {}",
             &redirect
@@ -190,7 +238,7 @@ fn get_ctype(filename: &str) -> &str {
         }
     }
 
-    &ctype
+    ctype
 }
 
 /// NotFound is a handler that always returns a 404 Not Found Response.
@@ -232,3 +280,66 @@ where
         clone(req, resp)
     }
 }
+
+/// TryHandlerFunc is a closure returning a Result used as a TryHandler.
+pub struct TryHandlerFunc<F> {
+    func: F,
+}
+
+impl<F, E> TryHandlerFunc<F>
+where
+    F: FnOnce(Request, Response) -> result::Result<Response, E>,
+    F: Clone + Send + Sync + 'static,
+    E: Into<HttpError>,
+{
+    pub fn new(func: F) -> TryHandlerFunc<F> {
+        TryHandlerFunc { func }
+    }
+}
+
+impl<F, E> TryHandler for TryHandlerFunc<F>
+where
+    F: FnOnce(Request, Response) -> result::Result<Response, E>,
+    F: Clone + Send + Sync + 'static,
+    E: Into<HttpError>,
+{
+    type Error = E;
+
+    fn try_handle(&self, req: Request, resp: Response) -> result::Result<Response, E> {
+        let clone = self.func.clone();
+        clone(req, resp)
+    }
+}
+
+/// AsyncHandlerFunc lets a closure returning a BoxFuture be used as an
+/// AsyncHandler, as in `|req, resp| Box::pin(async move { ... })`.
+#[cfg(feature = "async")]
+pub struct AsyncHandlerFunc<F>
+where
+    F: for<'a> Fn(Request<'a>, Response) -> BoxFuture<'a, Response>,
+    F: Send + Sync + 'static,
+{
+    func: F,
+}
+
+#[cfg(feature = "async")]
+impl<F> AsyncHandlerFunc<F>
+where
+    F: for<'a> Fn(Request<'a>, Response) -> BoxFuture<'a, Response>,
+    F: Send + Sync + 'static,
+{
+    pub fn new(func: F) -> AsyncHandlerFunc<F> {
+        AsyncHandlerFunc { func }
+    }
+}
+
+#[cfg(feature = "async")]
+impl<F> AsyncHandler for AsyncHandlerFunc<F>
+where
+    F: for<'a> Fn(Request<'a>, Response) -> BoxFuture<'a, Response>,
+    F: Send + Sync + 'static,
+{
+    fn handle<'a>(&'a self, req: Request<'a>, resp: Response) -> BoxFuture<'a, Response> {
+        (self.func)(req, resp)
+    }
+}
@@ -2,19 +2,47 @@
 extern crate log;
 extern crate ctrlc;
 
+use std::hash::BuildHasherDefault;
+
+use seahash::SeaHasher;
+
+mod conn;
 pub mod error;
+mod h1;
+pub mod h2;
 pub mod handlers;
+mod listener;
+pub mod middleware;
+mod redirect;
 pub mod request;
 pub mod response;
+pub mod router;
+pub mod routes;
 pub mod server;
-pub mod tls;
+#[cfg(unix)]
+mod signal;
+#[cfg(unix)]
+mod systemd;
+mod tickets;
+mod tls;
+#[cfg(unix)]
+mod upgrade;
 pub mod workers;
 
-pub use error::Result;
-pub use handlers::{Handler, StaticFile};
+pub use error::{HttpError, Result};
+#[cfg(feature = "async")]
+pub use handlers::{AsyncHandler, AsyncHandlerFunc, BoxFuture};
+pub use handlers::{Handler, StaticFile, TryHandler};
+pub use listener::Mode;
+pub use middleware::{Middleware, MiddlewareFunc, Next};
 pub use request::{Action, Request};
 pub use response::Response;
-pub use server::Server;
+pub use routes::Routes;
+pub use server::{Server, ServerHandle, ShutdownReport};
+pub use tls::{ClientVerify, TlsProfile, TlsVersion};
+
+/// BuildHasher lets us use SeaHasher with HashMap.
+pub(crate) type BuildHasher = BuildHasherDefault<SeaHasher>;
 
 #[cfg(test)]
 mod tests {
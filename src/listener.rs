@@ -0,0 +1,366 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::PathBuf;
+
+use mio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use mio::unix::EventedFd;
+use mio::{Evented, Poll, PollOpt, Ready, Token};
+use net2::TcpBuilder;
+
+use crate::error::Result;
+#[cfg(unix)]
+use crate::upgrade;
+
+/// UNIX_PREFIX marks a listen address as the path of a Unix domain socket.
+pub(crate) const UNIX_PREFIX: &str = "unix:";
+
+/// BACKLOG is the length of the queue of connections waiting for accept.
+const BACKLOG: i32 = 1024;
+
+/// Mode is how a listener serves the connections it accepts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// Tls serves HTTP/2 and HTTP/1.1 over TLS.
+    Tls,
+    /// Plaintext serves cleartext HTTP/2 (h2c) and HTTP/1.1.
+    Plaintext,
+    /// Redirect serves plain HTTP, redirecting clients to HTTPS, and ACME
+    /// challenges.
+    Redirect,
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Mode::Tls => write!(f, "tls"),
+            Mode::Plaintext => write!(f, "plaintext"),
+            Mode::Redirect => write!(f, "redirect"),
+        }
+    }
+}
+
+/// Bound is a listening socket.
+enum Bound {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+/// Listener is a bound socket and the Mode of the connections it accepts.
+pub(crate) struct Listener {
+    pub(crate) mode: Mode,
+    bound: Bound,
+}
+
+impl Listener {
+    /// bind_all binds the addresses of listens, host:port pairs or unix:PATH
+    /// for a Unix domain socket. Host names bind every address they resolve
+    /// to. An IPv6 socket also accepts IPv4 clients unless an IPv4 address
+    /// is bound to the same port. The sockets inherited from systemd or on
+    /// an upgrade are adopted instead of bound for the addresses they have.
+    pub(crate) fn bind_all(listens: &[(String, Mode)]) -> Result<Vec<Listener>> {
+        let mut resolved = Vec::new();
+        for (addr, mode) in listens {
+            if let Some(path) = addr.strip_prefix(UNIX_PREFIX) {
+                resolved.push((Err(path), *mode));
+                continue;
+            }
+            let addrs = addr.to_socket_addrs().map_err(|e| {
+                io::Error::new(e.kind(), format!("error resolving {}: {}", addr, e))
+            })?;
+            resolved.extend(addrs.map(|a| (Ok(a), *mode)));
+        }
+        let ipv4_ports: Vec<u16> = resolved
+            .iter()
+            .filter_map(|(addr, _)| addr.ok().filter(SocketAddr::is_ipv4))
+            .map(|addr| addr.port())
+            .collect();
+
+        let mut listeners = Vec::new();
+        for (addr, mode) in resolved {
+            let addr = match addr {
+                Ok(addr) => addr,
+                Err(path) => {
+                    listeners.push(Listener::bind_unix(path, mode)?);
+                    continue;
+                }
+            };
+            let only_v6 = addr.port() != 0 && ipv4_ports.contains(&addr.port());
+            let listener = TcpListener::from_std(Listener::bind_tcp(addr, only_v6)?)?;
+            listeners.push(Listener {
+                mode,
+                bound: Bound::Tcp(listener),
+            });
+        }
+        #[cfg(unix)]
+        for addr in upgrade::unused() {
+            warn!("not listening on the inherited socket for {}", addr);
+        }
+        Ok(listeners)
+    }
+
+    /// bind_tcp binds a TCP socket to addr, or adopts the one the process
+    /// inherited for it on an upgrade.
+    fn bind_tcp(addr: SocketAddr, only_v6: bool) -> io::Result<std::net::TcpListener> {
+        #[cfg(unix)]
+        {
+            if let Some(fd) = upgrade::inherited(&addr.to_string()) {
+                debug!("adopting the inherited socket for {}", addr);
+                // SAFETY: the fd was handed over as a listening socket.
+                return Ok(unsafe { std::net::TcpListener::from_raw_fd(fd) });
+            }
+        }
+        let builder = match addr {
+            SocketAddr::V4(_) => TcpBuilder::new_v4()?,
+            SocketAddr::V6(_) => {
+                let builder = TcpBuilder::new_v6()?;
+                builder.only_v6(only_v6)?;
+                builder
+            }
+        };
+        builder.reuse_address(true)?;
+        builder.bind(addr)?;
+        builder.listen(BACKLOG)
+    }
+
+    /// bind_unix binds a Unix domain socket at path, replacing the socket
+    /// file a previous run left behind, or adopts the one the process
+    /// inherited for it on an upgrade.
+    #[cfg(unix)]
+    fn bind_unix(path: &str, mode: Mode) -> Result<Listener> {
+        let bound = |listener| Bound::Unix(listener, PathBuf::from(path));
+        if let Some(fd) = upgrade::inherited(&format!("{}{}", UNIX_PREFIX, path)) {
+            debug!("adopting the inherited socket for {}", path);
+            // SAFETY: the fd was handed over as a listening socket.
+            let listener = unsafe { UnixListener::from_raw_fd(fd) };
+            listener.set_nonblocking(true)?;
+            return Ok(Listener {
+                mode,
+                bound: bound(listener),
+            });
+        }
+        if let Ok(meta) = std::fs::symlink_metadata(path) {
+            if meta.file_type().is_socket() {
+                std::fs::remove_file(path)?;
+            }
+        }
+        let listener = UnixListener::bind(path)?;
+        listener.set_nonblocking(true)?;
+        Ok(Listener {
+            mode,
+            bound: bound(listener),
+        })
+    }
+
+    #[cfg(not(unix))]
+    fn bind_unix(path: &str, _mode: Mode) -> Result<Listener> {
+        let msg = format!("Unix domain sockets are not supported: {}", path);
+        Err(io::Error::new(io::ErrorKind::Unsupported, msg).into())
+    }
+
+    /// tcp_addr is the local address of a TCP Listener.
+    pub(crate) fn tcp_addr(&self) -> Option<SocketAddr> {
+        match &self.bound {
+            Bound::Tcp(listener) => listener.local_addr().ok(),
+            #[cfg(unix)]
+            Bound::Unix(..) => None,
+        }
+    }
+
+    /// handoff is the address and socket to hand to a new process on an
+    /// upgrade.
+    #[cfg(unix)]
+    pub(crate) fn handoff(&self) -> (String, RawFd) {
+        match &self.bound {
+            Bound::Tcp(listener) => {
+                let addr = listener.local_addr().map(|addr| addr.to_string());
+                (addr.unwrap_or_default(), listener.as_raw_fd())
+            }
+            Bound::Unix(listener, path) => (
+                format!("{}{}", UNIX_PREFIX, path.display()),
+                listener.as_raw_fd(),
+            ),
+        }
+    }
+
+    /// accept accepts a pending connection, failing with WouldBlock when
+    /// there is none.
+    pub(crate) fn accept(&self) -> io::Result<Socket> {
+        match &self.bound {
+            Bound::Tcp(listener) => listener.accept().map(|(stream, _)| Socket::Tcp(stream)),
+            #[cfg(unix)]
+            Bound::Unix(listener, _) => {
+                let (stream, _) = listener.accept()?;
+                stream.set_nonblocking(true)?;
+                Ok(Socket::Unix(stream))
+            }
+        }
+    }
+
+    /// register registers the Listener with poll for readable events.
+    pub(crate) fn register(&self, poll: &Poll, token: Token) -> io::Result<()> {
+        match &self.bound {
+            Bound::Tcp(listener) => {
+                poll.register(listener, token, Ready::readable(), PollOpt::edge())
+            }
+            #[cfg(unix)]
+            Bound::Unix(listener, _) => poll.register(
+                &EventedFd(&listener.as_raw_fd()),
+                token,
+                Ready::readable(),
+                PollOpt::edge(),
+            ),
+        }
+    }
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.bound {
+            Bound::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => write!(f, "{} ({})", addr, self.mode),
+                Err(_) => write!(f, "? ({})", self.mode),
+            },
+            #[cfg(unix)]
+            Bound::Unix(_, path) => write!(f, "{}{} ({})", UNIX_PREFIX, path.display(), self.mode),
+        }
+    }
+}
+
+/// Socket is an accepted non-blocking connection.
+pub(crate) enum Socket {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Socket {
+    /// shutdown closes the writing half of the connection.
+    pub(crate) fn shutdown(&self) -> io::Result<()> {
+        match self {
+            Socket::Tcp(stream) => stream.shutdown(Shutdown::Write),
+            #[cfg(unix)]
+            Socket::Unix(stream) => stream.shutdown(Shutdown::Write),
+        }
+    }
+}
+
+impl Read for Socket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Socket::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Socket::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Socket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Socket::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Socket::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Socket::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Socket::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+impl fmt::Debug for Socket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Socket::Tcp(stream) => stream.fmt(f),
+            #[cfg(unix)]
+            Socket::Unix(stream) => stream.fmt(f),
+        }
+    }
+}
+
+impl Evented for Socket {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        match self {
+            Socket::Tcp(stream) => stream.register(poll, token, interest, opts),
+            #[cfg(unix)]
+            Socket::Unix(stream) => {
+                EventedFd(&stream.as_raw_fd()).register(poll, token, interest, opts)
+            }
+        }
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        match self {
+            Socket::Tcp(stream) => stream.reregister(poll, token, interest, opts),
+            #[cfg(unix)]
+            Socket::Unix(stream) => {
+                EventedFd(&stream.as_raw_fd()).reregister(poll, token, interest, opts)
+            }
+        }
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        match self {
+            Socket::Tcp(stream) => stream.deregister(poll),
+            #[cfg(unix)]
+            Socket::Unix(stream) => EventedFd(&stream.as_raw_fd()).deregister(poll),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn binds_tcp_and_unix() {
+        let path = std::env::temp_dir().join(format!("zws-{}.sock", std::process::id()));
+        let unix = format!("{}{}", UNIX_PREFIX, path.display());
+        let listens = vec![
+            ("127.0.0.1:0".to_string(), Mode::Tls),
+            ("127.0.0.1:0".to_string(), Mode::Redirect),
+            (unix.clone(), Mode::Plaintext),
+        ];
+        let listeners = Listener::bind_all(&listens).unwrap();
+        assert_eq!(listeners.len(), 3);
+        assert!(listeners[0].tcp_addr().is_some());
+        assert_ne!(listeners[0].tcp_addr(), listeners[1].tcp_addr());
+        assert_eq!(listeners[2].tcp_addr(), None);
+        assert_eq!(listeners[2].to_string(), format!("{} (plaintext)", unix));
+
+        // A stale socket file doesn't keep the next run from binding.
+        drop(listeners);
+        let listeners = Listener::bind_all(&listens[2..]).unwrap();
+        std::os::unix::net::UnixStream::connect(&path).unwrap();
+        assert!(listeners[0].accept().is_ok());
+        std::fs::remove_file(path).unwrap();
+
+        assert!(Listener::bind_all(&[("nowhere:x".to_string(), Mode::Tls)]).is_err());
+    }
+}
@@ -0,0 +1,113 @@
+use std::sync::Arc;
+
+use crate::request::Request;
+use crate::response::Response;
+
+/// Middleware wraps request handling. It can inspect or modify the Request
+/// before passing it on with next.run, short-circuit by returning its own
+/// Response without calling next, or post-process the Response next returns.
+///
+/// Middleware registered with Builder::middleware run for every request,
+/// before routing, in registration order. Middleware registered with
+/// Routes::middleware run for the routes of the group, after routing, from
+/// the outermost group inwards, followed by those registered for a single
+/// route with Routes::wrap, and finally the route's handler.
+pub trait Middleware: Send + Sync + 'static {
+    fn handle<'a>(&self, req: Request<'a>, resp: Response, next: Next<'a>) -> Response;
+}
+
+/// Next is the rest of a middleware chain.
+pub struct Next<'a> {
+    chain: &'a [Arc<dyn Middleware>],
+    end: &'a dyn Fn(Request<'a>, Response) -> Response,
+}
+
+impl<'a> Next<'a> {
+    pub(crate) fn new(
+        chain: &'a [Arc<dyn Middleware>],
+        end: &'a dyn Fn(Request<'a>, Response) -> Response,
+    ) -> Next<'a> {
+        Next { chain, end }
+    }
+
+    /// run passes the Request on to the rest of the chain.
+    pub fn run(self, req: Request<'a>, resp: Response) -> Response {
+        match self.chain.split_first() {
+            Some((first, rest)) => first.handle(req, resp, Next::new(rest, self.end)),
+            None => (self.end)(req, resp),
+        }
+    }
+}
+
+/// MiddlewareFunc lets a closure be used as Middleware.
+pub struct MiddlewareFunc<F>
+where
+    F: for<'a> Fn(Request<'a>, Response, Next<'a>) -> Response,
+    F: Send + Sync + 'static,
+{
+    func: F,
+}
+
+impl<F> MiddlewareFunc<F>
+where
+    F: for<'a> Fn(Request<'a>, Response, Next<'a>) -> Response,
+    F: Send + Sync + 'static,
+{
+    pub fn new(func: F) -> MiddlewareFunc<F> {
+        MiddlewareFunc { func }
+    }
+}
+
+impl<F> Middleware for MiddlewareFunc<F>
+where
+    F: for<'a> Fn(Request<'a>, Response, Next<'a>) -> Response,
+    F: Send + Sync + 'static,
+{
+    fn handle<'a>(&self, req: Request<'a>, resp: Response, next: Next<'a>) -> Response {
+        (self.func)(req, resp, next)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::Message;
+
+    fn tag(name: &'static str) -> Arc<dyn Middleware> {
+        Arc::new(MiddlewareFunc::new(move |req, mut resp, next: Next| {
+            if req.path == "/stop" && name == "b" {
+                resp.set_body("stopped");
+                return resp;
+            }
+            let mut resp = next.run(req, resp);
+            let mut body = resp.body().to_vec();
+            body.extend(name.bytes());
+            resp.set_body(body);
+            resp
+        }))
+    }
+
+    #[test]
+    fn chain_order_and_short_circuit() {
+        let chain = vec![tag("a"), tag("b")];
+        let end = |_req: Request, mut resp: Response| {
+            resp.set_body("handled");
+            resp
+        };
+
+        for (path, body) in &[("/go", "handledba"), ("/stop", "stoppeda")] {
+            let msg = Message {
+                stream_id: 1,
+                headers: vec![
+                    (b":method".to_vec(), b"GET".to_vec()),
+                    (b":path".to_vec(), path.as_bytes().to_vec()),
+                ],
+                body: Vec::new(),
+                tls: None,
+            };
+            let req = Request::new(&msg).unwrap();
+            let resp = Next::new(&chain, &end).run(req, Response::new(1));
+            assert_eq!(resp.body(), body.as_bytes());
+        }
+    }
+}
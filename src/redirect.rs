@@ -0,0 +1,202 @@
+use std::fs;
+use std::io;
+use std::net::Ipv6Addr;
+use std::path::PathBuf;
+
+use crate::error::{ErrorRenderer, HttpError};
+use crate::request::{Message, Method, Request};
+use crate::response::Response;
+
+/// ACME_CHALLENGE is the path prefix of ACME HTTP-01 challenges, RFC 8555
+/// section 8.3.
+const ACME_CHALLENGE: &str = "/.well-known/acme-challenge/";
+
+/// Redirect answers the requests of Redirect listeners, sending clients to
+/// the HTTPS port, except for ACME challenges, which must be answered over
+/// HTTP.
+pub(crate) struct Redirect {
+    /// port is the HTTPS port redirected to.
+    port: u16,
+    /// acme_dir holds the ACME challenge files, named after their token.
+    acme_dir: Option<PathBuf>,
+}
+
+impl Redirect {
+    /// new returns a Redirect to port.
+    pub(crate) fn new(port: u16, acme_dir: Option<PathBuf>) -> Redirect {
+        Redirect { port, acme_dir }
+    }
+
+    /// respond answers a request with its ACME challenge file, or with a
+    /// redirect to the same host, path and query over HTTPS: 301 Moved
+    /// Permanently for GET and HEAD, and 308 Permanent Redirect, which keeps
+    /// the method and body, for others.
+    pub(crate) fn respond(&self, msg: &Message, renderer: &dyn ErrorRenderer) -> Response {
+        let mut resp = Response::new(msg.stream_id);
+        let req = match Request::new(msg) {
+            Ok(req) => req,
+            Err(e) => return renderer.render(&e.into(), resp),
+        };
+
+        if let (Some(dir), Some(token)) = (&self.acme_dir, req.path.strip_prefix(ACME_CHALLENGE)) {
+            let valid = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+            if token.is_empty() || !token.chars().all(valid) {
+                return renderer.render(&HttpError::not_found(""), resp);
+            }
+            return match fs::read(dir.join(token)) {
+                Ok(body) => {
+                    resp.add_header("content-type", "application/octet-stream");
+                    resp.set_body(body);
+                    if req.method == Method::HEAD {
+                        resp.strip_body(true);
+                    }
+                    resp
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                    renderer.render(&HttpError::not_found(""), resp)
+                }
+                Err(e) => {
+                    warn!("error reading ACME challenge {}: {}", token, e);
+                    renderer.render(&HttpError::new(500, ""), resp)
+                }
+            };
+        }
+
+        let authority = match req.header(":authority") {
+            Some(authority) => authority,
+            None => return renderer.render(&HttpError::new(400, "missing Host"), resp),
+        };
+        let host = match host(&authority) {
+            Some(host) => host,
+            None => return renderer.render(&HttpError::new(400, "invalid Host"), resp),
+        };
+        let mut location = match self.port {
+            443 => format!("https://{}{}", host, req.path),
+            port => format!("https://{}:{}{}", host, port, req.path),
+        };
+        if let Some(query) = &req.query {
+            location.push('?');
+            location.push_str(query);
+        }
+
+        let status = match req.method {
+            Method::GET | Method::HEAD => "301",
+            _ => "308",
+        };
+        resp.add_header(":status", status);
+        resp.add_header("location", &location);
+        resp
+    }
+}
+
+/// host strips the port off an authority, returning None unless the host
+/// is an IP literal or a reg-name, RFC 3986 section 3.2.2, which keeps it
+/// from changing the meaning of the redirect's location.
+fn host(authority: &str) -> Option<&str> {
+    let (host, port) = match authority.strip_prefix('[') {
+        Some(rest) => {
+            let bracket = rest.find(']')?;
+            rest[..bracket].parse::<Ipv6Addr>().ok()?;
+            (&authority[..bracket + 2], &rest[bracket + 1..])
+        }
+        None => match authority.find(':') {
+            Some(colon) => authority.split_at(colon),
+            None => (authority, ""),
+        },
+    };
+    let port_valid =
+        port.is_empty() || (port.starts_with(':') && port[1..].bytes().all(|b| b.is_ascii_digit()));
+    if host.is_empty() || !port_valid || !(host.starts_with('[') || is_reg_name(host)) {
+        return None;
+    }
+    Some(host)
+}
+
+/// is_reg_name tells if host is made of unreserved characters, percent
+/// escapes and sub-delims only.
+fn is_reg_name(host: &str) -> bool {
+    let bytes = host.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let escape = bytes.get(i + 1..i + 3);
+                if !escape.is_some_and(|hex| hex.iter().all(u8::is_ascii_hexdigit)) {
+                    return false;
+                }
+                i += 2;
+            }
+            b if b.is_ascii_alphanumeric() || b"-._~!$&'()*+,;=".contains(&b) => {}
+            _ => return false,
+        }
+        i += 1;
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::PlainText;
+
+    fn request(method: &str, path: &str, authority: &str) -> Message {
+        Message {
+            stream_id: 1,
+            headers: vec![
+                (b":method".to_vec(), method.as_bytes().to_vec()),
+                (b":path".to_vec(), path.as_bytes().to_vec()),
+                (b":authority".to_vec(), authority.as_bytes().to_vec()),
+            ],
+            body: Vec::new(),
+            tls: None,
+        }
+    }
+
+    #[test]
+    fn redirects_and_challenges() {
+        let dir = std::env::temp_dir().join(format!("zws-acme-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("tok_en-1"), "tok_en-1.thumbprint").unwrap();
+        let redirect = Redirect::new(8443, Some(dir.clone()));
+
+        let resp = redirect.respond(&request("GET", "/a/b?c=d", "example.com:80"), &PlainText);
+        assert_eq!(resp.header(":status"), Some("301"));
+        assert_eq!(
+            resp.header("location"),
+            Some("https://example.com:8443/a/b?c=d")
+        );
+
+        let resp = redirect.respond(&request("POST", "/form", "[::1]:8080"), &PlainText);
+        assert_eq!(resp.header(":status"), Some("308"));
+        assert_eq!(resp.header("location"), Some("https://[::1]:8443/form"));
+
+        for authority in [
+            "evil.com/x?",
+            "a@evil.com",
+            "example.com:80:80",
+            "example.com:x",
+            "[::1",
+            "[not-ip]",
+            "[::1]x",
+            ":80",
+            "exa mple.com",
+            "a%2",
+        ] {
+            let resp = redirect.respond(&request("GET", "/", authority), &PlainText);
+            assert_eq!(resp.header(":status"), Some("400"), "{}", authority);
+        }
+        let resp = redirect.respond(&request("GET", "/", "a%2Fb.example:"), &PlainText);
+        assert_eq!(resp.header("location"), Some("https://a%2Fb.example:8443/"));
+
+        let path = "/.well-known/acme-challenge/tok_en-1";
+        let resp = redirect.respond(&request("GET", path, "example.com"), &PlainText);
+        assert_eq!(resp.header(":status"), Some("200"));
+        assert_eq!(resp.body(), b"tok_en-1.thumbprint");
+
+        let path = "/.well-known/acme-challenge/..%2Fsecret";
+        let resp = redirect.respond(&request("GET", path, "example.com"), &PlainText);
+        assert_eq!(resp.header(":status"), Some("404"));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
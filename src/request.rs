@@ -1,37 +1,106 @@
-use std::collections::HashMap;
 use std::fmt;
 use std::hash::{Hash, Hasher};
-use std::path::PathBuf;
 use std::str::{self, FromStr};
+use std::sync::Arc;
 
-use solicit::http::session::DefaultStream;
-use solicit::http::{Header, StreamId};
-
-use crate::error::{Result, ServerError};
+use crate::error::{ErrorRenderer, PlainText, Result, ServerError};
+use crate::h2::{Header, StreamId};
+use crate::router::{percent_decode, Params};
+use crate::routes::Names;
 
 /// Method is an HTTP verb.
-#[derive(Clone, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum Method {
     GET,
+    POST,
+    PUT,
+    PATCH,
+    DELETE,
+    HEAD,
+    OPTIONS,
+}
+
+impl Method {
+    /// ALL lists every supported method, in the order used for Allow headers.
+    pub const ALL: [Method; 7] = [
+        Method::GET,
+        Method::HEAD,
+        Method::POST,
+        Method::PUT,
+        Method::PATCH,
+        Method::DELETE,
+        Method::OPTIONS,
+    ];
+
+    /// index returns the position of the method in ALL.
+    pub(crate) fn index(self) -> usize {
+        match self {
+            Method::GET => 0,
+            Method::HEAD => 1,
+            Method::POST => 2,
+            Method::PUT => 3,
+            Method::PATCH => 4,
+            Method::DELETE => 5,
+            Method::OPTIONS => 6,
+        }
+    }
+
+    /// as_str returns the canonical upper case name of the method.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Method::GET => "GET",
+            Method::POST => "POST",
+            Method::PUT => "PUT",
+            Method::PATCH => "PATCH",
+            Method::DELETE => "DELETE",
+            Method::HEAD => "HEAD",
+            Method::OPTIONS => "OPTIONS",
+        }
+    }
+}
+
+impl FromStr for Method {
+    type Err = ServerError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "GET" => Ok(Method::GET),
+            "POST" => Ok(Method::POST),
+            "PUT" => Ok(Method::PUT),
+            "PATCH" => Ok(Method::PATCH),
+            "DELETE" => Ok(Method::DELETE),
+            "HEAD" => Ok(Method::HEAD),
+            "OPTIONS" => Ok(Method::OPTIONS),
+            _ => Err(ServerError::NotImplemented(s.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
 }
 
-/// Action is an HTTP method and path combination.
+/// Action is an HTTP method and route path combination. Route paths are
+/// made of static segments, `:name` parameter segments and an optional
+/// trailing `*name` wildcard segment.
 #[derive(Clone, Debug)]
 pub struct Action {
     pub method: Method,
     pub path: String,
-    pub params: Option<HashMap<String, usize>>,
 }
 
 impl PartialEq for Action {
     fn eq(&self, other: &Self) -> bool {
-        self.path == other.path
+        self.method == other.method && self.path == other.path
     }
 }
 impl Eq for Action {}
 
 impl Hash for Action {
     fn hash<H: Hasher>(&self, state: &mut H) {
+        self.method.hash(state);
         self.path.hash(state);
     }
 }
@@ -49,43 +118,18 @@ impl FromStr for Action {
             )));
         }
 
-        let mut path = parts[1].to_string();
-        let mut params = None;
-
-        if path.contains(':') {
-            let path_parts: Vec<&str> = parts[1].split('/').collect();
-
-            // Extract params
-            let mut params_map = HashMap::new();
-            for (index, part) in path_parts.iter().enumerate() {
-                if part.contains(':') {
-                    params_map.insert(part[1..].to_string(), index);
-                }
-            }
-            params = Some(params_map);
-
-            // Extract path to match
-            path.clear();
-            for p in path_parts.iter().skip(1) {
-                if p.contains(':') {
-                    break;
-                }
-                path.push('/');
-                path.push_str(p);
-            }
-            if path.is_empty() {
-                path.push('/');
-            }
+        let path = parts[1].to_string();
+        if !path.starts_with('/') {
+            return Err(ServerError::ParseAction(format!(
+                "Request action paths start with '/', got {}",
+                path
+            )));
         }
-        debug!("from_str: path is {}, params is {:?}", path, params);
+        debug!("from_str: path is {}", path);
 
-        match &parts[0].to_uppercase()[..] {
-            "GET" => Ok(Action {
-                method: Method::GET,
-                path: path,
-                params: params,
-            }),
-            _ => Err(ServerError::ParseAction(format!(
+        match parts[0].to_uppercase().parse() {
+            Ok(method) => Ok(Action { method, path }),
+            Err(_) => Err(ServerError::ParseAction(format!(
                 "Request action verb not implemented: {}",
                 parts[0]
             ))),
@@ -93,137 +137,195 @@ impl FromStr for Action {
     }
 }
 
+/// TlsInfo describes the TLS session of the connection a request came on.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TlsInfo {
+    /// server_name is the name the client asked for with SNI, if any.
+    pub server_name: Option<String>,
+    /// client_cert is the certificate the client presented, if verified,
+    /// see Builder::client_verify.
+    pub client_cert: Option<ClientCert>,
+}
+
+/// ClientCert describes a verified TLS client certificate, to authorize
+/// clients by identity.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ClientCert {
+    /// subject is the subject distinguished name, its attributes in order,
+    /// as in "O=Example, CN=alice".
+    pub subject: String,
+    /// common_name is the CN attribute of the subject, if any.
+    pub common_name: Option<String>,
+    /// sans are the subject alternative names with their type, as in
+    /// "DNS:example.com", "email:alice@example.com", "URI:spiffe://a/b" or
+    /// "IP:10.0.0.1".
+    pub sans: Vec<String>,
+    /// fingerprint is the SHA-256 digest of the certificate, in lowercase
+    /// hexadecimal.
+    pub fingerprint: String,
+}
+
+/// Message is a request as received from a connection, before parsing.
+#[derive(Clone, Debug, Default)]
+pub struct Message {
+    pub stream_id: StreamId,
+    pub headers: Vec<Header>,
+    pub body: Vec<u8>,
+    /// tls is None for requests received without TLS.
+    pub tls: Option<Arc<TlsInfo>>,
+}
+
 /// Request represents a fully received request.
 pub struct Request<'a> {
-    pub action: Action,
+    pub method: Method,
+    /// route is the path of the Action that matched the request, empty until
+    /// the request is routed.
+    pub route: &'a str,
+    /// mount is the prefix the matched route was mounted under, "" for none.
+    pub mount: &'a str,
+    pub(crate) mount_depth: usize,
+    pub(crate) names: Option<&'a Names>,
+    pub(crate) renderer: &'a dyn ErrorRenderer,
     pub path: String,
-    pub params: Option<HashMap<String, String>>,
+    pub params: Params<'a>,
+    /// decoded holds the percent-decoded values of the params holding
+    /// escapes, see set_params.
+    decoded: Vec<(&'a str, String)>,
     pub query: Option<String>,
     pub stream_id: StreamId,
     pub headers: &'a [Header],
     pub body: &'a [u8],
+    /// tls describes the TLS session, None for requests received without TLS.
+    pub tls: Option<&'a TlsInfo>,
 }
 
 impl<'a> Request<'a> {
-    pub fn new(stream: &'a DefaultStream, actions: &Vec<Action>) -> Result<Request<'a>> {
-        let headers = match stream.headers.as_ref() {
-            Some(h) => h,
-            None => {
-                warn!("error, no HTTP/2 stream headers");
-                return Err(ServerError::BadRequest);
-            }
-        };
-
+    pub fn new(msg: &'a Message) -> Result<Request<'a>> {
         let mut req = Request {
-            action: Action {
-                method: Method::GET,
-                path: String::new(),
-                params: None,
-            },
+            method: Method::GET,
+            route: "",
+            mount: "",
+            mount_depth: 0,
+            names: None,
+            renderer: &PlainText,
             path: String::new(),
-            params: None,
+            params: Params::default(),
+            decoded: Vec::new(),
             query: None,
-            stream_id: stream.stream_id,
-            headers: headers,
-            body: &stream.body,
+            stream_id: msg.stream_id,
+            headers: &msg.headers,
+            body: &msg.body,
+            tls: msg.tls.as_deref(),
         };
 
-        let method = match req.header(":method") {
-            Some(method) => match method.as_str() {
-                "GET" => Method::GET,
-                _ => {
+        req.method = match req.header(":method") {
+            Some(method) => match method.parse() {
+                Ok(method) => method,
+                Err(e) => {
                     warn!("error, unsupported request method: {}", method);
-                    return Err(ServerError::BadRequest);
+                    return Err(e);
                 }
             },
             None => {
                 warn!("error, request without :method header");
-                return Err(ServerError::BadRequest);
+                return Err(ServerError::MissingHeader(":method".to_string()));
             }
         };
 
-        let path = match req.header(":path") {
+        match req.header(":path") {
             Some(path) => {
-                let parts: Vec<&str> = path.split('?').collect();
-                req.path = parts[0].to_string();
-                if parts.len() > 1 {
-                    req.query = Some(parts[1].to_string());
-                }
-                parts[0].to_string()
+                let mut parts = path.splitn(2, '?');
+                req.path = parts.next().unwrap_or("").to_string();
+                req.query = parts.next().map(|q| q.to_string());
             }
             None => {
                 warn!("error, request without :path header");
-                return Err(ServerError::BadRequest);
+                return Err(ServerError::MissingHeader(":path".to_string()));
             }
-        };
-
-        req.action = Action {
-            method,
-            path,
-            params: None,
-        };
+        }
 
-        let mut test_action = req.action.clone();
-        let mut action_path = PathBuf::from(&test_action.path);
-        let mut done = false;
+        debug!("new: Request: {}", &req);
+        Ok(req)
+    }
 
-        for action in actions {
-            if action == &test_action {
-                req.action = action.clone();
-                done = true;
-                break;
+    /// relative_path returns the request path relative to the mount point of
+    /// the matched route, which is the full path for routes not mounted.
+    pub fn relative_path(&self) -> &str {
+        let mut rest = self.path.as_str();
+        for _ in 0..self.mount_depth {
+            match rest[1..].find('/') {
+                Some(i) => rest = &rest[i + 1..],
+                None => return "/",
             }
         }
+        rest
+    }
 
-        if !done {
-            'WHILE: while action_path.pop() {
-                test_action = Action {
-                    method: Method::GET,
-                    path: action_path.to_string_lossy().to_string(),
-                    params: None,
-                };
-                for action in actions {
-                    if action == &test_action {
-                        req.action = action.clone();
-                        break 'WHILE;
-                    }
-                }
-            }
+    /// url_for generates the path of a named route, see Server::url_for.
+    pub fn url_for(&self, name: &str, params: &[(&str, &str)]) -> Result<String> {
+        match self.names {
+            Some(names) => names.url_for(name, params),
+            None => Err(ServerError::UnknownRoute(name.to_string())),
         }
+    }
 
-        if let Some(params) = &req.action.params {
-            let mut req_params = HashMap::new();
-            let path_parts: Vec<&str> = req.path.split('/').collect();
-            for (name, index) in params {
-                if index < &path_parts.len() {
-                    req_params.insert(name.to_string(), path_parts[*index].to_string());
-                }
-            }
-            if req_params.len() > 0 {
-                req.params = Some(req_params);
-            }
-        }
+    /// server_name returns the name the client asked for with SNI, which
+    /// chose the certificate presented.
+    pub fn server_name(&self) -> Option<&str> {
+        self.tls?.server_name.as_deref()
+    }
 
-        debug!("new: Request: {}", &req);
-        Ok(req)
+    /// client_cert returns the verified certificate the client presented, if
+    /// any.
+    pub fn client_cert(&self) -> Option<&ClientCert> {
+        self.tls?.client_cert.as_ref()
     }
 
     pub fn has_params(&self) -> bool {
-        self.params != None
+        !self.params.is_empty()
     }
 
+    /// set_params sets the route parameters of the request, decoding the
+    /// values holding percent-encoded octets.
+    pub(crate) fn set_params(&mut self, params: Params<'a>) {
+        let decoded = params
+            .iter(&self.path)
+            .filter(|(_, value)| value.contains('%'))
+            .map(|(name, value)| (name, percent_decode(value).into_owned()))
+            .collect();
+        self.params = params;
+        self.decoded = decoded;
+    }
+
+    /// param returns the percent-decoded value of the named route parameter,
+    /// or "" if the matched route has no such parameter.
     pub fn param(&self, name: &str) -> &str {
-        if let Some(params) = &self.params {
-            if let Some(value) = params.get(name) {
-                return &value;
-            }
+        self.param_value(name).unwrap_or("")
+    }
+
+    /// param_as parses the percent-decoded value of the named route parameter
+    /// as T.
+    pub fn param_as<T: FromStr>(&self, name: &str) -> Result<T> {
+        match self.param_value(name) {
+            Some(value) => value
+                .parse()
+                .map_err(|_| ServerError::InvalidParam(name.to_string(), value.to_string())),
+            None => Err(ServerError::MissingParam(name.to_string())),
+        }
+    }
+
+    /// param_value returns the percent-decoded value of the named route
+    /// parameter.
+    fn param_value(&self, name: &str) -> Option<&str> {
+        match self.decoded.iter().find(|(n, _)| *n == name) {
+            Some((_, value)) => Some(value),
+            None => self.params.get(name, &self.path),
         }
-        ""
     }
 
     pub fn header(&self, name: &str) -> Option<String> {
         for (key, value) in self.headers {
-            if key == &name.as_bytes() {
+            if key == name.as_bytes() {
                 return match str::from_utf8(value) {
                     Ok(sv) => Some(sv.to_string()),
                     Err(e) => {
@@ -241,8 +343,9 @@ impl<'a> fmt::Display for Request<'a> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
-            "{{ action: {:?}, path: '{}', params: {:?}, query: '{:?}, 'body_len: {} }}",
-            &self.action,
+            "{{ method: {}, route: '{}', path: '{}', params: {:?}, query: '{:?}, 'body_len: {} }}",
+            &self.method,
+            &self.route,
             &self.path,
             &self.params,
             &self.query,
@@ -250,3 +353,73 @@ impl<'a> fmt::Display for Request<'a> {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::router::{Lookup, Router};
+
+    /// message returns a GET request Message for path.
+    fn message(path: &str) -> Message {
+        Message {
+            headers: vec![
+                (b":method".to_vec(), b"GET".to_vec()),
+                (b":path".to_vec(), path.as_bytes().to_vec()),
+            ],
+            ..Message::default()
+        }
+    }
+
+    #[test]
+    fn parse_action_methods() {
+        let action: Action = "post /users".parse().unwrap();
+        assert_eq!(action.method, Method::POST);
+        assert_eq!(action.path, "/users");
+        assert!("TRACE /users".parse::<Action>().is_err());
+    }
+
+    #[test]
+    fn parse_action_requires_absolute_path() {
+        assert!("GET users".parse::<Action>().is_err());
+        assert!("GET".parse::<Action>().is_err());
+    }
+
+    #[test]
+    fn action_eq_includes_method() {
+        let get: Action = "GET /users".parse().unwrap();
+        let post: Action = "POST /users".parse().unwrap();
+        assert_ne!(get, post);
+
+        let mut router = HashMap::new();
+        router.insert(get, 1);
+        router.insert(post, 2);
+        assert_eq!(router.len(), 2);
+    }
+
+    #[test]
+    fn param_as() {
+        let mut router = Router::new();
+        router.insert(Method::GET, "/user/:id/:name", ()).unwrap();
+        let msg = message("/user/42/alice?x=1");
+        let mut req = Request::new(&msg).unwrap();
+        match router.lookup(req.method, &req.path) {
+            Lookup::Found(m) => req.set_params(m.params),
+            _ => panic!("no route for {}", req.path),
+        }
+
+        assert_eq!(req.param_as::<u32>("id").unwrap(), 42);
+        assert_eq!(req.param_as::<String>("name").unwrap(), "alice");
+        match req.param_as::<u32>("name") {
+            Err(ServerError::InvalidParam(name, value)) => {
+                assert_eq!((name.as_str(), value.as_str()), ("name", "alice"))
+            }
+            _ => panic!("expected InvalidParam"),
+        }
+        match req.param_as::<u32>("age") {
+            Err(ServerError::MissingParam(name)) => assert_eq!(name, "age"),
+            _ => panic!("expected MissingParam"),
+        }
+    }
+}
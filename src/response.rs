@@ -1,18 +1,44 @@
 use std::collections::HashMap;
-use std::convert::Into;
+use std::sync::Mutex;
 
-use solicit::http;
+use crate::h2::{Header, StreamId};
+
+/// Chunks is a streamed response body. Each chunk is sent as soon as it is
+/// produced: in DATA frames over HTTP/2, and with the chunked transfer
+/// coding over HTTP/1.1.
+pub type Chunks = Box<dyn Iterator<Item = Vec<u8>> + Send>;
+
+/// Body is the body of a Response, whole or streamed.
+pub(crate) enum Body {
+    Full(Vec<u8>),
+    Chunks(Chunks),
+}
 
-#[derive(Clone)]
 pub struct Response {
-    stream_id: http::StreamId,
+    stream_id: StreamId,
     pseudo_headers: HashMap<String, String>,
     headers: HashMap<String, String>,
     body: Vec<u8>,
+    /// chunks is in a Mutex for Responses to be Sync, like cached ones.
+    chunks: Option<Mutex<Chunks>>,
+}
+
+/// Cloning a Response with a streamed body leaves the clone without a body,
+/// as the chunks can only be sent once.
+impl Clone for Response {
+    fn clone(&self) -> Response {
+        Response {
+            stream_id: self.stream_id,
+            pseudo_headers: self.pseudo_headers.clone(),
+            headers: self.headers.clone(),
+            body: self.body.clone(),
+            chunks: None,
+        }
+    }
 }
 
 impl Response {
-    pub fn new(id: http::StreamId) -> Response {
+    pub fn new(id: StreamId) -> Response {
         Response {
             stream_id: id,
             pseudo_headers: [(":status".to_string(), "200".to_string())]
@@ -24,10 +50,11 @@ impl Response {
                 .cloned()
                 .collect(),
             body: Vec::new(),
+            chunks: None,
         }
     }
 
-    pub fn stream_id(&mut self, id: http::StreamId) {
+    pub fn stream_id(&mut self, id: StreamId) {
         self.stream_id = id;
     }
 
@@ -40,27 +67,77 @@ impl Response {
         }
     }
 
+    /// header returns the value of a header or pseudo header.
+    pub fn header(&self, key: &str) -> Option<&str> {
+        if key.starts_with(':') {
+            self.pseudo_headers.get(key).map(|v| v.as_str())
+        } else {
+            self.headers.get(key).map(|v| v.as_str())
+        }
+    }
+
     pub fn set_body<T: Into<Vec<u8>>>(&mut self, b: T) {
         self.body = b.into();
+        self.chunks = None;
     }
-}
 
-impl Into<http::Response> for Response {
-    fn into(self) -> http::Response {
-        let mut resp = http::Response {
-            stream_id: self.stream_id,
-            headers: Vec::new(),
-            body: self.body,
-        };
+    /// set_chunks streams the body from an iterator of chunks, whose next
+    /// chunk is produced on the handler threads once the handler returned and
+    /// whenever the client has taken most of the previous ones. Unless a
+    /// content-length header is set, HTTP/1.1 clients get the body with the
+    /// chunked transfer coding, and HTTP/1.0 ones until the connection closes.
+    /// If the iterator panics, the stream is reset, or the HTTP/1.1
+    /// connection closed, before the end of the body.
+    pub fn set_chunks<I>(&mut self, chunks: I)
+    where
+        I: IntoIterator<Item = Vec<u8>>,
+        I::IntoIter: Send + 'static,
+    {
+        self.body.clear();
+        self.chunks = Some(Mutex::new(Box::new(chunks.into_iter())));
+    }
 
-        self.pseudo_headers.into_iter().for_each(|(k, v)| {
-            resp.headers.push((k.into_bytes(), v.into_bytes()));
-        });
+    /// body returns the body set with set_body, empty for a streamed one.
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
 
-        self.headers.into_iter().for_each(|(k, v)| {
-            resp.headers.push((k.into_bytes(), v.into_bytes()));
-        });
+    /// strip_body removes the body, as required for responses to HEAD
+    /// requests. With length, as when a GET handler answered, its length is
+    /// kept in content-length, unless the handler set one or the status has
+    /// no body. A streamed body is replaced by an empty one instead, as its
+    /// length is unknown.
+    pub(crate) fn strip_body(&mut self, length: bool) {
+        if self.chunks.is_some() {
+            self.set_chunks(std::iter::empty());
+            return;
+        }
+        let status = self.header(":status").and_then(|s| s.parse::<u16>().ok());
+        let has_body = status.is_none_or(|s| s >= 200 && s != 204 && s != 304);
+        let set = self
+            .headers
+            .keys()
+            .any(|k| k.eq_ignore_ascii_case("content-length"));
+        if length && has_body && !set {
+            let len = self.body.len().to_string();
+            self.headers.insert("content-length".to_string(), len);
+        }
+        self.body.clear();
+    }
 
-        resp
+    /// into_parts splits the Response into its stream, headers, pseudo
+    /// headers first, and body, ready to be framed.
+    pub(crate) fn into_parts(self) -> (StreamId, Vec<Header>, Body) {
+        let headers = self
+            .pseudo_headers
+            .into_iter()
+            .chain(self.headers)
+            .map(|(k, v)| (k.to_ascii_lowercase().into_bytes(), v.into_bytes()))
+            .collect();
+        let body = match self.chunks {
+            Some(chunks) => Body::Chunks(chunks.into_inner().unwrap_or_else(|e| e.into_inner())),
+            None => Body::Full(self.body),
+        };
+        (self.stream_id, headers, body)
     }
 }
@@ -0,0 +1,689 @@
+use std::borrow::Cow;
+use std::fmt;
+
+use regex::Regex;
+
+use crate::error::{Result, ServerError};
+use crate::request::Method;
+
+/// MAX_PARAMS is the maximum number of parameters a single route can capture.
+pub const MAX_PARAMS: usize = 8;
+
+/// Params holds the parameters captured while matching a path. Names borrow
+/// from the Router and values are byte ranges into the matched path, so
+/// capturing them never allocates.
+#[derive(Clone, Copy, Default)]
+pub struct Params<'r> {
+    len: usize,
+    items: [(&'r str, usize, usize); MAX_PARAMS],
+}
+
+impl<'r> Params<'r> {
+    /// get returns the value of the named parameter within path.
+    pub fn get<'p>(&self, name: &str, path: &'p str) -> Option<&'p str> {
+        self.items[..self.len]
+            .iter()
+            .find(|(n, _, _)| *n == name)
+            .map(|(_, start, end)| &path[*start..*end])
+    }
+
+    /// iter returns the captured parameter names and values within path.
+    pub fn iter<'a, 'p: 'a>(
+        &'a self,
+        path: &'p str,
+    ) -> impl Iterator<Item = (&'r str, &'p str)> + 'a {
+        self.items[..self.len]
+            .iter()
+            .map(move |(n, start, end)| (*n, &path[*start..*end]))
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn push(&mut self, name: &'r str, start: usize, end: usize) {
+        self.items[self.len] = (name, start, end);
+        self.len += 1;
+    }
+
+    fn pop(&mut self) {
+        self.len -= 1;
+    }
+}
+
+impl<'r> fmt::Debug for Params<'r> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list()
+            .entries(self.items[..self.len].iter().map(|(n, s, e)| (n, s..e)))
+            .finish()
+    }
+}
+
+/// Match is a successful Router lookup.
+pub struct Match<'r, T> {
+    pub value: &'r T,
+    /// method is the method value was registered for: GET when a HEAD
+    /// request falls back to the GET route.
+    pub method: Method,
+    pub pattern: &'r str,
+    pub params: Params<'r>,
+}
+
+/// Lookup is the outcome of a Router lookup. Found is not boxed, to keep
+/// lookups free of allocations.
+#[allow(clippy::large_enum_variant)]
+pub enum Lookup<'r, T> {
+    Found(Match<'r, T>),
+    /// The path matched a route, but not for the requested method. Holds the
+    /// methods registered for that route, plus HEAD if GET is registered.
+    MethodNotAllowed(Vec<Method>),
+    NotFound,
+}
+
+/// Kind is the kind of path segment a Node matches.
+enum Kind {
+    Static(String),
+    Param(String, Option<Constraint>),
+    Wildcard(String),
+}
+
+/// Constraint restricts the values a parameter segment matches, either to
+/// those parsing as a primitive type, as in `:age{u32}`, or to those fully
+/// matching a regular expression, as in `:id{[0-9a-f]{8}}`. Values are
+/// checked once percent-decoded.
+pub(crate) struct Constraint {
+    spec: String,
+    check: Check,
+}
+
+enum Check {
+    Type(fn(&str) -> bool),
+    Regex(Regex),
+}
+
+impl Constraint {
+    pub(crate) fn parse(spec: &str) -> Result<Constraint> {
+        fn parses<T: std::str::FromStr>(s: &str) -> bool {
+            s.parse::<T>().is_ok()
+        }
+
+        let check = match spec {
+            "u8" => Check::Type(parses::<u8>),
+            "u16" => Check::Type(parses::<u16>),
+            "u32" => Check::Type(parses::<u32>),
+            "u64" => Check::Type(parses::<u64>),
+            "usize" => Check::Type(parses::<usize>),
+            "i8" => Check::Type(parses::<i8>),
+            "i16" => Check::Type(parses::<i16>),
+            "i32" => Check::Type(parses::<i32>),
+            "i64" => Check::Type(parses::<i64>),
+            "isize" => Check::Type(parses::<isize>),
+            "f32" => Check::Type(parses::<f32>),
+            "f64" => Check::Type(parses::<f64>),
+            "bool" => Check::Type(parses::<bool>),
+            _ => match Regex::new(&format!("^(?:{})$", spec)) {
+                Ok(re) => Check::Regex(re),
+                Err(e) => {
+                    return Err(ServerError::ParseAction(format!(
+                        "invalid parameter constraint {{{}}}: {}",
+                        spec, e
+                    )))
+                }
+            },
+        };
+
+        Ok(Constraint {
+            spec: spec.to_string(),
+            check,
+        })
+    }
+
+    pub(crate) fn matches(&self, value: &str) -> bool {
+        match &self.check {
+            Check::Type(parses) => parses(value),
+            Check::Regex(re) => re.is_match(value),
+        }
+    }
+}
+
+struct Node<T> {
+    kind: Kind,
+    pattern: String,
+    values: [Option<T>; Method::ALL.len()],
+    statics: Vec<Node<T>>,
+    /// params holds the parameter children, constrained ones first.
+    params: Vec<Node<T>>,
+    wildcard: Option<Box<Node<T>>>,
+}
+
+impl<T> Node<T> {
+    fn new(kind: Kind) -> Node<T> {
+        Node {
+            kind,
+            pattern: String::new(),
+            values: Default::default(),
+            statics: Vec::new(),
+            params: Vec::new(),
+            wildcard: None,
+        }
+    }
+
+    fn constraint(&self) -> Option<&Constraint> {
+        match &self.kind {
+            Kind::Param(_, constraint) => constraint.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// accepts reports whether a parameter node matches segment.
+    fn accepts(&self, segment: &str) -> bool {
+        !segment.is_empty()
+            && self
+                .constraint()
+                .is_none_or(|c| c.matches(&percent_decode(segment)))
+    }
+
+    fn is_route(&self) -> bool {
+        self.values.iter().any(|v| v.is_some())
+    }
+
+    /// value returns the value for method, falling back to GET for HEAD,
+    /// with the method it is registered for.
+    fn value(&self, method: Method) -> Option<(Method, &T)> {
+        match &self.values[method.index()] {
+            Some(value) => Some((method, value)),
+            None if method == Method::HEAD => self.values[Method::GET.index()]
+                .as_ref()
+                .map(|value| (Method::GET, value)),
+            None => None,
+        }
+    }
+
+    fn allowed(&self) -> Vec<Method> {
+        Method::ALL
+            .iter()
+            .cloned()
+            .filter(|m| self.value(*m).is_some())
+            .collect()
+    }
+
+    fn name(&self) -> &str {
+        match &self.kind {
+            Kind::Static(s) | Kind::Param(s, _) | Kind::Wildcard(s) => s,
+        }
+    }
+}
+
+/// Router is a segment based radix tree mapping Actions to values, usually
+/// handlers. Routes are made of static segments, `:name` parameter segments
+/// and an optional trailing `*name` wildcard capturing the rest of the path.
+/// Parameters may be constrained with a type or regular expression in braces,
+/// as in `/user/:age{u32}`, and do not match values failing the constraint.
+///
+/// At each segment static children are tried first, then the constrained
+/// parameter children in registration order, then the unconstrained
+/// parameter child, then the wildcard, backtracking when a branch does not
+/// lead to a route. When no route matches the whole path, the route at the
+/// longest static prefix of the path is used, so `GET /` serves every GET
+/// request no other route matches.
+pub struct Router<T> {
+    root: Node<T>,
+    methods: [bool; Method::ALL.len()],
+}
+
+impl<T> Default for Router<T> {
+    fn default() -> Self {
+        Router::new()
+    }
+}
+
+impl<T> Router<T> {
+    pub fn new() -> Router<T> {
+        Router {
+            root: Node::new(Kind::Static(String::new())),
+            methods: [false; Method::ALL.len()],
+        }
+    }
+
+    /// insert registers value for method and route pattern, returning the
+    /// value previously registered for them, if any.
+    pub fn insert(&mut self, method: Method, pattern: &str, value: T) -> Result<Option<T>> {
+        if !pattern.starts_with('/') {
+            return Err(ServerError::ParseAction(format!(
+                "route must start with '/': {}",
+                pattern
+            )));
+        }
+
+        let mut node = &mut self.root;
+        let mut count = 0;
+        let segments = pattern_segments(pattern)?;
+        let mut segments = segments.into_iter().peekable();
+
+        while let Some(segment) = segments.next() {
+            node = if let Some(param) = segment.strip_prefix(':') {
+                count += 1;
+                let (name, spec) = match param.find('{') {
+                    Some(i) if param.ends_with('}') => {
+                        (&param[..i], Some(&param[i + 1..param.len() - 1]))
+                    }
+                    _ => (param, None),
+                };
+                check_name(pattern, name)?;
+                let index = match node
+                    .params
+                    .iter()
+                    .position(|n| n.constraint().map(|c| c.spec.as_str()) == spec)
+                {
+                    Some(index) => index,
+                    None => {
+                        let constraint = match spec {
+                            Some(spec) => Some(Constraint::parse(spec)?),
+                            None => None,
+                        };
+                        let child = Node::new(Kind::Param(name.to_string(), constraint));
+                        // Keep the unconstrained parameter, if any, last.
+                        let index = match node.params.last() {
+                            Some(last) if spec.is_some() && last.constraint().is_none() => {
+                                node.params.len() - 1
+                            }
+                            _ => node.params.len(),
+                        };
+                        node.params.insert(index, child);
+                        index
+                    }
+                };
+                let child = &mut node.params[index];
+                if child.name() != name {
+                    return Err(ServerError::ParseAction(format!(
+                        "parameter :{} in {} conflicts with :{}",
+                        name,
+                        pattern,
+                        child.name()
+                    )));
+                }
+                child
+            } else if let Some(name) = segment.strip_prefix('*') {
+                count += 1;
+                check_name(pattern, name)?;
+                if segments.peek().is_some() {
+                    return Err(ServerError::ParseAction(format!(
+                        "wildcard *{} must be the last segment of {}",
+                        name, pattern
+                    )));
+                }
+                let child = node
+                    .wildcard
+                    .get_or_insert_with(|| Box::new(Node::new(Kind::Wildcard(name.to_string()))));
+                if child.name() != name {
+                    return Err(ServerError::ParseAction(format!(
+                        "wildcard *{} in {} conflicts with *{}",
+                        name,
+                        pattern,
+                        child.name()
+                    )));
+                }
+                child
+            } else {
+                let index = match node.statics.iter().position(|n| n.name() == segment) {
+                    Some(index) => index,
+                    None => {
+                        node.statics
+                            .push(Node::new(Kind::Static(segment.to_string())));
+                        node.statics.len() - 1
+                    }
+                };
+                &mut node.statics[index]
+            };
+        }
+
+        if count > MAX_PARAMS {
+            return Err(ServerError::ParseAction(format!(
+                "route {} has more than {} parameters",
+                pattern, MAX_PARAMS
+            )));
+        }
+
+        node.pattern = pattern.to_string();
+        self.methods[method.index()] = true;
+        Ok(node.values[method.index()].replace(value))
+    }
+
+    /// methods lists every method with at least one registered route, plus
+    /// HEAD when GET is registered.
+    pub fn methods(&self) -> Vec<Method> {
+        Method::ALL
+            .iter()
+            .cloned()
+            .filter(|m| {
+                self.methods[m.index()] || (*m == Method::HEAD && self.methods[Method::GET.index()])
+            })
+            .collect()
+    }
+
+    /// lookup finds the value registered for method and path.
+    pub fn lookup(&self, method: Method, path: &str) -> Lookup<'_, T> {
+        if !path.starts_with('/') {
+            return Lookup::NotFound;
+        }
+        let start = if path.len() > 1 { Some(1) } else { None };
+
+        let mut params = Params::default();
+        let mut any = None;
+        if let Some(node) = find(&self.root, path, start, method, &mut params, &mut any) {
+            if let Some((method, value)) = node.value(method) {
+                return Lookup::Found(Match {
+                    value,
+                    method,
+                    pattern: &node.pattern,
+                    params,
+                });
+            }
+        }
+        if let Some(node) = any {
+            return Lookup::MethodNotAllowed(node.allowed());
+        }
+
+        // Fall back to the longest static prefix with a route.
+        let mut node = &self.root;
+        let mut prefix = if node.is_route() { Some(node) } else { None };
+        for segment in segments(path) {
+            match node.statics.iter().find(|n| n.name() == segment) {
+                Some(child) => node = child,
+                None => break,
+            }
+            if node.is_route() {
+                prefix = Some(node);
+            }
+        }
+
+        match prefix {
+            Some(node) => match node.value(method) {
+                Some((method, value)) => Lookup::Found(Match {
+                    value,
+                    method,
+                    pattern: &node.pattern,
+                    params: Params::default(),
+                }),
+                None => Lookup::MethodNotAllowed(node.allowed()),
+            },
+            None => Lookup::NotFound,
+        }
+    }
+}
+
+/// find walks the tree looking for the route matching path from the segment
+/// starting at pos. It returns the first route with a value for method, and
+/// records in any the first route found regardless of method.
+fn find<'r, T>(
+    node: &'r Node<T>,
+    path: &str,
+    pos: Option<usize>,
+    method: Method,
+    params: &mut Params<'r>,
+    any: &mut Option<&'r Node<T>>,
+) -> Option<&'r Node<T>> {
+    let pos = match pos {
+        Some(pos) => pos,
+        None => {
+            if node.value(method).is_some() {
+                return Some(node);
+            }
+            if any.is_none() && node.is_route() {
+                *any = Some(node);
+            }
+            return find_wildcard(node, path, path.len(), method, params, any);
+        }
+    };
+
+    let (end, next) = match path[pos..].find('/') {
+        Some(i) => (pos + i, Some(pos + i + 1)),
+        None => (path.len(), None),
+    };
+    let segment = &path[pos..end];
+
+    if let Some(child) = node.statics.iter().find(|n| n.name() == segment) {
+        if let Some(found) = find(child, path, next, method, params, any) {
+            return Some(found);
+        }
+    }
+
+    for child in &node.params {
+        if child.accepts(segment) && params.len() < MAX_PARAMS {
+            params.push(child.name(), pos, end);
+            if let Some(found) = find(child, path, next, method, params, any) {
+                return Some(found);
+            }
+            params.pop();
+        }
+    }
+
+    find_wildcard(node, path, pos, method, params, any)
+}
+
+/// find_wildcard matches the rest of path, starting at pos, against the
+/// wildcard child of node.
+fn find_wildcard<'r, T>(
+    node: &'r Node<T>,
+    path: &str,
+    pos: usize,
+    method: Method,
+    params: &mut Params<'r>,
+    any: &mut Option<&'r Node<T>>,
+) -> Option<&'r Node<T>> {
+    let child = node.wildcard.as_ref()?;
+    if child.value(method).is_some() && params.len() < MAX_PARAMS {
+        params.push(child.name(), pos, path.len());
+        return Some(child);
+    }
+    if any.is_none() && child.is_route() {
+        *any = Some(child);
+    }
+    None
+}
+
+/// segments splits a path into its segments, ignoring the leading slash.
+fn segments(path: &str) -> impl Iterator<Item = &str> {
+    let path = path.strip_prefix('/').unwrap_or(path);
+    path.split('/').filter(move |_| !path.is_empty())
+}
+
+/// pattern_segments splits a route pattern into its segments, ignoring the
+/// leading slash and any slash within parameter constraint braces.
+pub(crate) fn pattern_segments(pattern: &str) -> Result<Vec<&str>> {
+    let mut segments = Vec::new();
+    let mut depth = 0usize;
+    let mut start = 1;
+
+    for (i, c) in pattern.char_indices().skip(1) {
+        match c {
+            '{' => depth += 1,
+            '}' if depth > 0 => depth -= 1,
+            '}' => return Err(unbalanced(pattern)),
+            '/' if depth == 0 => {
+                segments.push(&pattern[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    if depth != 0 {
+        return Err(unbalanced(pattern));
+    }
+    if pattern.len() > 1 {
+        segments.push(&pattern[start..]);
+    }
+
+    Ok(segments)
+}
+
+/// percent_decode decodes the percent-encoded octets of s, leaving invalid
+/// escapes as they are and replacing invalid UTF-8. It only allocates when s
+/// holds escapes.
+pub(crate) fn percent_decode(s: &str) -> Cow<'_, str> {
+    if !s.contains('%') {
+        return Cow::Borrowed(s);
+    }
+
+    let hex = |b: u8| (b as char).to_digit(16).map(|d| d as u8);
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escape = match bytes.get(i + 1..i + 3) {
+            Some(&[hi, lo]) if bytes[i] == b'%' => hex(hi).zip(hex(lo)),
+            _ => None,
+        };
+        match escape {
+            Some((hi, lo)) => {
+                decoded.push(hi << 4 | lo);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    Cow::Owned(String::from_utf8_lossy(&decoded).into_owned())
+}
+
+fn unbalanced(pattern: &str) -> ServerError {
+    ServerError::ParseAction(format!("unbalanced braces in route {}", pattern))
+}
+
+/// check_name validates a parameter or wildcard name.
+fn check_name(pattern: &str, name: &str) -> Result<()> {
+    if name.is_empty() {
+        return Err(ServerError::ParseAction(format!(
+            "unnamed parameter in route {}",
+            pattern
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn router() -> Router<usize> {
+        let mut router = Router::new();
+        let routes = [
+            "/",
+            "/hello",
+            "/user/new",
+            "/user/:id",
+            "/user/:id/posts",
+            "/static/*path",
+        ];
+        for (value, route) in routes.iter().enumerate() {
+            router.insert(Method::GET, route, value).unwrap();
+        }
+        router.insert(Method::POST, "/user/new", 10).unwrap();
+        router
+    }
+
+    fn found(router: &Router<usize>, method: Method, path: &str) -> (usize, Vec<(String, String)>) {
+        match router.lookup(method, path) {
+            Lookup::Found(m) => (
+                *m.value,
+                m.params
+                    .iter(path)
+                    .map(|(n, v)| (n.to_string(), v.to_string()))
+                    .collect(),
+            ),
+            _ => panic!("no route for {} {}", method, path),
+        }
+    }
+
+    #[test]
+    fn priority_and_params() {
+        let router = router();
+        assert_eq!(found(&router, Method::GET, "/").0, 0);
+        assert_eq!(found(&router, Method::GET, "/user/new").0, 2);
+        assert_eq!(found(&router, Method::POST, "/user/new").0, 10);
+
+        let (value, params) = found(&router, Method::GET, "/user/42/posts");
+        assert_eq!(value, 4);
+        assert_eq!(params, vec![("id".to_string(), "42".to_string())]);
+
+        let (value, params) = found(&router, Method::HEAD, "/static/css/site.css");
+        assert_eq!(value, 5);
+        assert_eq!(params[0].1, "css/site.css");
+        match router.lookup(Method::HEAD, "/") {
+            Lookup::Found(m) => assert_eq!(m.method, Method::GET),
+            _ => panic!("no route for HEAD /"),
+        }
+    }
+
+    #[test]
+    fn prefix_fallback_and_method_not_allowed() {
+        let router = router();
+        assert_eq!(found(&router, Method::GET, "/hello/world").0, 1);
+        assert_eq!(found(&router, Method::GET, "/nope").0, 0);
+
+        match router.lookup(Method::DELETE, "/user/42") {
+            Lookup::MethodNotAllowed(allowed) => {
+                assert_eq!(allowed, vec![Method::GET, Method::HEAD])
+            }
+            _ => panic!("expected MethodNotAllowed"),
+        }
+        match router.lookup(Method::GET, "*") {
+            Lookup::NotFound => {}
+            _ => panic!("expected NotFound"),
+        }
+    }
+
+    #[test]
+    fn constrained_params() {
+        let mut router = Router::new();
+        router.insert(Method::GET, "/user/:name", 0).unwrap();
+        router.insert(Method::GET, "/user/:age{u32}", 1).unwrap();
+        router
+            .insert(Method::GET, "/file/:id{[0-9a-f]{8}}/*path", 2)
+            .unwrap();
+
+        assert_eq!(found(&router, Method::GET, "/user/42").0, 1);
+        assert_eq!(found(&router, Method::GET, "/user/-42").0, 0);
+
+        let (value, params) = found(&router, Method::GET, "/file/deadbeef/a/b.txt");
+        assert_eq!(value, 2);
+        assert_eq!(params[0].1, "deadbeef");
+        assert_eq!(params[1].1, "a/b.txt");
+        match router.lookup(Method::GET, "/file/xyz/a") {
+            Lookup::NotFound => {}
+            _ => panic!("expected NotFound"),
+        }
+
+        assert!(router.insert(Method::GET, "/x/:id{[0-9}", 3).is_err());
+        assert!(router.insert(Method::GET, "/x/:id{[0-9]+", 3).is_err());
+    }
+
+    #[test]
+    fn percent_decoding() {
+        assert_eq!(percent_decode("a%20b%2Fc"), "a b/c");
+        assert_eq!(percent_decode("Jos%C3%A9"), "José");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz%4"), "%zz%4");
+        assert!(matches!(percent_decode("plain"), Cow::Borrowed("plain")));
+
+        let mut router = Router::new();
+        router.insert(Method::GET, "/tag/:tag{[a-z ]+}", 0).unwrap();
+        assert_eq!(found(&router, Method::GET, "/tag/a%20b").0, 0);
+    }
+
+    #[test]
+    fn insert_errors() {
+        let mut router = Router::new();
+        router.insert(Method::GET, "/a/:id", 0).unwrap();
+        assert!(router.insert(Method::GET, "/a/:name/b", 1).is_err());
+        assert!(router.insert(Method::GET, "/b/*rest/c", 1).is_err());
+        assert!(router.insert(Method::GET, "b", 1).is_err());
+        assert_eq!(router.insert(Method::GET, "/a/:id", 2).unwrap(), Some(0));
+    }
+}
@@ -0,0 +1,429 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+#[cfg(feature = "async")]
+use std::future::Future;
+use std::sync::Arc;
+#[cfg(feature = "async")]
+use std::task::{Context, Poll, Wake, Waker};
+#[cfg(feature = "async")]
+use std::thread::{self, Thread};
+
+use crate::error::{HttpError, Result, ServerError};
+#[cfg(feature = "async")]
+use crate::handlers::{AsyncHandler, AsyncHandlerFunc, BoxFuture};
+use crate::handlers::{Fallible, Handler, HandlerFunc, TryHandler, TryHandlerFunc};
+use crate::middleware::Middleware;
+use crate::request::{Action, Request};
+use crate::response::Response;
+use crate::router::{pattern_segments, Constraint};
+use crate::BuildHasher;
+
+/// Endpoint is the handler of a Route.
+pub(crate) enum Endpoint {
+    Sync(Box<dyn Handler>),
+    #[cfg(feature = "async")]
+    Async(Box<dyn AsyncHandler>),
+}
+
+impl Endpoint {
+    /// handle runs the handler. It waits for an AsyncHandler to be done, see
+    /// block_on, which is why the Server awaits those itself when it can.
+    pub(crate) fn handle(&self, req: Request, resp: Response) -> Response {
+        match self {
+            Endpoint::Sync(handler) => handler.handle(req, resp),
+            #[cfg(feature = "async")]
+            Endpoint::Async(handler) => block_on(handler.handle(req, resp)),
+        }
+    }
+}
+
+/// block_on waits for future from synchronous code, such as Middleware.
+/// Within a tokio runtime, the future runs on it, with its timers and I/O,
+/// and a worker thread calling hands its other tasks over first. Elsewhere
+/// the future is polled on the calling thread.
+#[cfg(feature = "async")]
+fn block_on<F: Future>(future: F) -> F::Output {
+    if let Ok(handle) = tokio::runtime::Handle::try_current() {
+        return tokio::task::block_in_place(|| handle.block_on(future));
+    }
+
+    /// Unpark wakes the thread polling the future.
+    struct Unpark(Thread);
+
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    let mut future = std::pin::pin!(future);
+    let waker = Waker::from(Arc::new(Unpark(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+/// Route is what the Server's Router maps an Action to.
+pub(crate) struct Route {
+    pub(crate) handler: Endpoint,
+    /// mount is the prefix the route was mounted under, "" for none.
+    pub(crate) mount: String,
+    /// depth is the number of path segments in mount.
+    pub(crate) depth: usize,
+    /// name is the name used to generate URLs for the route.
+    pub(crate) name: Option<String>,
+    /// middleware is the chain of Middleware run before handler.
+    pub(crate) middleware: Vec<Arc<dyn Middleware>>,
+}
+
+impl Route {
+    pub(crate) fn new(handler: Endpoint) -> Route {
+        Route {
+            handler,
+            mount: String::new(),
+            depth: 0,
+            name: None,
+            middleware: Vec::new(),
+        }
+    }
+}
+
+/// Routes is a group of routes that can be built separately, for example in
+/// its own module, and then attached to a Builder, or to another Routes,
+/// under a prefix with scope or mount.
+#[derive(Default)]
+pub struct Routes {
+    pub(crate) entries: Vec<(Action, Route)>,
+    middleware: Vec<Arc<dyn Middleware>>,
+}
+
+impl Routes {
+    pub fn new() -> Routes {
+        Routes {
+            entries: Vec::new(),
+            middleware: Vec::new(),
+        }
+    }
+
+    /// middleware registers Middleware for every route of the group,
+    /// including those registered later. It runs after routing, so it only
+    /// sees requests matching a route of the group.
+    pub fn middleware<M: Middleware>(mut self, middleware: M) -> Self {
+        self.middleware.push(Arc::new(middleware));
+        self
+    }
+
+    /// wrap registers Middleware for the most recently registered route. It
+    /// runs after the group Middleware.
+    pub fn wrap<M: Middleware>(mut self, middleware: M) -> Result<Self> {
+        match self.entries.last_mut() {
+            Some((_, route)) => route.middleware.push(Arc::new(middleware)),
+            None => {
+                return Err(ServerError::ParseAction(
+                    "no route to wrap with middleware".to_string(),
+                ))
+            }
+        }
+        Ok(self)
+    }
+
+    /// handler registers a handler for a given Action.
+    pub fn handler<H: Handler>(mut self, action: &str, handler: H) -> Result<Self> {
+        self.entries.push((
+            action.parse()?,
+            Route::new(Endpoint::Sync(Box::new(handler))),
+        ));
+        Ok(self)
+    }
+
+    /// async_handler registers an AsyncHandler for a given Action.
+    #[cfg(feature = "async")]
+    pub fn async_handler<H: AsyncHandler>(mut self, action: &str, handler: H) -> Result<Self> {
+        self.entries.push((
+            action.parse()?,
+            Route::new(Endpoint::Async(Box::new(handler))),
+        ));
+        Ok(self)
+    }
+
+    /// async_handler_func registers a closure returning a BoxFuture as an
+    /// AsyncHandler for a given Action.
+    #[cfg(feature = "async")]
+    pub fn async_handler_func<F>(self, action: &str, func: F) -> Result<Self>
+    where
+        F: for<'a> Fn(Request<'a>, Response) -> BoxFuture<'a, Response>,
+        F: Send + Sync + 'static,
+    {
+        self.async_handler(action, AsyncHandlerFunc::new(func))
+    }
+
+    /// handler_func registers a closure as a handler for a given Action.
+    pub fn handler_func<F>(self, action: &str, func: F) -> Result<Self>
+    where
+        F: FnOnce(Request, Response) -> Response,
+        F: Clone + Send + Sync + 'static,
+    {
+        self.handler(action, HandlerFunc::new(func))
+    }
+
+    /// try_handler registers a fallible handler for a given Action.
+    pub fn try_handler<H: TryHandler>(self, action: &str, handler: H) -> Result<Self> {
+        self.handler(action, Fallible(handler))
+    }
+
+    /// try_handler_func registers a fallible closure as a handler for a given
+    /// Action.
+    pub fn try_handler_func<F, E>(self, action: &str, func: F) -> Result<Self>
+    where
+        F: FnOnce(Request, Response) -> std::result::Result<Response, E>,
+        F: Clone + Send + Sync + 'static,
+        E: Into<HttpError>,
+    {
+        self.try_handler(action, TryHandlerFunc::new(func))
+    }
+
+    /// name names the most recently registered route, so that its URLs can
+    /// be generated with Server::url_for and Request::url_for.
+    pub fn name(mut self, name: &str) -> Result<Self> {
+        match self.entries.last_mut() {
+            Some((_, route)) => route.name = Some(name.to_string()),
+            None => {
+                return Err(ServerError::ParseAction(format!(
+                    "no route to name {}",
+                    name
+                )))
+            }
+        }
+        Ok(self)
+    }
+
+    /// scope registers the routes built by f under prefix.
+    pub fn scope<F>(self, prefix: &str, f: F) -> Result<Self>
+    where
+        F: FnOnce(Routes) -> Result<Routes>,
+    {
+        self.mount(prefix, f(Routes::new())?)
+    }
+
+    /// mount registers routes under prefix. Handlers of mounted routes see
+    /// the full request path in Request::path, and the path relative to the
+    /// prefix in Request::relative_path. The prefix may hold parameters, but
+    /// not a wildcard.
+    pub fn mount(mut self, prefix: &str, routes: Routes) -> Result<Self> {
+        if !prefix.starts_with('/') || prefix.contains('*') {
+            return Err(ServerError::ParseAction(format!(
+                "mount prefixes start with '/' and have no wildcard, got {}",
+                prefix
+            )));
+        }
+        let prefix = prefix.trim_end_matches('/');
+        let depth = pattern_segments(prefix)?.len();
+
+        for (mut action, mut route) in routes.entries {
+            let mut middleware = routes.middleware.clone();
+            middleware.append(&mut route.middleware);
+            route.middleware = middleware;
+            action.path = join(prefix, &action.path);
+            route.mount = join(prefix, &route.mount);
+            route.depth += depth;
+            self.entries.push((action, route));
+        }
+        Ok(self)
+    }
+}
+
+/// Named is the path of a named route, with the constraints of its
+/// parameters.
+struct Named {
+    path: String,
+    constraints: Vec<(String, Constraint)>,
+}
+
+/// Names maps route names to route paths.
+#[derive(Default)]
+pub(crate) struct Names(HashMap<String, Named, BuildHasher>);
+
+impl Names {
+    pub(crate) fn insert(&mut self, name: &str, path: &str) -> Result<()> {
+        if self.0.contains_key(name) {
+            return Err(ServerError::ParseAction(format!(
+                "duplicate route name: {}",
+                name
+            )));
+        }
+        let mut constraints = Vec::new();
+        for segment in pattern_segments(path)? {
+            if let Some(param) = segment.strip_prefix(':') {
+                if let (Some(i), true) = (param.find('{'), param.ends_with('}')) {
+                    let spec = &param[i + 1..param.len() - 1];
+                    constraints.push((param[..i].to_string(), Constraint::parse(spec)?));
+                }
+            }
+        }
+        let named = Named {
+            path: path.to_string(),
+            constraints,
+        };
+        self.0.insert(name.to_string(), named);
+        Ok(())
+    }
+
+    /// url_for generates the path of the named route, filling in its
+    /// parameters from params. Values are percent-encoded, except for the
+    /// slashes of wildcard values, and must satisfy the constraints of their
+    /// parameters. Pairs not naming a parameter are ignored.
+    pub(crate) fn url_for(&self, name: &str, params: &[(&str, &str)]) -> Result<String> {
+        let named = match self.0.get(name) {
+            Some(named) => named,
+            None => return Err(ServerError::UnknownRoute(name.to_string())),
+        };
+        let value = |name: &str| match params.iter().find(|(n, _)| *n == name) {
+            Some((_, value)) => Ok(*value),
+            None => Err(ServerError::MissingParam(name.to_string())),
+        };
+
+        let path = &named.path;
+        let mut url = String::with_capacity(path.len());
+        for segment in pattern_segments(path)? {
+            url.push('/');
+            if let Some(param) = segment.strip_prefix(':') {
+                let name = param.split('{').next().unwrap_or(param);
+                let value = value(name)?;
+                let constraint = named.constraints.iter().find(|(n, _)| n == name);
+                if let Some((_, constraint)) = constraint {
+                    if !constraint.matches(value) {
+                        return Err(ServerError::InvalidParam(
+                            name.to_string(),
+                            value.to_string(),
+                        ));
+                    }
+                }
+                percent_encode(&mut url, value);
+            } else if let Some(name) = segment.strip_prefix('*') {
+                for (i, part) in value(name)?.split('/').enumerate() {
+                    if i > 0 {
+                        url.push('/');
+                    }
+                    percent_encode(&mut url, part);
+                }
+            } else {
+                url.push_str(segment);
+            }
+        }
+        if url.is_empty() {
+            url.push('/');
+        }
+
+        Ok(url)
+    }
+}
+
+/// percent_encode appends s to url, percent-encoding all but the unreserved
+/// characters of RFC 3986.
+fn percent_encode(url: &mut String, s: &str) {
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                url.push(b as char)
+            }
+            _ => {
+                let _ = write!(url, "%{:02X}", b);
+            }
+        }
+    }
+}
+
+/// join prefixes path with prefix, which has no trailing slash.
+fn join(prefix: &str, path: &str) -> String {
+    match path {
+        "" | "/" if prefix.is_empty() => "/".to_string(),
+        "" | "/" => prefix.to_string(),
+        _ => format!("{}{}", prefix, path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::NotFound;
+
+    #[test]
+    fn nested_mounts() {
+        let routes = Routes::new()
+            .handler("GET /", NotFound)
+            .unwrap()
+            .scope("/api/", |r| {
+                r.handler("GET /users/:id", NotFound)?
+                    .mount("/admin", Routes::new().handler("POST /", NotFound)?)
+            })
+            .unwrap();
+
+        let entries: Vec<(&str, &str, usize)> = routes
+            .entries
+            .iter()
+            .map(|(a, r)| (a.path.as_str(), r.mount.as_str(), r.depth))
+            .collect();
+        assert_eq!(
+            entries,
+            vec![
+                ("/", "", 0),
+                ("/api/users/:id", "/api", 1),
+                ("/api/admin", "/api/admin", 2),
+            ]
+        );
+        assert!(Routes::new().mount("api", Routes::new()).is_err());
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn async_endpoint_outside_runtime() {
+        use crate::request::Message;
+
+        let endpoint = Endpoint::Async(Box::new(AsyncHandlerFunc::new(|_req, mut resp| {
+            Box::pin(async move {
+                resp.set_body("async");
+                resp
+            })
+        })));
+        let msg = Message {
+            headers: vec![
+                (b":method".to_vec(), b"GET".to_vec()),
+                (b":path".to_vec(), b"/".to_vec()),
+            ],
+            ..Message::default()
+        };
+        let req = Request::new(&msg).unwrap();
+        let resp = endpoint.handle(req, Response::new(0));
+        assert_eq!(resp.body(), b"async");
+    }
+
+    #[test]
+    fn url_for() {
+        let mut names = Names::default();
+        names.insert("user", "/user/:fname/:age{u32}").unwrap();
+        names.insert("file", "/files/*path").unwrap();
+        names.insert("root", "/").unwrap();
+        assert!(names.insert("root", "/other").is_err());
+
+        assert_eq!(
+            names
+                .url_for("user", &[("fname", "José Q"), ("age", "42")])
+                .unwrap(),
+            "/user/Jos%C3%A9%20Q/42"
+        );
+        assert_eq!(
+            names.url_for("file", &[("path", "a b/c.txt")]).unwrap(),
+            "/files/a%20b/c.txt"
+        );
+        assert_eq!(names.url_for("root", &[]).unwrap(), "/");
+        assert!(names.url_for("user", &[("fname", "a")]).is_err());
+        assert!(names
+            .url_for("user", &[("fname", "a"), ("age", "old")])
+            .is_err());
+        assert!(names.url_for("nope", &[]).is_err());
+    }
+}
@@ -1,73 +1,344 @@
-use std::collections::HashMap;
-use std::hash::BuildHasherDefault;
-use std::net::{TcpListener, TcpStream};
+use std::any::Any;
+use std::io;
+use std::mem;
+use std::net::SocketAddr;
+use std::panic::{self, AssertUnwindSafe};
 use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, Once};
 use std::thread;
+use std::time::{Duration, Instant};
 
-use crossbeam::{channel, Sender};
+use crossbeam::{channel, Receiver, Sender};
 use env_logger::Env;
-use openssl::ssl::{AlpnError, SslAcceptor, SslFiletype, SslMethod};
-use seahash::SeaHasher;
-use solicit::http::connection::{EndStream, HttpConnection, SendStatus};
-use solicit::http::server::ServerConnection;
-use solicit::http::session::{DefaultSessionState, SessionState, Stream};
-use solicit::http::transport::TransportStream;
-use solicit::http::{self, HttpScheme};
-
-use crate::error::Result;
-use crate::handlers::{Handler, HandlerFunc, NotFound};
-use crate::request::{Action, Request};
-use crate::response::Response;
-use crate::tls::Wrapper;
-use crate::workers;
-
-/// BuildHasher lets us use SeaHasher with HashMap.
-type BuildHasher = BuildHasherDefault<SeaHasher>;
+use mio::{Events, Poll, PollOpt, Ready, Registration, SetReadiness, Token};
+use openssl::ssl::SslAcceptor;
+use slab::Slab;
+
+use crate::conn::{Conn, Produced, Timeouts};
+use crate::error::{ErrorRenderer, HttpError, PlainText, Result};
+use crate::h2::{Settings, StreamId};
+#[cfg(feature = "async")]
+use crate::handlers::{AsyncHandler, BoxFuture};
+use crate::handlers::{Handler, HandlerFunc, TryHandler};
+use crate::listener::{Listener, Mode};
+use crate::middleware::{Middleware, Next};
+use crate::redirect::Redirect;
+use crate::request::{Action, Message, Method, Request};
+use crate::response::{Chunks, Response};
+use crate::router::{Lookup, Match, Router};
+use crate::routes::{Endpoint, Names, Route, Routes};
+#[cfg(unix)]
+use crate::signal;
+#[cfg(unix)]
+use crate::systemd;
+use crate::tickets::Keys;
+use crate::tls::{self, ClientVerify, TlsProfile, TlsVersion};
+#[cfg(unix)]
+use crate::upgrade;
+#[cfg(not(feature = "async"))]
+use crate::workers::Pool;
+
+/// Executor runs handlers: the worker pool, or with the async feature, a
+/// tokio runtime.
+#[cfg(not(feature = "async"))]
+type Executor = Pool;
+#[cfg(feature = "async")]
+type Executor = tokio::runtime::Runtime;
+
+/// CONCURRENCY is the default number of requests of one connection handled
+/// at the same time.
+const CONCURRENCY: usize = 32;
+
+/// MAX_BODY_SIZE is the default largest request body accepted.
+const MAX_BODY_SIZE: usize = 8 << 20;
+
+/// SHUTDOWN_TIMEOUT is how long connections get by default to finish
+/// their requests when the Server shuts down.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// HANDSHAKE_TIMEOUT is how long TLS handshakes may take by default.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// READ_TIMEOUT is how long a request may take to arrive by default, once
+/// it started.
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// IDLE_TIMEOUT is how long connections may stay idle by default.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// TIMEOUTS are the default connection Timeouts.
+const TIMEOUTS: Timeouts = Timeouts {
+    handshake: HANDSHAKE_TIMEOUT,
+    read: READ_TIMEOUT,
+    idle: IDLE_TIMEOUT,
+};
+
+/// SWEEP is how often the event loop closes the connections that timed out.
+const SWEEP: Duration = Duration::from_secs(1);
+
+/// WAKER is the event loop Token of the Notifier.
+const WAKER: Token = Token(0);
+
+/// FIRST_LISTENER is the event loop Token of the first listener. Connection
+/// Tokens follow those of the listeners.
+const FIRST_LISTENER: usize = 1;
 
 /// Builder is the Server builder.
 pub struct Builder {
-    cert: String,
-    key: String,
-    router: HashMap<Action, Box<dyn Handler>, BuildHasher>,
+    tls: tls::Config,
+    routes: Routes,
+    middleware: Vec<Arc<dyn Middleware>>,
+    renderer: Box<dyn ErrorRenderer>,
     socket: String,
     threads: usize,
+    concurrency: usize,
+    max_body_size: usize,
+    shutdown_timeout: Duration,
+    timeouts: Timeouts,
+    h2_settings: Settings,
+    plaintext: bool,
+    listeners: Vec<(String, Mode)>,
+    acme_dir: Option<String>,
+    upgrade: bool,
+    reload: bool,
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Builder::new()
+    }
 }
 
 impl Builder {
     /// new returns an initialized Server Builder.
     pub fn new() -> Builder {
         Builder {
-            cert: "tls/dev/cert.pem".to_string(),
-            key: "tls/dev/key.pem".to_string(),
-            router: HashMap::<Action, Box<dyn Handler>, BuildHasher>::default(),
+            tls: tls::Config::default(),
+            routes: Routes::new(),
+            middleware: Vec::new(),
+            renderer: Box::new(PlainText),
             socket: "127.0.0.1:8443".to_string(),
-            threads: 0,
+            threads: num_cpus::get(),
+            concurrency: CONCURRENCY,
+            max_body_size: MAX_BODY_SIZE,
+            shutdown_timeout: SHUTDOWN_TIMEOUT,
+            timeouts: TIMEOUTS,
+            h2_settings: Settings::default(),
+            plaintext: false,
+            listeners: Vec::new(),
+            acme_dir: None,
+            upgrade: false,
+            reload: false,
         }
     }
 
-    /// tls sets the certificate and key files.
+    /// tls adds a certificate and key file. With several, the one presented
+    /// is the first for the server name the client asks for with SNI, or
+    /// else the first added. Without any, the development ones in tls/dev
+    /// are used.
     pub fn tls(mut self, cert: &str, key: &str) -> Self {
-        self.cert = cert.to_string();
-        self.key = key.to_string();
+        self.tls.certs.push((cert.to_string(), key.to_string()));
+        self
+    }
+
+    /// client_ca sets the file of CA certificates, in PEM, that client
+    /// certificates are verified against, see client_verify.
+    pub fn client_ca(mut self, ca: &str) -> Self {
+        self.tls.client_ca = Some(ca.to_string());
+        self
+    }
+
+    /// client_verify sets whether TLS clients are asked for a certificate,
+    /// ClientVerify::None by default. Handlers get the verified certificate
+    /// from Request::client_cert.
+    pub fn client_verify(mut self, verify: ClientVerify) -> Self {
+        self.tls.client_verify = verify;
+        self
+    }
+
+    /// tls_profile sets the base TLS versions and ciphers, adjusted by the
+    /// other TLS options, TlsProfile::Intermediate by default.
+    pub fn tls_profile(mut self, profile: TlsProfile) -> Self {
+        self.tls.profile = profile;
+        self
+    }
+
+    /// min_tls_version sets the oldest TLS version accepted.
+    pub fn min_tls_version(mut self, version: TlsVersion) -> Self {
+        self.tls.min_version = Some(version);
+        self
+    }
+
+    /// max_tls_version sets the newest TLS version accepted.
+    pub fn max_tls_version(mut self, version: TlsVersion) -> Self {
+        self.tls.max_version = Some(version);
+        self
+    }
+
+    /// ciphers sets the ciphers of TLS 1.2 and below, as an OpenSSL cipher
+    /// list, such as "ECDHE-ECDSA-AES128-GCM-SHA256:ECDHE-RSA-AES128-GCM-SHA256".
+    pub fn ciphers(mut self, ciphers: &str) -> Self {
+        self.tls.ciphers = Some(ciphers.to_string());
+        self
+    }
+
+    /// ciphersuites sets the TLS 1.3 cipher suites, separated by colons, such
+    /// as "TLS_AES_128_GCM_SHA256:TLS_CHACHA20_POLY1305_SHA256".
+    pub fn ciphersuites(mut self, ciphersuites: &str) -> Self {
+        self.tls.ciphersuites = Some(ciphersuites.to_string());
+        self
+    }
+
+    /// curves sets the key exchange groups, separated by colons, in order of
+    /// preference, such as "X25519:P-256".
+    pub fn curves(mut self, curves: &str) -> Self {
+        self.tls.curves = Some(curves.to_string());
+        self
+    }
+
+    /// session_tickets lets clients resume TLS sessions with tickets, whose
+    /// keys are replaced every rotation, hourly by default. Tickets stay
+    /// valid for up to two rotations, and across certificate reloads. None
+    /// disables them.
+    pub fn session_tickets(mut self, rotation: Option<Duration>) -> Self {
+        self.tls.ticket_keys = rotation.map(Keys::new);
+        self
+    }
+
+    /// key_log appends the secrets of the TLS sessions to the file at path,
+    /// in the SSLKEYLOGFILE format, so tools like Wireshark can decrypt
+    /// captured traffic. It is meant for debugging only: the file lets anyone
+    /// reading it decrypt the traffic, so a new one is only readable by its
+    /// owner.
+    pub fn key_log(mut self, path: &str) -> Self {
+        self.tls.key_log = Some(path.to_string());
+        self
+    }
+
+    /// reload_tls reloads the certificates and keys when their files change
+    /// and, on Unix, on SIGHUP. New connections get the new ones, while
+    /// those open keep theirs. If they fail to load, the error is logged and
+    /// the current ones are kept.
+    pub fn reload_tls(mut self, enable: bool) -> Self {
+        self.reload = enable;
+        self
+    }
+
+    /// plaintext serves cleartext HTTP/2 (h2c) and HTTP/1.1 instead of TLS
+    /// on the socket, as behind a TLS-terminating proxy. HTTP/2 clients may
+    /// start with the preface or upgrade from HTTP/1.1.
+    pub fn plaintext(mut self, plaintext: bool) -> Self {
+        self.plaintext = plaintext;
+        self
+    }
+
+    /// http_redirect also listens for plain HTTP on socket, redirecting
+    /// clients to the same host, path and query on the port of the first TLS
+    /// listener. It is short for listen(socket, Mode::Redirect).
+    pub fn http_redirect(self, socket: &str) -> Self {
+        self.listen(socket, Mode::Redirect)
+    }
+
+    /// listen adds a listener on addr, a host:port pair or unix:PATH for a
+    /// Unix domain socket, serving connections as mode says. It may be called
+    /// repeatedly, and all listeners share the Server's routes. A host name
+    /// listens on every address it resolves to, and an IPv6 address also
+    /// accepts IPv4 clients unless an IPv4 address is listened on with the
+    /// same port. Without any TLS or plaintext listener, the Server listens
+    /// on the socket.
+    pub fn listen(mut self, addr: &str, mode: Mode) -> Self {
+        self.listeners.push((addr.to_string(), mode));
+        self
+    }
+
+    /// acme_challenges serves the ACME HTTP-01 challenge files in dir under
+    /// /.well-known/acme-challenge/ on the Redirect listeners, so
+    /// certificates can be renewed while the Server runs.
+    pub fn acme_challenges(mut self, dir: &str) -> Self {
+        self.acme_dir = Some(dir.to_string());
         self
     }
 
-    /// socket sets the TcP socket to listen on.
+    /// graceful_upgrade lets SIGUSR2 upgrade the Server, as ServerHandle's
+    /// upgrade does.
+    #[cfg(unix)]
+    pub fn graceful_upgrade(mut self, enable: bool) -> Self {
+        self.upgrade = enable;
+        self
+    }
+
+    /// socket sets the TCP socket to listen on when no TLS or plaintext
+    /// listener is added with listen.
     pub fn socket(mut self, socket: &str) -> Self {
         self.socket = socket.to_string();
         self
     }
 
-    /// threads sets the number of worker pool threads.
+    /// threads sets the number of worker pool threads, at least 1. The
+    /// default is the number of CPUs.
     pub fn threads(mut self, threads: usize) -> Self {
         self.threads = threads;
         self
     }
 
+    /// concurrency sets the maximum number of requests of one connection
+    /// handled at the same time. Further requests wait for one of them to
+    /// finish. The default is 32.
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// max_body_size sets the largest request body accepted. Larger ones
+    /// are refused, resetting their HTTP/2 stream, or over HTTP/1.1, with
+    /// 413 Payload Too Large. The default is 8 MiB.
+    pub fn max_body_size(mut self, max_body_size: usize) -> Self {
+        self.max_body_size = max_body_size;
+        self
+    }
+
+    /// shutdown_timeout sets how long connections get to finish their
+    /// requests when the Server shuts down, after which they are closed. The
+    /// default is 30 seconds.
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// handshake_timeout sets how long clients get to complete the TLS
+    /// handshake, after which the connection is closed. The default is 10
+    /// seconds.
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.timeouts.handshake = timeout;
+        self
+    }
+
+    /// read_timeout sets how long clients get to send a whole request once
+    /// they started, head and body, after which the connection is closed.
+    /// The default is 30 seconds.
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.timeouts.read = timeout;
+        self
+    }
+
+    /// idle_timeout sets how long a connection may go without receiving or
+    /// sending anything while no request is being handled, after which it
+    /// is closed, with a GOAWAY for HTTP/2. The default is 2 minutes.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.timeouts.idle = timeout;
+        self
+    }
+
+    /// h2_settings sets the HTTP/2 settings advertised to clients, such as
+    /// the flow control window and the number of concurrent streams.
+    pub fn h2_settings(mut self, settings: Settings) -> Self {
+        self.h2_settings = settings;
+        self
+    }
+
     /// handler registers a handler for a given Action.
     pub fn handler<H: Handler>(mut self, action: &str, handler: H) -> Result<Self> {
-        self.router.insert(action.parse()?, Box::new(handler));
+        self.routes = self.routes.handler(action, handler)?;
         Ok(self)
     }
 
@@ -77,32 +348,313 @@ impl Builder {
         F: FnOnce(Request, Response) -> Response,
         F: Clone + Send + Sync + 'static,
     {
-        self.router
-            .insert(action.parse()?, Box::new(HandlerFunc::new(func)));
+        self.routes = self.routes.handler_func(action, func)?;
+        Ok(self)
+    }
+
+    /// async_handler registers an AsyncHandler for a given Action.
+    #[cfg(feature = "async")]
+    pub fn async_handler<H: AsyncHandler>(mut self, action: &str, handler: H) -> Result<Self> {
+        self.routes = self.routes.async_handler(action, handler)?;
+        Ok(self)
+    }
+
+    /// async_handler_func registers a closure returning a BoxFuture as an
+    /// AsyncHandler for a given Action.
+    #[cfg(feature = "async")]
+    pub fn async_handler_func<F>(mut self, action: &str, func: F) -> Result<Self>
+    where
+        F: for<'a> Fn(Request<'a>, Response) -> BoxFuture<'a, Response>,
+        F: Send + Sync + 'static,
+    {
+        self.routes = self.routes.async_handler_func(action, func)?;
+        Ok(self)
+    }
+
+    /// try_handler registers a fallible handler for a given Action.
+    pub fn try_handler<H: TryHandler>(mut self, action: &str, handler: H) -> Result<Self> {
+        self.routes = self.routes.try_handler(action, handler)?;
+        Ok(self)
+    }
+
+    /// try_handler_func registers a fallible closure as a handler for a given
+    /// Action.
+    pub fn try_handler_func<F, E>(mut self, action: &str, func: F) -> Result<Self>
+    where
+        F: FnOnce(Request, Response) -> std::result::Result<Response, E>,
+        F: Clone + Send + Sync + 'static,
+        E: Into<HttpError>,
+    {
+        self.routes = self.routes.try_handler_func(action, func)?;
+        Ok(self)
+    }
+
+    /// error_renderer sets the ErrorRenderer producing error responses. The
+    /// default is PlainText.
+    pub fn error_renderer<R: ErrorRenderer>(mut self, renderer: R) -> Self {
+        self.renderer = Box::new(renderer);
+        self
+    }
+
+    /// middleware registers Middleware run for every request, before routing.
+    pub fn middleware<M: Middleware>(mut self, middleware: M) -> Self {
+        self.middleware.push(Arc::new(middleware));
+        self
+    }
+
+    /// wrap registers Middleware for the most recently registered route, see
+    /// Routes::wrap.
+    pub fn wrap<M: Middleware>(mut self, middleware: M) -> Result<Self> {
+        self.routes = self.routes.wrap(middleware)?;
+        Ok(self)
+    }
+
+    /// name names the most recently registered route, see Routes::name.
+    pub fn name(mut self, name: &str) -> Result<Self> {
+        self.routes = self.routes.name(name)?;
+        Ok(self)
+    }
+
+    /// scope registers the routes built by f under prefix.
+    pub fn scope<F>(mut self, prefix: &str, f: F) -> Result<Self>
+    where
+        F: FnOnce(Routes) -> Result<Routes>,
+    {
+        self.routes = self.routes.scope(prefix, f)?;
+        Ok(self)
+    }
+
+    /// mount registers routes under prefix, see Routes::mount.
+    pub fn mount(mut self, prefix: &str, routes: Routes) -> Result<Self> {
+        self.routes = self.routes.mount(prefix, routes)?;
         Ok(self)
     }
 
+    /// build compiles the registered routes into the Server's Router.
     pub fn build(self) -> Result<Server> {
-        let mut server = Server::new(&self.cert, &self.key, &self.socket, self.threads)?;
-        for (key, value) in self.router {
-            server.router.insert(key, value);
+        let mut listeners = self.listeners;
+        if listeners.iter().all(|(_, mode)| *mode == Mode::Redirect) {
+            let mode = if self.plaintext {
+                Mode::Plaintext
+            } else {
+                Mode::Tls
+            };
+            listeners.insert(0, (self.socket, mode));
+        }
+        let mut tls = self.tls;
+        if tls.certs.is_empty() {
+            tls.certs.push((
+                "tls/dev/cert.pem".to_string(),
+                "tls/dev/key.pem".to_string(),
+            ));
+        }
+        let mut server = Server::open(tls, &listeners, self.threads)?;
+        server.middleware = self.middleware;
+        server.renderer = self.renderer;
+        server.concurrency = self.concurrency;
+        server.max_body_size = self.max_body_size;
+        server.shutdown_timeout = self.shutdown_timeout;
+        server.timeouts = self.timeouts;
+        server.upgrade = self.upgrade;
+        server.reload = self.reload;
+        server.h2_settings = self.h2_settings;
+        if listeners.iter().any(|(_, mode)| *mode == Mode::Redirect) {
+            let port = server
+                .listeners
+                .iter()
+                .filter(|listener| listener.mode == Mode::Tls)
+                .find_map(|listener| listener.tcp_addr())
+                .map(|addr| addr.port())
+                .unwrap_or(443);
+            let acme_dir = self.acme_dir.map(PathBuf::from);
+            server.redirect = Some(Redirect::new(port, acme_dir));
+            info!("Redirecting HTTP to HTTPS on port {}.", port);
+        }
+        for (action, route) in self.routes.entries {
+            if let Some(name) = &route.name {
+                server.names.insert(name, &action.path)?;
+            }
+            if server
+                .router
+                .insert(action.method, &action.path, route)?
+                .is_some()
+            {
+                warn!("build: overwriting handler for action: {:?}", action);
+            }
         }
         Ok(server)
     }
 }
 
+/// Event is a message to the event loop.
 enum Event {
-    Incoming(TcpStream),
+    /// Done is the Response to a request of the connection with the given
+    /// key and serial.
+    Done(usize, u64, Response),
+    /// Chunk is what producing the next chunk of the streamed body of a
+    /// stream of the connection with the given key and serial gave.
+    Chunk(usize, u64, StreamId, Produced),
     Shutdown,
+    /// Upgrade hands the listeners to a new process, then shuts down.
+    #[cfg(unix)]
+    Upgrade,
+    /// Upgraded shuts down once the new process accepts connections.
+    #[cfg(unix)]
+    Upgraded,
+    /// Reload loads the certificates and keys again.
+    Reload,
+    /// Reloaded replaces the acceptor of new TLS connections, if the
+    /// certificates and keys loaded.
+    Reloaded(Option<SslAcceptor>),
+}
+
+/// reload loads the certificates and keys again on the executor, as it
+/// reads files, and hands the new acceptor to the event loop, or logs why
+/// they failed to load.
+fn reload(srv: &Arc<Server>, executor: &Executor, notifier: &Notifier) {
+    let (srv, notifier) = (Arc::clone(srv), notifier.clone());
+    let job = move || {
+        let acceptor = match tls::acceptor(&srv.tls) {
+            Ok(acceptor) => Some(acceptor),
+            Err(e) => {
+                error!(
+                    "error reloading the certificates, keeping the current ones: {}",
+                    e
+                );
+                None
+            }
+        };
+        notifier.notify(Event::Reloaded(acceptor));
+    };
+    #[cfg(not(feature = "async"))]
+    executor.execute(job);
+    #[cfg(feature = "async")]
+    executor.spawn_blocking(job);
+}
+
+/// ready tells systemd, and the process being upgraded if any, that the
+/// process accepts connections. Embedders call it once all their Servers
+/// are started, which Server::run does on its own. Only the first call
+/// does anything.
+pub fn ready() {
+    #[cfg(unix)]
+    {
+        static READY: Once = Once::new();
+        READY.call_once(|| {
+            // The new process takes over as the main process of the service,
+            // before the one being upgraded, if any, shuts down.
+            systemd::notify(&format!("READY=1\nMAINPID={}", std::process::id()));
+            upgrade::ready();
+        });
+    }
+}
+
+/// Notifier sends Events to the event loop, waking it up.
+#[derive(Clone)]
+struct Notifier {
+    sender: Sender<Event>,
+    readiness: SetReadiness,
+}
+
+impl Notifier {
+    /// notify sends event, returning false if the event loop has stopped.
+    fn notify(&self, event: Event) -> bool {
+        if self.sender.send(event).is_err() {
+            return false;
+        }
+        let _ = self.readiness.set_readiness(Ready::readable());
+        true
+    }
+}
+
+/// ShutdownReport tells how the connections of a Server ended when it shut
+/// down.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ShutdownReport {
+    /// drained is the number of connections that closed cleanly once their
+    /// requests were done, after a GOAWAY or Connection: close.
+    pub drained: usize,
+    /// aborted is the number of connections closed at the deadline, or that
+    /// timed out or failed while draining.
+    pub aborted: usize,
+}
+
+/// ServerHandle controls a Server running on its own thread, see
+/// Server::start.
+pub struct ServerHandle {
+    addrs: Vec<SocketAddr>,
+    notifier: Notifier,
+    thread: thread::JoinHandle<Result<ShutdownReport>>,
+    /// _signals are the signal callbacks of the Server, removed when the
+    /// ServerHandle drops.
+    #[cfg(unix)]
+    _signals: Vec<signal::Guard>,
+}
+
+impl ServerHandle {
+    /// local_addr is the address of the first TCP listener, with the port
+    /// the OS picked for port 0. It is None if the Server only listens on
+    /// Unix domain sockets.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.addrs.first().copied()
+    }
+
+    /// local_addrs are the addresses of all TCP listeners.
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.addrs
+    }
+
+    /// shutdown asks the Server to stop, without waiting for it: it stops
+    /// accepting connections and closes them once their requests are done or
+    /// the shutdown timeout expires. Calling it again closes them at once.
+    /// See join.
+    pub fn shutdown(&self) {
+        self.notifier.notify(Event::Shutdown);
+    }
+
+    /// upgrade starts the executable again with the same arguments, handing
+    /// it the listening sockets, and shuts down once the new process accepts
+    /// connections, so none are refused. The Server keeps running if the new
+    /// process fails to start.
+    #[cfg(unix)]
+    pub fn upgrade(&self) {
+        self.notifier.notify(Event::Upgrade);
+    }
+
+    /// join waits for the Server to stop, returning its ShutdownReport, or
+    /// the error that stopped its event loop.
+    pub fn join(self) -> Result<ShutdownReport> {
+        match self.thread.join() {
+            Ok(result) => result,
+            Err(e) => {
+                Err(io::Error::other(format!("event loop panicked: {}", panic_message(&e))).into())
+            }
+        }
+    }
 }
 
 /// Server is a simple HTT/2 server
 pub struct Server {
-    acceptor: SslAcceptor,
-    listener: TcpListener,
-    router: HashMap<Action, Box<dyn Handler>, BuildHasher>,
-    not_found: Box<dyn Handler>,
+    /// acceptor is None without a TLS listener.
+    acceptor: Option<SslAcceptor>,
+    /// tls is the configuration of the acceptor.
+    tls: tls::Config,
+    listeners: Vec<Listener>,
+    router: Router<Route>,
+    names: Names,
+    middleware: Vec<Arc<dyn Middleware>>,
+    renderer: Box<dyn ErrorRenderer>,
     threads: usize,
+    concurrency: usize,
+    max_body_size: usize,
+    shutdown_timeout: Duration,
+    timeouts: Timeouts,
+    h2_settings: Settings,
+    redirect: Option<Redirect>,
+    /// upgrade tells if SIGUSR2 upgrades the Server.
+    upgrade: bool,
+    /// reload tells if the certificates are reloaded when they change.
+    reload: bool,
 }
 
 impl Server {
@@ -113,27 +665,82 @@ impl Server {
 
     /// new returns an initialized instance of Server
     pub fn new(cert: &str, key: &str, socket: &str, threads: usize) -> Result<Server> {
-        env_logger::from_env(Env::default().default_filter_or("info")).init();
+        let tls = tls::Config {
+            certs: vec![(cert.to_string(), key.to_string())],
+            ..tls::Config::default()
+        };
+        Server::open(tls, &[(socket.to_string(), Mode::Tls)], threads)
+    }
+
+    /// open returns an initialized instance of Server with the given
+    /// listeners, loading the certificate and key files if one serves TLS.
+    fn open(tls: tls::Config, listens: &[(String, Mode)], threads: usize) -> Result<Server> {
+        // Another Server of the process may have set up logging already.
+        let _ = env_logger::from_env(Env::default().default_filter_or("info")).try_init();
 
-        println!("zws HTTP server listening on {}. CTRL+C to stop.", socket);
-        info!("Using certificate: {}, and key: {}.", cert, key);
+        let listeners = Listener::bind_all(listens)?;
+        let threads = threads.max(1);
+        let acceptor = if listens.iter().any(|(_, mode)| *mode == Mode::Tls) {
+            for (cert, key) in &tls.certs {
+                info!("Using certificate: {}, and key: {}.", cert, key);
+            }
+            if let Some(path) = &tls.key_log {
+                warn!(
+                    "Logging TLS secrets to {}, which decrypt the traffic!",
+                    path
+                );
+            }
+            if tls.client_verify != ClientVerify::None {
+                info!(
+                    "Verifying client certificates ({:?}) against: {}.",
+                    tls.client_verify,
+                    tls.client_ca.as_deref().unwrap_or("")
+                );
+            }
+            Some(tls::acceptor(&tls)?)
+        } else {
+            None
+        };
+        if listens.iter().any(|(_, mode)| *mode == Mode::Plaintext) {
+            info!("Serving plaintext h2c and HTTP/1.1, without TLS.");
+        }
         info!(
             "Using {} threads for worker pool request handling.",
             threads
         );
 
         Ok(Server {
-            acceptor: Server::new_acceptor(cert, key)?,
-            listener: TcpListener::bind(socket)?,
-            router: HashMap::<Action, Box<dyn Handler>, BuildHasher>::default(),
-            not_found: Box::new(NotFound {}),
+            acceptor,
+            tls,
+            listeners,
+            router: Router::new(),
+            names: Names::default(),
+            middleware: Vec::new(),
+            renderer: Box::new(PlainText),
             threads,
+            concurrency: CONCURRENCY,
+            max_body_size: MAX_BODY_SIZE,
+            shutdown_timeout: SHUTDOWN_TIMEOUT,
+            timeouts: TIMEOUTS,
+            h2_settings: Settings::default(),
+            redirect: None,
+            upgrade: false,
+            reload: false,
         })
     }
 
     /// add_handler registers a handler for a given Action.
     pub fn add_handler<H: Handler>(mut self, action: &str, handler: H) -> Result<Self> {
-        if let Some(_) = self.router.insert(action.parse()?, Box::new(handler)) {
+        let parsed: Action = action.parse()?;
+        if self
+            .router
+            .insert(
+                parsed.method,
+                &parsed.path,
+                Route::new(Endpoint::Sync(Box::new(handler))),
+            )?
+            .is_some()
+        {
             warn!("add_handler: overwriting handler for action: {}", action);
         }
         Ok(self)
@@ -145,9 +752,15 @@ impl Server {
         F: FnOnce(Request, Response) -> Response,
         F: Clone + Send + Sync + 'static,
     {
-        if let Some(_) = self
+        let parsed: Action = action.parse()?;
+        if self
             .router
-            .insert(action.parse()?, Box::new(HandlerFunc::new(func)))
+            .insert(
+                parsed.method,
+                &parsed.path,
+                Route::new(Endpoint::Sync(Box::new(HandlerFunc::new(func)))),
+            )?
+            .is_some()
         {
             warn!(
                 "add_handler_func: overwriting handler func for action: {}",
@@ -157,169 +770,1374 @@ impl Server {
         Ok(self)
     }
 
-    // run does setup and takes an incoming TLS connection and sends its stream to be handled.
+    /// url_for generates the path of a named route, filling in and
+    /// percent-encoding its parameters. It fails when the route is unknown, or
+    /// a parameter is missing or fails its constraint.
+    pub fn url_for(&self, name: &str, params: &[(&str, &str)]) -> Result<String> {
+        self.names.url_for(name, params)
+    }
+
+    /// run starts the Server and waits for it to stop, which CTRL+C or SIGTERM
+    /// asks it to do. A second one closes the connections still draining.
     pub fn run(self) -> Result<()> {
+        println!(
+            "zws HTTP server listening on {}. CTRL+C to stop.",
+            self.listening()
+        );
+        let handle = self.start()?;
+        ready();
+
         // Graceful shutdown via CTRL+C
+        let notifier_ctrlc = handle.notifier.clone();
+        if let Err(e) = ctrlc::set_handler(move || {
+            info!("CTRL+C received! Shutting down...");
+            notifier_ctrlc.notify(Event::Shutdown);
+        }) {
+            warn!("CTRL+C won't stop the server: {}", e);
+        }
+        #[cfg(unix)]
+        let _sigterm = {
+            let notifier = handle.notifier.clone();
+            signal::on(libc::SIGTERM, move || {
+                info!("SIGTERM received! Shutting down...");
+                notifier.notify(Event::Shutdown);
+            })
+            .map_err(|e| warn!("SIGTERM won't stop the server: {}", e))
+        };
+
+        let report = handle.join()?;
+        info!(
+            "Stopped: {} connections drained, {} aborted.",
+            report.drained, report.aborted
+        );
+        Ok(())
+    }
+
+    /// start runs the event loop on a new thread and returns its
+    /// ServerHandle. The event loop accepts connections and reads and writes
+    /// them whenever their socket is ready. Only handlers run on the worker
+    /// pool, so idle connections cost no thread. With the async feature,
+    /// handlers run on a tokio runtime instead.
+    pub fn start(self) -> Result<ServerHandle> {
+        let poll = Poll::new()?;
+        let (registration, readiness) = Registration::new2();
         let (event_tx, event_rx) = channel::unbounded();
-        let event_tx_clone_ctrlc = Sender::clone(&event_tx);
+        let notifier = Notifier {
+            sender: event_tx,
+            readiness,
+        };
 
-        ctrlc::set_handler(move || {
-            info!("CTRL+C received! Shutting down...");
-            event_tx_clone_ctrlc.send(Event::Shutdown).unwrap();
+        poll.register(&registration, WAKER, Ready::readable(), PollOpt::edge())?;
+        for (i, listener) in self.listeners.iter().enumerate() {
+            listener.register(&poll, Token(FIRST_LISTENER + i))?;
+        }
+        debug!("listening on {}", self.listening());
+        #[cfg(unix)]
+        let mut signals = Vec::new();
+        #[cfg(unix)]
+        {
+            if self.upgrade {
+                let notifier = notifier.clone();
+                signals.push(signal::on(libc::SIGUSR2, move || {
+                    info!("SIGUSR2 received! Upgrading...");
+                    notifier.notify(Event::Upgrade);
+                })?);
+            }
+        }
+        // The watch stops with the event loop, which owns it.
+        let mut watch = None;
+        if self.reload && self.acceptor.is_some() {
+            let watch_notifier = notifier.clone();
+            match tls::watch(&self.tls, move || watch_notifier.notify(Event::Reload)) {
+                Ok(w) => watch = Some(w),
+                Err(e) => warn!("certificates won't reload when changed: {}", e),
+            }
+            #[cfg(unix)]
+            {
+                let notifier = notifier.clone();
+                signals.push(signal::on(libc::SIGHUP, move || {
+                    info!("SIGHUP received! Reloading the certificates...");
+                    notifier.notify(Event::Reload);
+                })?);
+            }
+        }
+
+        let addrs = self
+            .listeners
+            .iter()
+            .filter_map(|listener| listener.tcp_addr())
+            .collect();
+        let loop_notifier = notifier.clone();
+        let thread = thread::Builder::new()
+            .name("zws".to_string())
+            .spawn(move || {
+                let _watch = watch;
+                self.serve(poll, registration, event_rx, loop_notifier)
+            })?;
+        Ok(ServerHandle {
+            addrs,
+            notifier,
+            thread,
+            #[cfg(unix)]
+            _signals: signals,
         })
-        .unwrap();
+    }
+
+    /// listening lists the addresses and modes of the listeners.
+    fn listening(&self) -> String {
+        let addrs: Vec<String> = self.listeners.iter().map(|l| l.to_string()).collect();
+        addrs.join(", ")
+    }
 
+    /// serve runs the event loop until a Shutdown Event, and then until the
+    /// connections are drained or the shutdown timeout expires.
+    fn serve(
+        mut self,
+        poll: Poll,
+        _registration: Registration,
+        event_rx: Receiver<Event>,
+        notifier: Notifier,
+    ) -> Result<ShutdownReport> {
+        // The event loop owns the listeners, to close them on shutdown.
+        let mut listeners = mem::take(&mut self.listeners);
+        // It owns the acceptor too, to replace it on Reload.
+        let mut acceptor = self.acceptor.take();
+        let first_conn = FIRST_LISTENER + listeners.len();
         let srv = Arc::new(self);
-        let srv_clone_main = Arc::clone(&srv);
-        let event_tx_clone_main = Sender::clone(&event_tx);
-
-        thread::spawn(move || {
-            for stream in srv_clone_main.listener.incoming() {
-                match stream {
-                    Ok(stream) => {
-                        event_tx_clone_main.send(Event::Incoming(stream)).unwrap();
+        let pool = executor(srv.threads)?;
+        let mut conns: Slab<Conn> = Slab::new();
+        let mut serial = 0;
+        let mut events = Events::with_capacity(1024);
+        let mut buf = vec![0; 16 * 1024];
+        let mut deadline: Option<Instant> = None;
+        let mut report = ShutdownReport::default();
+        let (mut reloading, mut reload_again) = (false, false);
+        #[cfg(unix)]
+        let mut upgraded = false;
+        #[cfg(unix)]
+        let watchdog = systemd::watchdog();
+        #[cfg(not(unix))]
+        let watchdog: Option<Duration> = None;
+        let mut ping = watchdog.map(|interval| Instant::now() + interval);
+        let mut sweep = Instant::now() + SWEEP;
+
+        loop {
+            let sweeping = Some(sweep).filter(|_| !conns.is_empty());
+            let wake = deadline.into_iter().chain(ping).chain(sweeping).min();
+            let timeout = wake.map(|at| at.saturating_duration_since(Instant::now()));
+            if let Err(e) = poll.poll(&mut events, timeout) {
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e.into());
+            }
+
+            for event in &events {
+                match event.token() {
+                    Token(token) if (FIRST_LISTENER..first_conn).contains(&token) => {
+                        let listener = match listeners.get(token - FIRST_LISTENER) {
+                            Some(listener) => listener,
+                            None => continue,
+                        };
+                        loop {
+                            let stream = match listener.accept() {
+                                Ok(stream) => stream,
+                                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                                Err(e) => {
+                                    warn!("error in accept on {}: {}", listener, e);
+                                    break;
+                                }
+                            };
+                            let entry = conns.vacant_entry();
+                            let token = Token(first_conn + entry.key());
+                            let interest = Ready::readable() | Ready::writable();
+                            if let Err(e) = poll.register(&stream, token, interest, PollOpt::edge())
+                            {
+                                warn!("error registering connection: {}", e);
+                                continue;
+                            }
+                            serial += 1;
+                            let acceptor = acceptor.as_ref().filter(|_| listener.mode == Mode::Tls);
+                            let conn = Conn::new(
+                                acceptor,
+                                stream,
+                                serial,
+                                srv.h2_settings,
+                                srv.concurrency,
+                                srv.max_body_size,
+                            );
+                            match conn {
+                                Ok(mut conn) => {
+                                    conn.redirect = listener.mode == Mode::Redirect;
+                                    entry.insert(conn);
+                                }
+                                Err(e) => warn!("{}", e),
+                            }
+                        }
                     }
-                    Err(e) => {
-                        warn!("error in TCP accept: {}", e);
+                    WAKER => {
+                        let _ = notifier.readiness.set_readiness(Ready::empty());
+                        for event in event_rx.try_iter() {
+                            let (key, serial, resp) = match event {
+                                Event::Done(key, serial, resp) => (key, serial, resp),
+                                Event::Chunk(key, serial, stream_id, produced) => {
+                                    let result = match conns.get_mut(key) {
+                                        Some(conn) if conn.serial == serial => {
+                                            conn.produced(stream_id, produced);
+                                            srv.drive(key, conn, &pool, &notifier)
+                                        }
+                                        _ => continue,
+                                    };
+                                    if let Err(e) = result {
+                                        close(&mut conns, key, e, &mut report);
+                                    }
+                                    continue;
+                                }
+                                Event::Shutdown if deadline.is_none() => {
+                                    info!("Shutting down, draining {} connections.", conns.len());
+                                    #[cfg(unix)]
+                                    {
+                                        if !upgraded {
+                                            systemd::notify("STOPPING=1");
+                                        }
+                                    }
+                                    listeners.clear();
+                                    deadline = Some(Instant::now() + srv.shutdown_timeout);
+                                    // Only the connections closing from now on count.
+                                    report = ShutdownReport::default();
+                                    let keys: Vec<usize> =
+                                        conns.iter().map(|(key, _)| key).collect();
+                                    for key in keys {
+                                        conns[key].shutdown();
+                                        if let Err(e) = conns[key].flush() {
+                                            close(&mut conns, key, e, &mut report);
+                                        }
+                                    }
+                                    continue;
+                                }
+                                Event::Shutdown => {
+                                    deadline = Some(Instant::now());
+                                    continue;
+                                }
+                                #[cfg(unix)]
+                                Event::Upgrade => {
+                                    if deadline.is_none() {
+                                        let handoff =
+                                            listeners.iter().map(Listener::handoff).collect();
+                                        let notifier = notifier.clone();
+                                        let result = upgrade::spawn(handoff, move |ok| {
+                                            if ok {
+                                                notifier.notify(Event::Upgraded);
+                                            }
+                                        });
+                                        if let Err(e) = result {
+                                            error!("error starting the new process: {}", e);
+                                        }
+                                    }
+                                    continue;
+                                }
+                                #[cfg(unix)]
+                                Event::Upgraded => {
+                                    // The new process is the service now.
+                                    upgraded = true;
+                                    notifier.notify(Event::Shutdown);
+                                    continue;
+                                }
+                                Event::Reload => {
+                                    // One reload at a time, and one more for
+                                    // the changes made during it.
+                                    if reloading {
+                                        reload_again = true;
+                                    } else {
+                                        reloading = true;
+                                        reload(&srv, &pool, &notifier);
+                                    }
+                                    continue;
+                                }
+                                Event::Reloaded(new) => {
+                                    if let Some(new) = new {
+                                        info!("Reloaded the certificates for new connections.");
+                                        acceptor = Some(new);
+                                    }
+                                    reloading = reload_again;
+                                    if mem::take(&mut reload_again) {
+                                        reload(&srv, &pool, &notifier);
+                                    }
+                                    continue;
+                                }
+                            };
+                            let result = match conns.get_mut(key) {
+                                Some(conn) if conn.serial == serial => {
+                                    conn.respond(resp);
+                                    srv.drive(key, conn, &pool, &notifier).and_then(|_| {
+                                        srv.resume(key, conn, &mut buf, &pool, &notifier)
+                                    })
+                                }
+                                _ => continue,
+                            };
+                            if let Err(e) = result {
+                                close(&mut conns, key, e, &mut report);
+                            }
+                        }
+                    }
+                    Token(token) => {
+                        let key = token - first_conn;
+                        let result = match conns.get_mut(key) {
+                            Some(conn) => conn
+                                .ready(&mut buf)
+                                .and_then(|_| srv.drive(key, conn, &pool, &notifier)),
+                            None => continue,
+                        };
+                        if let Err(e) = result {
+                            close(&mut conns, key, e, &mut report);
+                        }
                     }
                 }
             }
-        });
 
-        let pool = workers::Pool::new(srv.threads);
-        for event in event_rx {
-            match event {
-                Event::Incoming(stream) => {
-                    let srv_clone_pool = Arc::clone(&srv);
-                    pool.execute(move || srv_clone_pool.handle_stream(stream));
+            #[cfg(unix)]
+            {
+                if let (Some(at), Some(interval)) = (ping, watchdog) {
+                    if Instant::now() >= at {
+                        systemd::notify("WATCHDOG=1");
+                        ping = Some(Instant::now() + interval);
+                    }
+                }
+            }
+            if Instant::now() >= sweep {
+                let now = Instant::now();
+                let expired: Vec<(usize, &str)> = conns
+                    .iter()
+                    .filter_map(|(key, conn)| Some((key, conn.expired(now, &srv.timeouts)?)))
+                    .collect();
+                for (key, what) in expired {
+                    debug!("closing connection: {} timed out", what);
+                    conns[key].shutdown();
+                    let _ = conns[key].flush();
+                    conns.remove(key);
+                    report.aborted += 1;
+                }
+                sweep = now + SWEEP;
+            }
+            if let Some(deadline) = deadline {
+                if conns.is_empty() || Instant::now() >= deadline {
+                    break;
                 }
-                Event::Shutdown => break,
             }
         }
 
+        if !conns.is_empty() {
+            warn!("closing {} connections still draining", conns.len());
+            report.aborted += conns.len();
+            drop(conns);
+            // Handlers still running would keep the executor from stopping.
+            abandon(pool);
+        }
+        Ok(report)
+    }
+
+    /// drive hands the pending requests of a connection to the Executor,
+    /// up to the Server's concurrency, writes out what it can, and has the
+    /// Executor produce the next chunks of the streamed bodies this made
+    /// room for.
+    fn drive(
+        self: &Arc<Self>,
+        key: usize,
+        conn: &mut Conn,
+        executor: &Executor,
+        notifier: &Notifier,
+    ) -> io::Result<()> {
+        while let Some(req) = conn.next_request(self.concurrency) {
+            let srv = Arc::clone(self);
+            let notifier = notifier.clone();
+            let serial = conn.serial;
+            let redirect = conn.redirect;
+            #[cfg(not(feature = "async"))]
+            executor.execute(move || {
+                let resp = if redirect {
+                    srv.respond_redirect(&req)
+                } else {
+                    srv.respond(&req)
+                };
+                notifier.notify(Event::Done(key, serial, resp));
+            });
+            #[cfg(feature = "async")]
+            executor.spawn(async move {
+                let stream_id = req.stream_id;
+                let task = if redirect {
+                    let srv = Arc::clone(&srv);
+                    tokio::task::spawn_blocking(move || srv.respond_redirect(&req))
+                } else {
+                    tokio::spawn(Arc::clone(&srv).respond_async(req))
+                };
+                let resp = match task.await {
+                    Ok(resp) => resp,
+                    Err(e) => {
+                        if let Ok(payload) = e.try_into_panic() {
+                            error!("async handler panicked: {}", panic_message(&payload));
+                        }
+                        srv.internal_error(stream_id)
+                    }
+                };
+                notifier.notify(Event::Done(key, serial, resp));
+            });
+        }
+        conn.flush()?;
+        for (stream_id, chunks) in conn.produce() {
+            let notifier = notifier.clone();
+            let serial = conn.serial;
+            // The Mutex makes the job Sync, as the Pool wants.
+            let chunks = Mutex::new(chunks);
+            let job = move || {
+                let produced = produce(chunks.into_inner().unwrap_or_else(|e| e.into_inner()));
+                notifier.notify(Event::Chunk(key, serial, stream_id, produced));
+            };
+            #[cfg(not(feature = "async"))]
+            executor.execute(job);
+            #[cfg(feature = "async")]
+            executor.spawn_blocking(job);
+        }
         Ok(())
     }
 
-    /// handler returns a handler for a given Action, or file_handler if none found.
-    fn handler(&self, action: &mut Action) -> &Box<dyn Handler> {
-        if let Some(h) = self.router.get(&action) {
-            return h.clone();
+    /// resume reads from a connection again once its pending requests went
+    /// under the limit that paused it.
+    fn resume(
+        self: &Arc<Self>,
+        key: usize,
+        conn: &mut Conn,
+        buf: &mut [u8],
+        executor: &Executor,
+        notifier: &Notifier,
+    ) -> io::Result<()> {
+        if !conn.is_paused() {
+            return Ok(());
         }
+        conn.ready(buf)?;
+        self.drive(key, conn, executor, notifier)
+    }
+
+    /// dispatch routes a Request to its handler, answering 405 Method Not
+    /// Allowed, HEAD and OPTIONS automatically when no handler is registered
+    /// for them.
+    fn dispatch<'a>(&'a self, mut req: Request<'a>, resp: Response) -> Response {
+        let method = req.method;
+        req.renderer = self.renderer.as_ref();
+        let stream_id = req.stream_id;
+        let route = |req, resp| self.route(req, resp);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            Next::new(&self.middleware, &route).run(req, resp)
+        }));
+        let mut resp = match result {
+            Ok(resp) => resp,
+            Err(e) => {
+                error!(
+                    "middleware for {} request panicked: {}",
+                    method,
+                    panic_message(&e)
+                );
+                self.internal_error(stream_id)
+            }
+        };
 
-        let mut path = PathBuf::from(&action.path);
+        if method == Method::HEAD {
+            resp.strip_body(false);
+        }
+        resp
+    }
 
-        while path.pop() {
-            action.path = path.to_string_lossy().to_string();
-            if let Some(h) = self.router.get(&action) {
-                return h.clone();
+    /// route looks up the route for a Request and runs its middleware chain
+    /// and handler.
+    fn route<'a>(&'a self, mut req: Request<'a>, mut resp: Response) -> Response {
+        let method = req.method;
+        match self.router.lookup(method, &req.path) {
+            Lookup::Found(m) => {
+                self.bind(&mut req, &m);
+                let stream_id = req.stream_id;
+                let handler = |req, resp| m.value.handler.handle(req, resp);
+                let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                    Next::new(&m.value.middleware, &handler).run(req, resp)
+                }));
+                match result {
+                    Ok(mut resp) => {
+                        // The GET handler answering a HEAD request sets the
+                        // body whose length it has.
+                        if method == Method::HEAD && m.method == Method::GET {
+                            resp.strip_body(true);
+                        }
+                        resp
+                    }
+                    Err(e) => {
+                        error!(
+                            "handler for {} {} panicked: {}",
+                            method,
+                            m.pattern,
+                            panic_message(&e)
+                        );
+                        self.internal_error(stream_id)
+                    }
+                }
+            }
+            Lookup::MethodNotAllowed(mut allowed) => {
+                if !allowed.contains(&Method::OPTIONS) {
+                    allowed.push(Method::OPTIONS);
+                }
+                if method != Method::OPTIONS {
+                    resp = self.renderer.render(&HttpError::new(405, ""), resp);
+                } else {
+                    resp.add_header(":status", "204");
+                }
+                resp.add_header("allow", &allow_header(&allowed));
+                resp
+            }
+            Lookup::NotFound => {
+                if method == Method::OPTIONS && req.path == "*" {
+                    let mut allowed = self.router.methods();
+                    if !allowed.contains(&Method::OPTIONS) {
+                        allowed.push(Method::OPTIONS);
+                    }
+                    resp.add_header("allow", &allow_header(&allowed));
+                    resp.add_header(":status", "204");
+                    resp
+                } else {
+                    self.renderer.render(&HttpError::not_found(""), resp)
+                }
             }
         }
+    }
 
-        &self.not_found
+    /// bind sets the routing information of a Request from its Match.
+    fn bind<'a>(&'a self, req: &mut Request<'a>, m: &Match<'a, Route>) {
+        req.route = m.pattern;
+        req.set_params(m.params);
+        req.mount = &m.value.mount;
+        req.mount_depth = m.value.depth;
+        req.names = Some(&self.names);
     }
 
-    /// new_acceptor creates a new TLS acceptor with the given certificate and key.
-    fn new_acceptor(cert: &str, key: &str) -> Result<SslAcceptor> {
-        let mut acceptor = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
-        acceptor.set_private_key_file(key, SslFiletype::PEM)?;
-        acceptor.set_certificate_chain_file(cert)?;
-        acceptor.check_private_key()?;
-        acceptor.set_alpn_protos(b"\x08http/1.1\x02h2")?;
-        acceptor.set_alpn_select_callback(|_, protos| {
-            const H2: &[u8] = b"\x02h2";
-            if protos.windows(3).any(|window| window == H2) {
-                Ok(b"h2")
-            } else {
-                Err(AlpnError::NOACK)
+    /// internal_error renders a 500 Internal Server Error Response.
+    fn internal_error(&self, stream_id: StreamId) -> Response {
+        let err = HttpError::new(500, "");
+        self.renderer.render(&err, Response::new(stream_id))
+    }
+
+    /// respond builds the Request of a Message and dispatches it.
+    fn respond(&self, msg: &Message) -> Response {
+        let resp = Response::new(msg.stream_id);
+        match Request::new(msg) {
+            Ok(req) => {
+                debug!("respond: received request: {}", req);
+                self.dispatch(req, resp)
             }
-        });
+            Err(e) => {
+                warn!("error processing request: {}", e);
+                self.renderer.render(&e.into(), resp)
+            }
+        }
+    }
 
-        Ok(acceptor.build())
+    /// respond_redirect responds to a request of a Redirect listener.
+    fn respond_redirect(&self, msg: &Message) -> Response {
+        match &self.redirect {
+            Some(redirect) => redirect.respond(msg, self.renderer.as_ref()),
+            None => self.internal_error(msg.stream_id),
+        }
     }
 
-    /// handle_stream processess an HTTP/2 TCP/TLS streaml
-    fn handle_stream(&self, stream: TcpStream) {
-        let stream = match self.acceptor.accept(stream) {
-            Ok(stream) => stream,
-            Err(e) => {
-                warn!("error in TLS accept: {}", e);
-                return;
+    /// respond_async awaits the AsyncHandler of a Message's route, unless
+    /// Middleware applies to it, and otherwise responds on a blocking thread.
+    #[cfg(feature = "async")]
+    async fn respond_async(self: Arc<Self>, msg: Message) -> Response {
+        if let (true, Ok(mut req)) = (self.middleware.is_empty(), Request::new(&msg)) {
+            if let Lookup::Found(m) = self.router.lookup(req.method, &req.path) {
+                if let (Endpoint::Async(handler), true) =
+                    (&m.value.handler, m.value.middleware.is_empty())
+                {
+                    debug!("respond_async: received request: {}", req);
+                    let method = req.method;
+                    req.renderer = self.renderer.as_ref();
+                    self.bind(&mut req, &m);
+                    let mut resp = handler.handle(req, Response::new(msg.stream_id)).await;
+                    if method == Method::HEAD {
+                        resp.strip_body(m.method == Method::GET);
+                    }
+                    return resp;
+                }
             }
-        };
-        let mut stream = Wrapper(Arc::new(Mutex::new(stream)));
+        }
 
-        let mut preface = [0; 24];
-        if let Err(e) = TransportStream::read_exact(&mut stream, &mut preface) {
-            warn!("error reading from client connection: {}", e);
-            return;
+        let stream_id = msg.stream_id;
+        let srv = Arc::clone(&self);
+        match tokio::task::spawn_blocking(move || srv.respond(&msg)).await {
+            Ok(resp) => resp,
+            Err(_) => self.internal_error(stream_id),
         }
-        if &preface != b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n" {
-            warn!("error in HTTP2 preface: {:?}", &preface);
-            return;
+    }
+}
+
+/// executor creates the Executor running handlers on threads threads.
+#[cfg(not(feature = "async"))]
+fn executor(threads: usize) -> io::Result<Executor> {
+    Ok(Pool::new(threads))
+}
+
+/// executor creates the Executor running handlers on threads threads.
+#[cfg(feature = "async")]
+fn executor(threads: usize) -> io::Result<Executor> {
+    tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(threads.max(1))
+        .max_blocking_threads(threads.max(1))
+        .build()
+}
+
+/// abandon drops the Executor without waiting for the handlers running.
+#[cfg(not(feature = "async"))]
+fn abandon(executor: Executor) {
+    executor.detach();
+}
+
+/// abandon drops the Executor without waiting for the handlers running.
+#[cfg(feature = "async")]
+fn abandon(executor: Executor) {
+    executor.shutdown_background();
+}
+
+/// produce produces the next chunk of a streamed body, which may block,
+/// so it runs on the Executor like handlers.
+fn produce(mut chunks: Chunks) -> Produced {
+    match panic::catch_unwind(AssertUnwindSafe(|| chunks.next())) {
+        Ok(Some(chunk)) => Produced::Chunk(chunks, chunk),
+        Ok(None) => Produced::End,
+        Err(e) => {
+            error!("streamed response body panicked: {}", panic_message(&e));
+            Produced::Failed
         }
+    }
+}
 
-        let conn = HttpConnection::<Wrapper, Wrapper>::with_stream(stream, HttpScheme::Https);
-        let mut conn: ServerConnection<Wrapper, Wrapper> =
-            ServerConnection::with_connection(conn, DefaultSessionState::new());
-        if let Err(e) = conn.init() {
-            error!("error initializing ServerConnection: {}", e);
-            return;
-        };
+/// close drops a connection, logging why unless the client closed it, and
+/// counts it in report as drained if it closed cleanly, once done with its
+/// requests, or else as aborted.
+fn close(conns: &mut Slab<Conn>, key: usize, e: io::Error, report: &mut ShutdownReport) {
+    let clean = match e.kind() {
+        io::ErrorKind::UnexpectedEof => {
+            debug!("connection closed by client");
+            conns[key].is_idle()
+        }
+        io::ErrorKind::ConnectionAborted => {
+            debug!("{}", e);
+            true
+        }
+        _ => {
+            warn!("closing connection: {}", e);
+            false
+        }
+    };
+    if clean {
+        report.drained += 1;
+    } else {
+        report.aborted += 1;
+    }
+    conns.remove(key);
+}
 
-        while let Ok(_) = conn.handle_next_frame() {
-            let mut responses = Vec::new();
-            for stream in conn.state.iter() {
-                if stream.is_closed_remote() {
-                    let actions = self.router.keys().cloned().collect();
-                    let mut req = match Request::new(&stream, &actions) {
-                        Ok(req) => req,
-                        Err(e) => {
-                            warn!("error processing request: {}", e);
-                            let mut resp = Response::new(stream.stream_id);
-                            resp.add_header(":status", "400");
-                            resp.set_body("Bad Request\n");
-                            responses.push(resp);
-                            continue;
-                        }
-                    };
-                    debug!("handle_stream: received request: {}", req);
-                    let resp = Response::new(stream.stream_id);
-                    responses.push(self.handler(&mut req.action).handle(req, resp));
-                }
-            }
+/// allow_header formats methods as the value of an Allow header.
+fn allow_header(methods: &[Method]) -> String {
+    methods
+        .iter()
+        .map(|method| method.as_str())
+        .collect::<Vec<&str>>()
+        .join(", ")
+}
 
-            for resp in responses {
-                let response: http::Response = resp.into();
-                if let Err(e) =
-                    conn.start_response(response.headers, response.stream_id, EndStream::No)
-                {
-                    warn!("error starting response: {}", e);
-                    return;
-                }
-                let stream = match conn.state.get_stream_mut(response.stream_id) {
-                    Some(stream) => stream,
-                    None => {
-                        warn!("error getting mutable stream");
-                        return;
+/// panic_message extracts the message from a panic payload.
+fn panic_message(payload: &Box<dyn Any + Send>) -> &str {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s
+    } else {
+        "unknown panic payload"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    /// get sends an HTTP/1.1 GET request for path and reads the response.
+    fn get(addr: SocketAddr, path: &str) -> String {
+        send(addr, "GET", path)
+    }
+
+    /// send sends an HTTP/1.1 request for method and path and reads the
+    /// response.
+    fn send(addr: SocketAddr, method: &str, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "{} {} HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n",
+            method, path
+        )
+        .unwrap();
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).unwrap();
+        resp
+    }
+
+    fn start(body: &'static str, shutdown_timeout: Duration) -> ServerHandle {
+        Server::builder()
+            .listen("127.0.0.1:0", Mode::Plaintext)
+            .threads(2)
+            .shutdown_timeout(shutdown_timeout)
+            .handler_func("GET /", move |_req, mut resp| {
+                resp.set_body(body);
+                resp
+            })
+            .unwrap()
+            .handler_func("GET /sleep/:ms{u64}", |req, resp| {
+                let ms = req.param_as("ms").unwrap();
+                thread::sleep(Duration::from_millis(ms));
+                resp
+            })
+            .unwrap()
+            .build()
+            .unwrap()
+            .start()
+            .unwrap()
+    }
+
+    #[test]
+    fn start_and_shutdown() {
+        let (a, b) = (start("a", SHUTDOWN_TIMEOUT), start("b", SHUTDOWN_TIMEOUT));
+        let (addr_a, addr_b) = (a.local_addr().unwrap(), b.local_addr().unwrap());
+        assert_ne!(addr_a.port(), 0);
+        assert_ne!(addr_a, addr_b);
+        assert!(get(addr_a, "/").ends_with("\r\n\r\na"));
+        assert!(get(addr_b, "/").ends_with("\r\n\r\nb"));
+
+        a.shutdown();
+        a.join().unwrap();
+        assert!(TcpStream::connect(addr_a).is_err());
+        assert!(get(addr_b, "/").starts_with("HTTP/1.1 200"));
+        b.shutdown();
+        b.join().unwrap();
+    }
+
+    #[test]
+    fn automatic_methods() {
+        let server = start("body", SHUTDOWN_TIMEOUT);
+        let addr = server.local_addr().unwrap();
+
+        let resp = send(addr, "DELETE", "/sleep/1");
+        assert!(resp.starts_with("HTTP/1.1 405"));
+        assert!(resp.contains("allow: GET, HEAD, OPTIONS\r\n"));
+
+        let resp = send(addr, "OPTIONS", "/");
+        assert!(resp.starts_with("HTTP/1.1 204"));
+        assert!(resp.contains("allow: GET, HEAD, OPTIONS\r\n"));
+
+        let resp = send(addr, "OPTIONS", "*");
+        assert!(resp.starts_with("HTTP/1.1 204"));
+        assert!(resp.contains("allow: GET, HEAD, OPTIONS\r\n"));
+
+        let resp = send(addr, "HEAD", "/");
+        assert!(resp.starts_with("HTTP/1.1 200"));
+        assert!(resp.contains("content-length: 4\r\n"));
+        assert!(resp.ends_with("\r\n\r\n"));
+
+        server.shutdown();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn explicit_head() {
+        let server = Server::builder()
+            .listen("127.0.0.1:0", Mode::Plaintext)
+            .threads(1)
+            .handler_func("HEAD /file", |_req, mut resp| {
+                resp.add_header("content-length", "1234");
+                resp
+            })
+            .unwrap()
+            .handler_func("HEAD /unknown", |_req, resp| resp)
+            .unwrap()
+            .handler_func("GET /unchanged", |_req, mut resp| {
+                resp.add_header(":status", "304");
+                resp
+            })
+            .unwrap()
+            .build()
+            .unwrap()
+            .start()
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+
+        let resp = send(addr, "HEAD", "/file");
+        assert!(resp.starts_with("HTTP/1.1 200"));
+        assert_eq!(resp.matches("content-length").count(), 1);
+        assert!(resp.contains("content-length: 1234\r\n"));
+        assert!(resp.ends_with("\r\n\r\n"));
+
+        let resp = send(addr, "HEAD", "/unknown");
+        assert!(resp.starts_with("HTTP/1.1 200"));
+        assert!(!resp.contains("content-length"));
+
+        let resp = send(addr, "HEAD", "/unchanged");
+        assert!(resp.starts_with("HTTP/1.1 304"));
+        assert!(!resp.contains("content-length"));
+
+        server.shutdown();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn mounted_relative_paths() {
+        fn relative_path(req: Request, mut resp: Response) -> Response {
+            resp.set_body(req.relative_path().to_string());
+            resp
+        }
+        let dir = std::env::temp_dir().join(format!("zws-mounted-{}", std::process::id()));
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("a.txt"), "file a").unwrap();
+        fs::write(dir.join("sub").join("b.txt"), "file b").unwrap();
+        let admin = Routes::new()
+            .handler_func("GET /", relative_path)
+            .unwrap()
+            .handler_func("GET /users/:id/*rest", relative_path)
+            .unwrap()
+            .mount(
+                "/deep",
+                Routes::new()
+                    .handler_func("GET /*rest", relative_path)
+                    .unwrap(),
+            )
+            .unwrap();
+        let files = Routes::new()
+            .handler_func("GET /*rest", relative_path)
+            .unwrap();
+        let webroot = Routes::new()
+            .handler(
+                "GET /*path",
+                crate::handlers::StaticFile::new(dir.to_str().unwrap()),
+            )
+            .unwrap();
+        let server = Server::builder()
+            .listen("127.0.0.1:0", Mode::Plaintext)
+            .threads(1)
+            .mount("/admin", admin)
+            .unwrap()
+            .mount("/files/:user{[a-z]+}", files)
+            .unwrap()
+            .mount("/static/", webroot)
+            .unwrap()
+            .build()
+            .unwrap()
+            .start()
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+
+        for (path, relative) in [
+            ("/admin", "/"),
+            ("/admin/", "/"),
+            ("/admin/users/7/x/y", "/users/7/x/y"),
+            ("/admin/deep/z", "/z"),
+            ("/admin/deep/", "/"),
+            ("/files/bob/notes.txt", "/notes.txt"),
+        ] {
+            let resp = get(addr, path);
+            assert!(resp.starts_with("HTTP/1.1 200"), "{}: {}", path, resp);
+            assert!(
+                resp.ends_with(&format!("\r\n\r\n{}", relative)),
+                "{}: {}",
+                path,
+                resp
+            );
+        }
+        assert!(get(addr, "/static/a.txt").ends_with("\r\n\r\nfile a"));
+        assert!(get(addr, "/static/sub/b.txt").ends_with("\r\n\r\nfile b"));
+
+        server.shutdown();
+        server.join().unwrap();
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn url_for_round_trip() {
+        let server = Server::builder()
+            .listen("127.0.0.1:0", Mode::Plaintext)
+            .threads(1)
+            .handler_func("GET /user/:name/:tag{[^0-9]+}/*rest", |req, mut resp| {
+                let body = format!(
+                    "{}|{}|{}",
+                    req.param("name"),
+                    req.param("tag"),
+                    req.param("rest")
+                );
+                resp.set_body(body);
+                resp
+            })
+            .unwrap()
+            .name("user")
+            .unwrap()
+            .build()
+            .unwrap();
+        let params = [("name", "José Q%"), ("tag", "a/b c"), ("rest", "x y/z")];
+        let url = server.url_for("user", &params).unwrap();
+        assert_eq!(url, "/user/Jos%C3%A9%20Q%25/a%2Fb%20c/x%20y/z");
+        assert!(server
+            .url_for("user", &[("name", "a"), ("tag", "7"), ("rest", "b")])
+            .is_err());
+
+        let server = server.start().unwrap();
+        let resp = get(server.local_addr().unwrap(), &url);
+        assert!(resp.ends_with("\r\n\r\nJosé Q%|a/b c|x y/z"));
+        server.shutdown();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn streamed_body() {
+        let server = Server::builder()
+            .listen("127.0.0.1:0", Mode::Plaintext)
+            .threads(1)
+            .handler_func("GET /", |_req, mut resp| {
+                resp.set_chunks((0..64u8).map(|i| {
+                    if i % 16 == 0 {
+                        thread::sleep(Duration::from_millis(10));
                     }
-                };
-                stream.set_full_data(response.body);
+                    vec![b'a' + i % 26; 16 << 10]
+                }));
+                resp
+            })
+            .unwrap()
+            .handler_func("GET /fail", |_req, mut resp| {
+                let fail = std::iter::from_fn(|| panic!("body failed"));
+                resp.set_chunks(std::iter::once(b"a".to_vec()).chain(fail));
+                resp
+            })
+            .unwrap()
+            .build()
+            .unwrap()
+            .start()
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        let expected: Vec<u8> = (0..64u8)
+            .flat_map(|i| vec![b'a' + i % 26; 16 << 10])
+            .collect();
+
+        let resp = get(addr, "/");
+        let (head, mut rest) = resp.split_at(resp.find("\r\n\r\n").unwrap() + 4);
+        assert!(head.contains("transfer-encoding: chunked\r\n"));
+        let mut body = Vec::new();
+        loop {
+            let (size, data) = rest.split_at(rest.find("\r\n").unwrap());
+            let size = usize::from_str_radix(size, 16).unwrap();
+            body.extend_from_slice(&data.as_bytes()[2..2 + size]);
+            rest = &data[2 + size + 2..];
+            if size == 0 {
+                break;
             }
+        }
+        assert_eq!(body, expected);
+        assert!(rest.is_empty());
 
-            loop {
-                match conn.send_next_data() {
-                    Ok(status) => {
-                        if status != SendStatus::Sent {
-                            break;
-                        }
-                    }
-                    Err(e) => {
-                        warn!("error sending next data: {}", e);
-                        break;
-                    }
-                }
+        // HTTP/1.0 clients get the body until the connection closes.
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET / HTTP/1.0\r\n\r\n").unwrap();
+        let mut resp = Vec::new();
+        stream.read_to_end(&mut resp).unwrap();
+        let head = find_head(&resp);
+        assert!(!resp[..head].windows(8).any(|w| w == b"chunked\r"));
+        assert_eq!(&resp[head..], &expected[..]);
+
+        let resp = send(addr, "HEAD", "/");
+        assert!(resp.starts_with("HTTP/1.1 200"));
+        assert!(resp.ends_with("transfer-encoding: chunked\r\nconnection: close\r\n\r\n"));
+
+        // A failed body closes the connection before its last chunk.
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET /fail HTTP/1.1\r\nhost: localhost\r\n\r\n").unwrap();
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).unwrap();
+        assert!(resp.ends_with("\r\n\r\n1\r\na\r\n"), "{}", resp);
+
+        server.shutdown();
+        server.join().unwrap();
+    }
+
+    /// find_head returns the length of the head of an HTTP/1.1 response.
+    fn find_head(resp: &[u8]) -> usize {
+        resp.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4
+    }
+
+    #[test]
+    fn timeouts() {
+        let server = Server::builder()
+            .listen("127.0.0.1:0", Mode::Plaintext)
+            .threads(1)
+            .read_timeout(Duration::from_millis(200))
+            .idle_timeout(Duration::from_millis(500))
+            .handler_func("GET /", |_req, resp| resp)
+            .unwrap()
+            .build()
+            .unwrap()
+            .start()
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        let started = Instant::now();
+
+        // A request head trickling in times out with the read timeout.
+        let mut slow = TcpStream::connect(addr).unwrap();
+        write!(slow, "GET / HTTP/1.1\r\n").unwrap();
+        let mut idle = TcpStream::connect(addr).unwrap();
+        write!(idle, "GET / HTTP/1.1\r\nhost: localhost\r\n\r\n").unwrap();
+        thread::sleep(Duration::from_millis(100));
+        write!(slow, "host: localhost\r\n").unwrap();
+        assert_eq!(slow.read(&mut [0; 1]).unwrap_or(0), 0);
+        let slow_closed = started.elapsed();
+        assert!(slow_closed < Duration::from_secs(3));
+
+        // A connection without requests times out with the idle timeout.
+        let mut resp = [0; 1024];
+        assert!(idle.read(&mut resp).unwrap() > 0);
+        assert_eq!(idle.read(&mut resp).unwrap_or(0), 0);
+        assert!(started.elapsed() >= Duration::from_millis(500));
+
+        server.shutdown();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn pipelined_requests() {
+        let server = Server::builder()
+            .listen("127.0.0.1:0", Mode::Plaintext)
+            .threads(2)
+            .concurrency(1)
+            .handler_func("GET /", |_req, mut resp| {
+                resp.set_body("ok");
+                resp
+            })
+            .unwrap()
+            .build()
+            .unwrap()
+            .start()
+            .unwrap();
+        let mut stream = TcpStream::connect(server.local_addr().unwrap()).unwrap();
+        let mut requests = "GET / HTTP/1.1\r\nhost: localhost\r\n\r\n".repeat(199);
+        requests.push_str("GET / HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n");
+        stream.write_all(requests.as_bytes()).unwrap();
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).unwrap();
+        assert_eq!(resp.matches("HTTP/1.1 200").count(), 200);
+
+        server.shutdown();
+        server.join().unwrap();
+    }
+
+    /// h2_requests sends GET requests for paths on one h2c connection, as
+    /// streams 1, 3 and so on, and waits for the end of their responses.
+    fn h2_requests(addr: SocketAddr, paths: &[&str]) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut out = crate::h2::PREFACE.to_vec();
+        out.extend_from_slice(&[0, 0, 0, 4, 0, 0, 0, 0, 0]);
+        let mut encoder = crate::h2::hpack::Encoder::new(4096);
+        for (i, path) in paths.iter().enumerate() {
+            let headers = [
+                (b":method".to_vec(), b"GET".to_vec()),
+                (b":scheme".to_vec(), b"http".to_vec()),
+                (b":path".to_vec(), path.as_bytes().to_vec()),
+                (b":authority".to_vec(), b"localhost".to_vec()),
+            ];
+            let mut block = Vec::new();
+            encoder.encode(&headers, &mut block);
+            // HEADERS with END_STREAM and END_HEADERS.
+            out.extend_from_slice(&(block.len() as u32).to_be_bytes()[1..]);
+            out.extend_from_slice(&[1, 0x5]);
+            out.extend_from_slice(&(2 * i as u32 + 1).to_be_bytes());
+            out.extend_from_slice(&block);
+        }
+        stream.write_all(&out).unwrap();
+
+        let mut ended = 0;
+        let mut head = [0; 9];
+        while ended < paths.len() {
+            stream.read_exact(&mut head).unwrap();
+            let len = u32::from_be_bytes([0, head[0], head[1], head[2]]) as usize;
+            stream.read_exact(&mut vec![0; len]).unwrap();
+            if head[3] <= 1 && head[4] & 0x1 != 0 {
+                ended += 1;
+            }
+        }
+    }
+
+    #[test]
+    fn concurrent_streams() {
+        for (concurrency, peak) in [(32, 2), (1, 1)] {
+            // state counts the handlers running, and the most that did at once.
+            let state = Arc::new((Mutex::new((0, 0)), std::sync::Condvar::new()));
+            let handler_state = Arc::clone(&state);
+            let server = Server::builder()
+                .listen("127.0.0.1:0", Mode::Plaintext)
+                .threads(2)
+                .concurrency(concurrency)
+                .handler_func("GET /wait", move |_req, resp| {
+                    let (lock, entered) = &*handler_state;
+                    let mut counts = lock.lock().unwrap();
+                    counts.0 += 1;
+                    counts.1 = counts.1.max(counts.0);
+                    entered.notify_all();
+                    // Wait for the other stream to enter too, if it can.
+                    let timeout = Duration::from_millis(300);
+                    let (mut counts, _) = entered
+                        .wait_timeout_while(counts, timeout, |counts| counts.1 < 2)
+                        .unwrap();
+                    counts.0 -= 1;
+                    resp
+                })
+                .unwrap()
+                .build()
+                .unwrap()
+                .start()
+                .unwrap();
+
+            h2_requests(server.local_addr().unwrap(), &["/wait", "/wait"]);
+            assert_eq!(
+                state.0.lock().unwrap().1,
+                peak,
+                "concurrency {}",
+                concurrency
+            );
+            server.shutdown();
+            server.join().unwrap();
+        }
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn async_handler_with_middleware() {
+        use crate::middleware::MiddlewareFunc;
+
+        let server = Server::builder()
+            .listen("127.0.0.1:0", Mode::Plaintext)
+            .threads(1)
+            .async_handler_func("GET /", |_req, mut resp| {
+                Box::pin(async move {
+                    resp.set_body("async");
+                    resp
+                })
+            })
+            .unwrap()
+            .wrap(MiddlewareFunc::new(|req, resp, next: Next| {
+                let mut resp = next.run(req, resp);
+                resp.add_header("x-wrapped", "1");
+                resp
+            }))
+            .unwrap()
+            .build()
+            .unwrap()
+            .start()
+            .unwrap();
+        let resp = get(server.local_addr().unwrap(), "/");
+        assert!(resp.contains("x-wrapped: 1\r\n"));
+        assert!(resp.ends_with("\r\n\r\nasync"));
+        server.shutdown();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn graceful_shutdown() {
+        let server = start("", SHUTDOWN_TIMEOUT);
+        let addr = server.local_addr().unwrap();
+        let mut busy = TcpStream::connect(addr).unwrap();
+        write!(busy, "GET /sleep/200 HTTP/1.1\r\nhost: localhost\r\n\r\n").unwrap();
+        let mut idle = TcpStream::connect(addr).unwrap();
+        let reset = TcpStream::connect(addr).unwrap();
+        write!(&reset, "GET /sleep/200 HTTP/1.1\r\nhost: localhost\r\n\r\n").unwrap();
+        thread::sleep(Duration::from_millis(50));
+
+        server.shutdown();
+        thread::sleep(Duration::from_millis(50));
+        // A client resetting its connection while draining aborts it.
+        #[cfg(unix)]
+        {
+            use std::os::unix::io::AsRawFd;
+            let linger = libc::linger {
+                l_onoff: 1,
+                l_linger: 0,
+            };
+            // SAFETY: linger outlives the call, which only reads it.
+            unsafe {
+                libc::setsockopt(
+                    reset.as_raw_fd(),
+                    libc::SOL_SOCKET,
+                    libc::SO_LINGER,
+                    &linger as *const libc::linger as *const libc::c_void,
+                    mem::size_of::<libc::linger>() as libc::socklen_t,
+                );
             }
-            let _ = conn.state.get_closed();
         }
+        drop(reset);
+        let mut resp = String::new();
+        busy.read_to_string(&mut resp).unwrap();
+        assert!(resp.starts_with("HTTP/1.1 200"));
+        assert!(resp.contains("connection: close"));
+        assert_eq!(idle.read(&mut [0; 1]).unwrap(), 0);
+        let report = server.join().unwrap();
+        #[cfg(unix)]
+        assert_eq!((report.drained, report.aborted), (2, 1));
+        #[cfg(not(unix))]
+        assert_eq!(report.drained, 2);
+    }
+
+    #[test]
+    fn shutdown_timeout() {
+        let server = start("", Duration::from_millis(100));
+        let addr = server.local_addr().unwrap();
+        let mut busy = TcpStream::connect(addr).unwrap();
+        write!(busy, "GET /sleep/5000 HTTP/1.1\r\nhost: localhost\r\n\r\n").unwrap();
+        thread::sleep(Duration::from_millis(50));
+
+        let started = Instant::now();
+        server.shutdown();
+        let report = server.join().unwrap();
+        assert_eq!((report.drained, report.aborted), (0, 1));
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert_eq!(busy.read(&mut [0; 1]).unwrap_or(0), 0);
+    }
+
+    /// sigterm runs Server::run in a child process, running this test again
+    /// with ZWS_TEST_SIGTERM set to the port to listen on, as SIGTERM stops
+    /// the whole process.
+    #[cfg(unix)]
+    #[test]
+    fn sigterm() {
+        use std::os::unix::net::UnixDatagram;
+        use std::process::{Command, Stdio};
+
+        if let Ok(port) = std::env::var("ZWS_TEST_SIGTERM") {
+            Server::builder()
+                .listen(&format!("127.0.0.1:{}", port), Mode::Plaintext)
+                .threads(2)
+                .handler_func("GET /sleep/:ms{u64}", |req, resp| {
+                    thread::sleep(Duration::from_millis(req.param_as("ms").unwrap()));
+                    resp
+                })
+                .unwrap()
+                .build()
+                .unwrap()
+                .run()
+                .unwrap();
+            return;
+        }
+
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let path = std::env::temp_dir().join(format!("zws-sigterm-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let manager = UnixDatagram::bind(&path).unwrap();
+        manager
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        let child = Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "server::tests::sigterm", "--nocapture"])
+            .env("ZWS_TEST_SIGTERM", addr.port().to_string())
+            .env("NOTIFY_SOCKET", &path)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut state = [0; 64];
+        let n = manager.recv(&mut state).unwrap();
+        assert!(state[..n].starts_with(b"READY=1"));
+
+        let mut busy = TcpStream::connect(addr).unwrap();
+        write!(busy, "GET /sleep/200 HTTP/1.1\r\nhost: localhost\r\n\r\n").unwrap();
+        let mut h2 = TcpStream::connect(addr).unwrap();
+        h2.write_all(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n\0\0\0\x04\0\0\0\0\0")
+            .unwrap();
+        thread::sleep(Duration::from_millis(50));
+
+        // SAFETY: kill only sends the signal to the child.
+        unsafe {
+            libc::kill(child.id() as libc::pid_t, libc::SIGTERM);
+        }
+        let mut resp = String::new();
+        busy.read_to_string(&mut resp).unwrap();
+        assert!(resp.starts_with("HTTP/1.1 200"));
+        let mut frames = Vec::new();
+        h2.read_to_end(&mut frames).unwrap();
+        let mut types = Vec::new();
+        let mut rest = &frames[..];
+        while rest.len() >= 9 {
+            let len = (rest[0] as usize) << 16 | (rest[1] as usize) << 8 | rest[2] as usize;
+            types.push(rest[3]);
+            rest = &rest[(9 + len).min(rest.len())..];
+        }
+        assert!(types.contains(&7), "no GOAWAY in frame types {:?}", types);
+        let n = manager.recv(&mut state).unwrap();
+        assert_eq!(&state[..n], b"STOPPING=1");
+
+        let output = child.wait_with_output().unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    #[test]
+    fn reload_tls() {
+        let (cert, key) = tls::tests::self_signed("reload", &["one.test"]);
+        let server = Server::builder()
+            .tls(&cert, &key)
+            .listen("127.0.0.1:0", Mode::Tls)
+            .reload_tls(true)
+            .threads(2)
+            .handler_func("GET /", |_req, resp| resp)
+            .unwrap()
+            .build()
+            .unwrap()
+            .start()
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        let connect = || tls::tests::connect(TcpStream::connect(addr).unwrap(), "one.test");
+        let mut open = connect();
+        assert_eq!(tls::tests::presented(&open), "one.test");
+
+        // Invalid files keep the certificate in use.
+        fs::write(&cert, "not a certificate").unwrap();
+        thread::sleep(Duration::from_secs(3));
+        assert_eq!(tls::tests::presented(&connect()), "one.test");
+
+        let (new_cert, new_key) = tls::tests::self_signed("reload-new", &["two.test"]);
+        fs::rename(new_key, &key).unwrap();
+        fs::rename(new_cert, &cert).unwrap();
+        let started = Instant::now();
+        while tls::tests::presented(&connect()) != "two.test" {
+            assert!(started.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        // Connections open before keep going.
+        write!(
+            open,
+            "GET / HTTP/1.1\r\nhost: one.test\r\nconnection: close\r\n\r\n"
+        )
+        .unwrap();
+        let mut resp = String::new();
+        open.read_to_string(&mut resp).unwrap();
+        assert!(resp.starts_with("HTTP/1.1 200"));
+        server.shutdown();
+        server.join().unwrap();
     }
 }
@@ -0,0 +1,208 @@
+use std::io::{self, Read};
+use std::mem;
+use std::os::raw::c_int;
+use std::os::unix::io::{IntoRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::ptr;
+use std::sync::atomic::{AtomicI32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use std::thread;
+
+/// Callback is run on a signal. It is shared to run outside the lock on the
+/// Handlers, so that it may itself add or remove callbacks.
+type Callback = Arc<dyn Fn() + Send + Sync>;
+
+/// PIPE is the writing end of the pipe the signal handler writes the
+/// signal number to, as a signal handler can do little else safely.
+static PIPE: AtomicI32 = AtomicI32::new(-1);
+
+/// Handlers holds the callbacks of each signal, run in order, and the
+/// actions the signals had before the first callback.
+struct Handlers {
+    callbacks: Vec<(u64, c_int, Callback)>,
+    previous: Vec<(c_int, libc::sigaction)>,
+}
+
+static HANDLERS: Mutex<Handlers> = Mutex::new(Handlers {
+    callbacks: Vec::new(),
+    previous: Vec::new(),
+});
+
+/// NEXT_ID numbers the callbacks, for their Guard to remove them.
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// INIT is the result of init, kept for every caller of on to see it fail.
+static INIT: OnceLock<Result<(), (io::ErrorKind, String)>> = OnceLock::new();
+
+/// Guard removes its callback when dropped, restoring the previous action
+/// of the signal once it has no callback left.
+pub(crate) struct Guard {
+    id: u64,
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        let mut handlers = handlers();
+        let signal = match handlers
+            .callbacks
+            .iter()
+            .position(|(id, ..)| *id == self.id)
+        {
+            Some(i) => handlers.callbacks.remove(i).1,
+            None => return,
+        };
+        if handlers.callbacks.iter().any(|(_, s, _)| *s == signal) {
+            return;
+        }
+        if let Some(i) = handlers.previous.iter().position(|(s, _)| *s == signal) {
+            let (_, action) = handlers.previous.remove(i);
+            // SAFETY: action is the one sigaction returned for signal.
+            unsafe {
+                libc::sigaction(signal, &action, ptr::null_mut());
+            }
+        }
+    }
+}
+
+/// handlers locks the Handlers, even if a callback panicked.
+fn handlers() -> MutexGuard<'static, Handlers> {
+    HANDLERS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// on runs callback on a thread of its own whenever the process receives
+/// signal, replacing its default action, until the returned Guard drops.
+pub(crate) fn on<F>(signal: c_int, callback: F) -> io::Result<Guard>
+where
+    F: Fn() + Send + Sync + 'static,
+{
+    if let Err((kind, e)) = INIT.get_or_init(|| init().map_err(|e| (e.kind(), e.to_string()))) {
+        return Err(io::Error::new(*kind, e.clone()));
+    }
+
+    let mut handlers = handlers();
+    if !handlers.callbacks.iter().any(|(_, s, _)| *s == signal) {
+        // SAFETY: handle only writes to the pipe, which is async-signal-safe.
+        unsafe {
+            let mut action: libc::sigaction = mem::zeroed();
+            action.sa_sigaction = handle as *const () as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+            let mut previous: libc::sigaction = mem::zeroed();
+            if libc::sigaction(signal, &action, &mut previous) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            handlers.previous.push((signal, previous));
+        }
+    }
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    handlers.callbacks.push((id, signal, Arc::new(callback)));
+    Ok(Guard { id })
+}
+
+/// init creates the pipe and the thread running the callbacks. Writing to
+/// the pipe doesn't block, so a signal arriving while it is full, as when a
+/// callback is slow, is dropped rather than stall the interrupted thread.
+fn init() -> io::Result<()> {
+    let (mut reader, writer) = UnixStream::pair()?;
+    writer.set_nonblocking(true)?;
+    thread::Builder::new()
+        .name("zws-signal".to_string())
+        .spawn(move || {
+            let mut signal = [0u8];
+            while reader.read_exact(&mut signal).is_ok() {
+                debug!("received signal {}", signal[0]);
+                let callbacks: Vec<Callback> = handlers()
+                    .callbacks
+                    .iter()
+                    .filter(|(_, s, _)| *s == c_int::from(signal[0]))
+                    .map(|(_, _, callback)| Arc::clone(callback))
+                    .collect();
+                for callback in callbacks {
+                    callback();
+                }
+            }
+        })?;
+    PIPE.store(writer.into_raw_fd(), Ordering::SeqCst);
+    Ok(())
+}
+
+extern "C" fn handle(signal: c_int) {
+    let fd: RawFd = PIPE.load(Ordering::SeqCst);
+    if fd >= 0 {
+        let byte = signal as u8;
+        // SAFETY: write is async-signal-safe, and byte outlives the call.
+        unsafe {
+            libc::write(fd, &byte as *const u8 as *const libc::c_void, 1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    /// action returns the current action of signal.
+    fn action(signal: c_int) -> libc::sighandler_t {
+        // SAFETY: sigaction only fills in old.
+        unsafe {
+            let mut old: libc::sigaction = mem::zeroed();
+            libc::sigaction(signal, ptr::null(), &mut old);
+            old.sa_sigaction
+        }
+    }
+
+    /// wait_for waits up to a second for done to be true.
+    fn wait_for(done: impl Fn() -> bool) -> bool {
+        for _ in 0..100 {
+            if done() {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        done()
+    }
+
+    #[test]
+    fn callbacks_until_dropped() {
+        let count = Arc::new(AtomicU64::new(0));
+        let counted = Arc::clone(&count);
+        let first = on(libc::SIGUSR1, move || {
+            counted.fetch_add(1, Ordering::SeqCst);
+        })
+        .unwrap();
+        let second = on(libc::SIGUSR1, || {}).unwrap();
+
+        // SAFETY: SIGUSR1 has a handler.
+        unsafe {
+            libc::raise(libc::SIGUSR1);
+        }
+        assert!(wait_for(|| count.load(Ordering::SeqCst) == 1));
+
+        drop(first);
+        assert_eq!(
+            action(libc::SIGUSR1),
+            handle as *const () as libc::sighandler_t
+        );
+        drop(second);
+        assert_eq!(action(libc::SIGUSR1), libc::SIG_DFL);
+
+        // A callback may drop its own Guard.
+        let guard = Arc::new(Mutex::new(None));
+        let (own, counted) = (Arc::clone(&guard), Arc::clone(&count));
+        *guard.lock().unwrap() = Some(
+            on(libc::SIGUSR1, move || {
+                counted.fetch_add(1, Ordering::SeqCst);
+                drop(own.lock().unwrap().take());
+            })
+            .unwrap(),
+        );
+        // SAFETY: SIGUSR1 has a handler.
+        unsafe {
+            libc::raise(libc::SIGUSR1);
+        }
+        assert!(wait_for(|| action(libc::SIGUSR1) == libc::SIG_DFL));
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,143 @@
+use std::env;
+use std::ffi::OsStr;
+use std::io;
+use std::mem::ManuallyDrop;
+use std::net::TcpListener;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::{UnixDatagram, UnixListener};
+use std::process;
+use std::time::Duration;
+
+use crate::listener::UNIX_PREFIX;
+
+/// LISTEN_FDS_START is the first socket passed by systemd, see
+/// sd_listen_fds(3).
+const LISTEN_FDS_START: RawFd = 3;
+
+/// listen_fds returns the sockets systemd passed to the process with
+/// LISTEN_FDS, by address.
+pub(crate) fn listen_fds() -> Vec<(String, RawFd)> {
+    let pid = env::var("LISTEN_PID").ok().and_then(|pid| pid.parse().ok());
+    if pid != Some(process::id()) {
+        return Vec::new();
+    }
+    let n: RawFd = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(0);
+    (LISTEN_FDS_START..LISTEN_FDS_START + n)
+        .filter_map(|fd| {
+            // SAFETY: fd is one of the sockets systemd passed.
+            unsafe {
+                libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+            }
+            match address(fd) {
+                Some(addr) => Some((addr, fd)),
+                None => {
+                    warn!("ignoring socket {} from systemd: not TCP or Unix", fd);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// address returns the local address of a listening socket, in the form
+/// given to Builder::listen.
+fn address(fd: RawFd) -> Option<String> {
+    // SAFETY: the listeners only borrow fd, which they don't close.
+    let tcp = ManuallyDrop::new(unsafe { TcpListener::from_raw_fd(fd) });
+    if let Ok(addr) = tcp.local_addr() {
+        return Some(addr.to_string());
+    }
+    let unix = ManuallyDrop::new(unsafe { UnixListener::from_raw_fd(fd) });
+    let addr = unix.local_addr().ok()?;
+    Some(format!("{}{}", UNIX_PREFIX, addr.as_pathname()?.display()))
+}
+
+/// notify sends state to the service manager over NOTIFY_SOCKET, if it
+/// asked for it, see sd_notify(3).
+pub(crate) fn notify(state: &str) {
+    if let Some(path) = env::var_os("NOTIFY_SOCKET") {
+        if let Err(e) = send(&path, state) {
+            warn!("error notifying systemd of {:?}: {}", state, e);
+        }
+    }
+}
+
+/// send sends state to the notify socket at path, which is in the abstract
+/// namespace if it starts with @.
+fn send(path: &OsStr, state: &str) -> io::Result<()> {
+    let socket = UnixDatagram::unbound()?;
+    #[cfg(target_os = "linux")]
+    {
+        if let Some(name) = path.as_bytes().strip_prefix(b"@") {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)?;
+            return Ok(());
+        }
+    }
+    socket.send_to(state.as_bytes(), path)?;
+    Ok(())
+}
+
+/// watchdog returns how often to send WATCHDOG=1: twice per interval the
+/// service manager expects one, if it does.
+pub(crate) fn watchdog() -> Option<Duration> {
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse() != Ok(process::id()) {
+            return None;
+        }
+    }
+    let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    Some(Duration::from_micros(usec / 2)).filter(|interval| !interval.is_zero())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::io::AsRawFd;
+
+    #[test]
+    fn notify_socket() {
+        let path = env::temp_dir().join(format!("zws-notify-{}.sock", process::id()));
+        let _ = std::fs::remove_file(&path);
+        let manager = UnixDatagram::bind(&path).unwrap();
+        send(path.as_os_str(), "READY=1").unwrap();
+        let mut buf = [0; 64];
+        let n = manager.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1");
+        std::fs::remove_file(path).unwrap();
+
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::linux::net::SocketAddrExt;
+            let name = format!("zws-notify-{}", process::id());
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(&name).unwrap();
+            let manager = UnixDatagram::bind_addr(&addr).unwrap();
+            send(OsStr::new(&format!("@{}", name)), "WATCHDOG=1").unwrap();
+            let n = manager.recv(&mut buf).unwrap();
+            assert_eq!(&buf[..n], b"WATCHDOG=1");
+        }
+    }
+
+    #[test]
+    fn socket_addresses() {
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = tcp.local_addr().unwrap().to_string();
+        assert_eq!(address(tcp.as_raw_fd()), Some(addr));
+
+        let path = env::temp_dir().join(format!("zws-activated-{}.sock", process::id()));
+        let _ = std::fs::remove_file(&path);
+        let unix = UnixListener::bind(&path).unwrap();
+        let addr = format!("unix:{}", path.display());
+        assert_eq!(address(unix.as_raw_fd()), Some(addr));
+        std::fs::remove_file(path).unwrap();
+
+        // the sockets are still open
+        assert!(tcp.local_addr().is_ok());
+        assert!(unix.local_addr().is_ok());
+    }
+}
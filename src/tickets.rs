@@ -0,0 +1,258 @@
+use std::fmt;
+use std::os::raw::{c_char, c_int, c_uchar, c_void};
+use std::ptr;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use openssl::error::ErrorStack;
+use openssl::ex_data::Index;
+use openssl::rand::rand_bytes;
+use openssl::ssl::{SslContext, SslContextBuilder};
+use openssl_sys as ffi;
+
+/// Callback is the session ticket key callback of
+/// SSL_CTX_set_tlsext_ticket_key_evp_cb(3).
+type Callback = unsafe extern "C" fn(
+    *mut ffi::SSL,
+    *mut c_uchar,
+    *mut c_uchar,
+    *mut ffi::EVP_CIPHER_CTX,
+    *mut ffi::EVP_MAC_CTX,
+    c_int,
+) -> c_int;
+
+// OpenSSL 3 functions openssl-sys doesn't bind.
+extern "C" {
+    fn SSL_CTX_set_tlsext_ticket_key_evp_cb(
+        ctx: *mut ffi::SSL_CTX,
+        callback: Option<Callback>,
+    ) -> c_int;
+    fn OSSL_PARAM_construct_utf8_string(
+        key: *const c_char,
+        buf: *mut c_char,
+        bsize: usize,
+    ) -> ffi::OSSL_PARAM;
+}
+
+/// NAME_LEN is the length of the name identifying the key of a ticket.
+const NAME_LEN: usize = 16;
+
+/// IV_LEN is the length of the AES-256-CBC initialization vector.
+const IV_LEN: usize = 16;
+
+/// Key encrypts and authenticates session tickets.
+struct Key {
+    name: [u8; NAME_LEN],
+    aes: [u8; 32],
+    hmac: [u8; 32],
+    created: Instant,
+}
+
+impl Key {
+    fn new() -> Result<Key, ErrorStack> {
+        let mut key = Key {
+            name: [0; NAME_LEN],
+            aes: [0; 32],
+            hmac: [0; 32],
+            created: Instant::now(),
+        };
+        rand_bytes(&mut key.name)?;
+        rand_bytes(&mut key.aes)?;
+        rand_bytes(&mut key.hmac)?;
+        Ok(key)
+    }
+}
+
+/// Keys are the session ticket keys shared by the contexts of a Server,
+/// and kept across reloads. A new key is made every rotation, and tickets
+/// of the previous one are still accepted, then renewed, so they last from
+/// one to two rotations.
+pub(crate) struct Keys {
+    rotation: Duration,
+    /// keys holds the current key first, then the previous one.
+    keys: Mutex<Vec<Key>>,
+}
+
+impl Keys {
+    pub(crate) fn new(rotation: Duration) -> Arc<Keys> {
+        Arc::new(Keys {
+            rotation,
+            keys: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// with_keys runs f on the keys, rotating them first if due.
+    fn with_keys<T>(&self, f: impl FnOnce(&[Key]) -> T) -> Result<T, ErrorStack> {
+        let mut keys = self.keys.lock().unwrap_or_else(|e| e.into_inner());
+        if keys
+            .first()
+            .is_none_or(|key| key.created.elapsed() >= self.rotation)
+        {
+            keys.insert(0, Key::new()?);
+            keys.truncate(2);
+        }
+        let expired = |key: &Key| key.created.elapsed() >= self.rotation * 2;
+        if keys.last().is_some_and(expired) {
+            keys.pop();
+        }
+        Ok(f(&keys))
+    }
+}
+
+impl fmt::Debug for Keys {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Keys")
+            .field("rotation", &self.rotation)
+            .finish()
+    }
+}
+
+/// index is the ex_data slot of SslContext holding the Keys.
+fn index() -> Result<Index<SslContext, Arc<Keys>>, ErrorStack> {
+    static INDEX: OnceLock<Index<SslContext, Arc<Keys>>> = OnceLock::new();
+    if let Some(index) = INDEX.get() {
+        return Ok(*index);
+    }
+    let index = SslContext::new_ex_index()?;
+    Ok(*INDEX.get_or_init(|| index))
+}
+
+/// enable makes context issue and accept session tickets encrypted with
+/// keys, instead of a key of its own, which would be lost on reload and
+/// never rotated.
+pub(crate) fn enable(context: &mut SslContextBuilder, keys: &Arc<Keys>) -> Result<(), ErrorStack> {
+    context.set_ex_data(index()?, Arc::clone(keys));
+    // SAFETY: ticket_key takes the arguments OpenSSL calls back with.
+    if unsafe { SSL_CTX_set_tlsext_ticket_key_evp_cb(context.as_ptr(), Some(ticket_key)) } != 1 {
+        return Err(ErrorStack::get());
+    }
+    Ok(())
+}
+
+/// ticket_key sets up cipher and hmac to encrypt a new ticket with the
+/// current key, naming it in name, or with enc 0, to decrypt a ticket with
+/// the key named name. It returns 1 on success, 2 to renew a ticket of the
+/// previous key, 0 for an unknown key and -1 on errors, see
+/// SSL_CTX_set_tlsext_ticket_key_evp_cb(3).
+unsafe extern "C" fn ticket_key(
+    ssl: *mut ffi::SSL,
+    name: *mut c_uchar,
+    iv: *mut c_uchar,
+    cipher: *mut ffi::EVP_CIPHER_CTX,
+    hmac: *mut ffi::EVP_MAC_CTX,
+    enc: c_int,
+) -> c_int {
+    let context = ffi::SSL_get_SSL_CTX(ssl);
+    let index = match index() {
+        Ok(index) => index,
+        Err(_) => return -1,
+    };
+    let keys = ffi::SSL_CTX_get_ex_data(context, index.as_raw()) as *const Arc<Keys>;
+    if keys.is_null() {
+        return -1;
+    }
+    let name = &mut *(name as *mut [u8; NAME_LEN]);
+    let result = (*keys).with_keys(|keys| {
+        let (i, key) = if enc == 1 {
+            let key = &keys[0];
+            *name = key.name;
+            if ffi::RAND_bytes(iv, IV_LEN as c_int) != 1 {
+                return -1;
+            }
+            (0, key)
+        } else {
+            match keys.iter().enumerate().find(|(_, key)| key.name == *name) {
+                Some(found) => found,
+                None => return 0,
+            }
+        };
+        let init = if enc == 1 {
+            ffi::EVP_EncryptInit_ex
+        } else {
+            ffi::EVP_DecryptInit_ex
+        };
+        let aes = ffi::EVP_aes_256_cbc();
+        let mut digest = *b"SHA256\0";
+        let params = [
+            ffi::OSSL_PARAM_construct_octet_string(
+                b"key\0".as_ptr().cast(),
+                key.hmac.as_ptr() as *mut c_void,
+                key.hmac.len(),
+            ),
+            OSSL_PARAM_construct_utf8_string(
+                b"digest\0".as_ptr().cast(),
+                digest.as_mut_ptr().cast(),
+                0,
+            ),
+            ffi::OSSL_PARAM_construct_end(),
+        ];
+        if init(cipher, aes, ptr::null_mut(), key.aes.as_ptr(), iv) != 1
+            || ffi::EVP_MAC_CTX_set_params(hmac, params.as_ptr()) != 1
+        {
+            return -1;
+        }
+        if i == 0 {
+            1
+        } else {
+            2
+        }
+    });
+    result.unwrap_or(-1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotation() {
+        let keys = Keys::new(Duration::from_millis(100));
+        let first = keys.with_keys(|keys| keys[0].name).unwrap();
+        assert_eq!(keys.with_keys(|keys| keys[0].name).unwrap(), first);
+
+        std::thread::sleep(Duration::from_millis(120));
+        let names = keys.with_keys(|keys| keys.iter().map(|key| key.name).collect::<Vec<_>>());
+        let names = names.unwrap();
+        assert_eq!(names.len(), 2);
+        assert_ne!(names[0], first);
+        assert_eq!(names[1], first);
+
+        // Keys older than two rotations are dropped.
+        std::thread::sleep(Duration::from_millis(250));
+        assert_eq!(keys.with_keys(|keys| keys.len()).unwrap(), 1);
+    }
+
+    #[test]
+    fn ticket_key() {
+        let keys = Keys::new(Duration::from_secs(3600));
+        let mut context = SslContext::builder(openssl::ssl::SslMethod::tls()).unwrap();
+        enable(&mut context, &keys).unwrap();
+        // SAFETY: the SSL and contexts are freed once done with, and the
+        // buffers have the lengths OpenSSL expects.
+        unsafe {
+            let ssl = ffi::SSL_new(context.as_ptr());
+            let cipher = ffi::EVP_CIPHER_CTX_new();
+            let mac = ffi::EVP_MAC_fetch(ptr::null_mut(), b"HMAC\0".as_ptr().cast(), ptr::null());
+            let hmac = ffi::EVP_MAC_CTX_new(mac);
+            let mut iv = [0; IV_LEN];
+            let mut call = |mut name: [u8; NAME_LEN], enc| {
+                let result =
+                    super::ticket_key(ssl, name.as_mut_ptr(), iv.as_mut_ptr(), cipher, hmac, enc);
+                (result, name)
+            };
+
+            let (result, name) = call([0; NAME_LEN], 1);
+            assert_eq!(result, 1);
+            assert_eq!(call(name, 0).0, 1);
+            // Tickets of the previous key are renewed.
+            keys.keys.lock().unwrap().insert(0, Key::new().unwrap());
+            assert_eq!(call(name, 0).0, 2);
+            assert_eq!(call([0; NAME_LEN], 0).0, 0);
+
+            ffi::EVP_MAC_CTX_free(hmac);
+            ffi::EVP_MAC_free(mac);
+            ffi::EVP_CIPHER_CTX_free(cipher);
+            ffi::SSL_free(ssl);
+        }
+    }
+}
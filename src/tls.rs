@@ -1,43 +1,733 @@
-use std::io;
-use std::net::TcpStream;
+use std::convert::TryFrom;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::{Ipv4Addr, Ipv6Addr};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use std::{env, thread};
 
-use openssl::ssl::{ShutdownResult, SslStream};
-use solicit::http::transport::TransportStream;
+use notify::{DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::ssl::{
+    select_next_proto, AlpnError, NameType, SniError, SslAcceptor, SslAcceptorBuilder, SslContext,
+    SslFiletype, SslMethod, SslOptions, SslRef, SslVerifyMode, SslVersion,
+};
+use openssl::x509::{GeneralNameRef, X509Name, X509NameRef, X509Ref, X509VerifyResult, X509};
 
-/// Wrapper is a newtype to implement solicit's TransportStream for an SslStream<TcpStream>.
-pub struct Wrapper(pub Arc<Mutex<SslStream<TcpStream>>>);
+use crate::error::Result;
+use crate::request::{ClientCert, TlsInfo};
+use crate::tickets::{self, Keys};
 
-// io::Write for Wrapper
-impl io::Write for Wrapper {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.0.lock().unwrap().write(buf)
+/// PROTOCOLS are the ALPN protocols served, in order of preference.
+const PROTOCOLS: &[u8] = b"\x02h2\x08http/1.1";
+
+/// WATCH_DELAY is how long file events are collected before acting on them,
+/// as certificates and keys are often replaced one after the other.
+const WATCH_DELAY: Duration = Duration::from_secs(1);
+
+/// SESSION_ID_CONTEXT tells apart the sessions of zws from those of other
+/// servers, as needed to resume sessions with client certificates.
+const SESSION_ID_CONTEXT: &[u8] = b"zws";
+
+/// TICKET_KEY_ROTATION is how often session ticket keys are replaced by
+/// default.
+const TICKET_KEY_ROTATION: Duration = Duration::from_secs(60 * 60);
+
+/// KeyLog is the file TLS secrets are logged to, shared by the contexts.
+type KeyLog = Arc<Mutex<File>>;
+
+/// TlsProfile is the base set of TLS versions and ciphers, after Mozilla's
+/// server side TLS recommendations (version 5), that the versions, ciphers
+/// and curves given to the Server Builder adjust.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TlsProfile {
+    /// Modern allows TLS 1.3 only.
+    Modern,
+    /// Intermediate allows TLS 1.2 with forward secret AEAD ciphers, and
+    /// TLS 1.3.
+    #[default]
+    Intermediate,
+    /// Custom starts from Intermediate with TLS 1.0 and 1.1 allowed and the
+    /// default OpenSSL ciphers and TLS 1.3 cipher suites, to be restricted
+    /// with the other options. HTTP/2 clients may refuse ciphers RFC 7540
+    /// forbids.
+    Custom,
+}
+
+/// TlsVersion is a version of the TLS protocol.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TlsVersion {
+    /// Tls1_0 is TLS 1.0, RFC 2246.
+    Tls1_0,
+    /// Tls1_1 is TLS 1.1, RFC 4346.
+    Tls1_1,
+    /// Tls1_2 is TLS 1.2, RFC 5246.
+    Tls1_2,
+    /// Tls1_3 is TLS 1.3, RFC 8446.
+    Tls1_3,
+}
+
+impl From<TlsVersion> for SslVersion {
+    fn from(version: TlsVersion) -> SslVersion {
+        match version {
+            TlsVersion::Tls1_0 => SslVersion::TLS1,
+            TlsVersion::Tls1_1 => SslVersion::TLS1_1,
+            TlsVersion::Tls1_2 => SslVersion::TLS1_2,
+            TlsVersion::Tls1_3 => SslVersion::TLS1_3,
+        }
+    }
+}
+
+/// ClientVerify is whether TLS clients are asked for a certificate, which
+/// is verified against the client CAs given to the Server Builder.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ClientVerify {
+    /// None doesn't ask clients for a certificate.
+    #[default]
+    None,
+    /// Optional asks for a certificate, but accepts clients without one.
+    Optional,
+    /// Required refuses clients without a valid certificate.
+    Required,
+}
+
+/// Config is the TLS configuration of a Server.
+#[derive(Clone, Debug)]
+pub(crate) struct Config {
+    /// certs are the certificate chain and key files, the first the default.
+    pub(crate) certs: Vec<(String, String)>,
+    /// client_ca is the file of the CAs client certificates are verified
+    /// against.
+    pub(crate) client_ca: Option<String>,
+    pub(crate) client_verify: ClientVerify,
+    pub(crate) profile: TlsProfile,
+    pub(crate) min_version: Option<TlsVersion>,
+    pub(crate) max_version: Option<TlsVersion>,
+    /// ciphers is the OpenSSL cipher list for TLS 1.2 and below.
+    pub(crate) ciphers: Option<String>,
+    /// ciphersuites is the OpenSSL list of TLS 1.3 cipher suites.
+    pub(crate) ciphersuites: Option<String>,
+    /// curves is the OpenSSL list of key exchange groups.
+    pub(crate) curves: Option<String>,
+    /// ticket_keys encrypt session tickets, None to disable them.
+    pub(crate) ticket_keys: Option<Arc<Keys>>,
+    /// key_log is the file TLS secrets are appended to, in the NSS key log
+    /// format read by Wireshark.
+    pub(crate) key_log: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            certs: Vec::new(),
+            client_ca: None,
+            client_verify: ClientVerify::None,
+            profile: TlsProfile::Intermediate,
+            min_version: None,
+            max_version: None,
+            ciphers: None,
+            ciphersuites: None,
+            curves: None,
+            ticket_keys: Some(Keys::new(TICKET_KEY_ROTATION)),
+            key_log: None,
+        }
+    }
+}
+
+/// acceptor creates a TLS acceptor with the configured certificates and
+/// keys, negotiating h2, or else http/1.1, with ALPN. The certificate
+/// presented is the first one for the server name the client asks for with
+/// SNI, or else the first one.
+pub(crate) fn acceptor(config: &Config) -> Result<SslAcceptor> {
+    let key_log = match &config.key_log {
+        Some(path) => {
+            // Only the owner may read the secrets of a new file.
+            let mut options = OpenOptions::new();
+            options.create(true).append(true);
+            #[cfg(unix)]
+            options.mode(0o600);
+            Some(Arc::new(Mutex::new(options.open(path)?)))
+        }
+        None => None,
+    };
+    let (cert, key) = &config.certs[0];
+    let mut acceptor = builder(cert, key, config, &key_log)?;
+    let default = names(cert)?;
+    let mut contexts: Vec<(Vec<String>, SslContext)> = Vec::new();
+    for (cert, key) in &config.certs[1..] {
+        let context = builder(cert, key, config, &key_log)?.build().into_context();
+        contexts.push((names(cert)?, context));
+    }
+
+    if !contexts.is_empty() {
+        acceptor.set_servername_callback(move |ssl, _| {
+            let name = match ssl.servername(NameType::HOST_NAME) {
+                Some(name) => name.to_string(),
+                None => return Ok(()),
+            };
+            if default.iter().any(|n| matches(n, &name)) {
+                return Ok(());
+            }
+            let found = contexts
+                .iter()
+                .find(|(names, _)| names.iter().any(|n| matches(n, &name)));
+            match found {
+                Some((_, context)) => ssl
+                    .set_ssl_context(context)
+                    .map_err(|_| SniError::ALERT_FATAL),
+                None => Ok(()),
+            }
+        });
+    }
+    Ok(acceptor.build())
+}
+
+/// info describes the TLS session of ssl, once the handshake is done.
+pub(crate) fn info(ssl: &SslRef) -> TlsInfo {
+    let client_cert = ssl
+        .peer_certificate()
+        .filter(|_| ssl.verify_result() == X509VerifyResult::OK)
+        .map(|cert| client_cert(&cert));
+    TlsInfo {
+        server_name: ssl.servername(NameType::HOST_NAME).map(str::to_string),
+        client_cert,
+    }
+}
+
+/// client_cert describes a verified client certificate.
+fn client_cert(cert: &X509Ref) -> ClientCert {
+    let common_name = cert
+        .subject_name()
+        .entries_by_nid(Nid::COMMONNAME)
+        .find_map(|cn| cn.data().to_string().ok());
+    let sans = match cert.subject_alt_names() {
+        Some(sans) => sans.iter().filter_map(general_name).collect(),
+        None => Vec::new(),
+    };
+    let fingerprint = match cert.digest(MessageDigest::sha256()) {
+        Ok(digest) => digest.iter().map(|b| format!("{:02x}", b)).collect(),
+        Err(_) => String::new(),
+    };
+    ClientCert {
+        subject: distinguished_name(cert.subject_name()),
+        common_name,
+        sans,
+        fingerprint,
+    }
+}
+
+/// distinguished_name formats name as its attributes in order, as in
+/// "O=Example, CN=alice".
+fn distinguished_name(name: &X509NameRef) -> String {
+    let attributes: Vec<String> = name
+        .entries()
+        .map(|entry| {
+            let object = entry.object();
+            let key = match object.nid().short_name() {
+                Ok(key) => key.to_string(),
+                Err(_) => object.to_string(),
+            };
+            let value = entry.data().to_string().unwrap_or_default();
+            format!("{}={}", key, value)
+        })
+        .collect();
+    attributes.join(", ")
+}
+
+/// general_name formats a subject alternative name with its type, as in
+/// "DNS:example.com", "email:alice@example.com", "URI:spiffe://example.com/a"
+/// or "IP:10.0.0.1". It is None for other types.
+fn general_name(name: &GeneralNameRef) -> Option<String> {
+    if let Some(dns) = name.dnsname() {
+        return Some(format!("DNS:{}", dns));
+    }
+    if let Some(email) = name.email() {
+        return Some(format!("email:{}", email));
+    }
+    if let Some(uri) = name.uri() {
+        return Some(format!("URI:{}", uri));
+    }
+    let ip = name.ipaddress()?;
+    if let Ok(v4) = <[u8; 4]>::try_from(ip) {
+        return Some(format!("IP:{}", Ipv4Addr::from(v4)));
+    }
+    let v6 = <[u8; 16]>::try_from(ip).ok()?;
+    Some(format!("IP:{}", Ipv6Addr::from(v6)))
+}
+
+/// Watch watches the certificate and key files until dropped, which stops
+/// its thread.
+pub(crate) struct Watch {
+    _watcher: RecommendedWatcher,
+    stop: Arc<AtomicBool>,
+}
+
+impl Drop for Watch {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+}
+
+/// watch calls changed on a thread of its own whenever any of the
+/// certificate or key files change, until it returns false or the returned
+/// Watch is dropped. The directories of the files are watched, so files
+/// replaced by a rename are noticed.
+pub(crate) fn watch<F>(config: &Config, mut changed: F) -> notify::Result<Watch>
+where
+    F: FnMut() -> bool + Send + 'static,
+{
+    let cwd = env::current_dir()?;
+    let files: Vec<PathBuf> = config
+        .certs
+        .iter()
+        .flat_map(|(cert, key)| vec![cwd.join(cert), cwd.join(key)])
+        .chain(config.client_ca.iter().map(|ca| cwd.join(ca)))
+        .collect();
+    let (tx, rx) = mpsc::channel();
+    let mut watcher: RecommendedWatcher = Watcher::new(tx, WATCH_DELAY)?;
+    let mut dirs: Vec<PathBuf> = files
+        .iter()
+        .filter_map(|file| file.parent().map(PathBuf::from))
+        .collect();
+    dirs.sort();
+    dirs.dedup();
+    for dir in &dirs {
+        debug!("watch: watching certificates in {}", dir.display());
+        watcher.watch(dir, RecursiveMode::NonRecursive)?;
+    }
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let stopped = Arc::clone(&stop);
+    thread::Builder::new()
+        .name("zws-tls-watch".to_string())
+        .spawn(move || {
+            while !stopped.load(Ordering::SeqCst) {
+                let relevant = match rx.recv_timeout(WATCH_DELAY) {
+                    Ok(DebouncedEvent::Create(path))
+                    | Ok(DebouncedEvent::Write(path))
+                    | Ok(DebouncedEvent::Chmod(path))
+                    | Ok(DebouncedEvent::Rename(_, path)) => files.contains(&path),
+                    Ok(DebouncedEvent::Rescan) => true,
+                    Ok(DebouncedEvent::Error(e, _)) => {
+                        warn!("error watching certificates: {}", e);
+                        false
+                    }
+                    Ok(_) | Err(RecvTimeoutError::Timeout) => false,
+                    Err(RecvTimeoutError::Disconnected) => return,
+                };
+                if !relevant {
+                    continue;
+                }
+                // Wait for the other files replaced with this one.
+                while rx.recv_timeout(WATCH_DELAY).is_ok() {}
+                debug!("watch: certificates changed");
+                if stopped.load(Ordering::SeqCst) || !changed() {
+                    return;
+                }
+            }
+        })?;
+    Ok(Watch {
+        _watcher: watcher,
+        stop,
+    })
+}
+
+/// builder prepares an acceptor with a certificate chain and key, and the
+/// rest of config.
+fn builder(
+    cert: &str,
+    key: &str,
+    config: &Config,
+    key_log: &Option<KeyLog>,
+) -> Result<SslAcceptorBuilder> {
+    let mut acceptor = match config.profile {
+        TlsProfile::Modern => SslAcceptor::mozilla_modern_v5(SslMethod::tls())?,
+        TlsProfile::Intermediate => SslAcceptor::mozilla_intermediate_v5(SslMethod::tls())?,
+        TlsProfile::Custom => {
+            let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls())?;
+            acceptor.clear_options(SslOptions::NO_TLSV1 | SslOptions::NO_TLSV1_1);
+            acceptor.set_cipher_list("DEFAULT")?;
+            acceptor.set_ciphersuites(
+                "TLS_AES_256_GCM_SHA384:TLS_CHACHA20_POLY1305_SHA256:TLS_AES_128_GCM_SHA256",
+            )?;
+            acceptor
+        }
+    };
+    if let Some(version) = config.min_version {
+        acceptor.set_min_proto_version(Some(version.into()))?;
+    }
+    if let Some(version) = config.max_version {
+        acceptor.set_max_proto_version(Some(version.into()))?;
+    }
+    if let Some(ciphers) = &config.ciphers {
+        acceptor.set_cipher_list(ciphers)?;
     }
-    fn flush(&mut self) -> io::Result<()> {
-        self.0.lock().unwrap().flush()
+    if let Some(ciphersuites) = &config.ciphersuites {
+        acceptor.set_ciphersuites(ciphersuites)?;
+    }
+    if let Some(curves) = &config.curves {
+        acceptor.set_groups_list(curves)?;
+    }
+    match &config.ticket_keys {
+        Some(keys) => tickets::enable(&mut acceptor, keys)?,
+        None => {
+            acceptor.set_options(SslOptions::NO_TICKET);
+            acceptor.set_num_tickets(0)?;
+        }
     }
+    if let Some(key_log) = key_log {
+        let key_log = Arc::clone(key_log);
+        acceptor.set_keylog_callback(move |_, line| {
+            let mut file = key_log.lock().unwrap_or_else(|e| e.into_inner());
+            if let Err(e) = writeln!(file, "{}", line) {
+                warn!("error writing the TLS key log: {}", e);
+            }
+        });
+    }
+    acceptor.set_session_id_context(SESSION_ID_CONTEXT)?;
+
+    acceptor.set_private_key_file(key, SslFiletype::PEM)?;
+    acceptor.set_certificate_chain_file(cert)?;
+    acceptor.check_private_key()?;
+    acceptor.set_alpn_select_callback(|_, protos| {
+        select_next_proto(PROTOCOLS, protos).ok_or(AlpnError::NOACK)
+    });
+
+    let mode = match config.client_verify {
+        ClientVerify::None => return Ok(acceptor),
+        ClientVerify::Optional => SslVerifyMode::PEER,
+        ClientVerify::Required => SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT,
+    };
+    let ca = match &config.client_ca {
+        Some(ca) => ca,
+        None => {
+            let e = "verifying client certificates takes a client CA file";
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, e).into());
+        }
+    };
+    acceptor.set_ca_file(ca)?;
+    acceptor.set_client_ca_list(X509Name::load_client_ca_file(ca)?);
+    acceptor.set_verify(mode);
+    Ok(acceptor)
 }
 
-// io::Read for Wrapper
-impl io::Read for Wrapper {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        self.0.lock().unwrap().read(buf)
+/// names lists the DNS names of the certificate in the PEM file cert: its
+/// subject alternative names, or without any, its common name.
+fn names(cert: &str) -> Result<Vec<String>> {
+    let cert = X509::from_pem(&fs::read(cert)?)?;
+    let mut names: Vec<String> = match cert.subject_alt_names() {
+        Some(sans) => sans
+            .iter()
+            .filter_map(|san| san.dnsname().map(str::to_string))
+            .collect(),
+        None => Vec::new(),
+    };
+    if names.is_empty() {
+        names = cert
+            .subject_name()
+            .entries_by_nid(Nid::COMMONNAME)
+            .filter_map(|cn| cn.data().to_string().ok())
+            .collect();
     }
+    Ok(names)
 }
 
-// solicit::http::transport::TransportStream
-impl TransportStream for Wrapper {
-    fn try_split(&self) -> io::Result<Wrapper> {
-        Ok(Wrapper(self.0.clone()))
+/// matches tells if a certificate name, which may start with a wildcard
+/// label, matches a server name.
+fn matches(pattern: &str, name: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(suffix) => match name.find('.') {
+            Some(dot) => dot > 0 && name[dot + 1..].eq_ignore_ascii_case(suffix),
+            None => false,
+        },
+        None => pattern.eq_ignore_ascii_case(name),
     }
+}
 
-    fn close(&mut self) -> io::Result<()> {
-        loop {
-            match self.0.lock().unwrap().shutdown() {
-                Ok(ShutdownResult::Received) => return Ok(()),
-                Err(e) => return Err(io::Error::new(io::ErrorKind::Other, e)),
-                _ => continue,
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::fmt;
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixStream;
+
+    use openssl::asn1::Asn1Time;
+    use openssl::pkey::{PKey, Private};
+    use openssl::rsa::Rsa;
+    use openssl::ssl::{SslConnector, SslConnectorBuilder, SslSession, SslStream};
+    use openssl::x509::extension::{BasicConstraints, SubjectAlternativeName};
+    use openssl::x509::X509NameBuilder;
+
+    /// certificate creates a certificate for names and its key, issued by
+    /// issuer, or else self-signed as a CA.
+    fn certificate(
+        names: &[&str],
+        issuer: Option<&(X509, PKey<Private>)>,
+    ) -> (X509, PKey<Private>) {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut subject = X509NameBuilder::new().unwrap();
+        subject
+            .append_entry_by_nid(Nid::COMMONNAME, names[0])
+            .unwrap();
+        let subject = subject.build();
+        let mut cert = X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_subject_name(&subject).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        let mut san = SubjectAlternativeName::new();
+        for name in names {
+            san.dns(name);
+        }
+        let san = match issuer {
+            Some((issuer, _)) => san.build(&cert.x509v3_context(Some(issuer), None)),
+            None => san.build(&cert.x509v3_context(None, None)),
+        };
+        cert.append_extension(san.unwrap()).unwrap();
+        match issuer {
+            Some((issuer, issuer_key)) => {
+                cert.set_issuer_name(issuer.subject_name()).unwrap();
+                cert.sign(issuer_key, MessageDigest::sha256()).unwrap();
+            }
+            None => {
+                cert.set_issuer_name(&subject).unwrap();
+                let ca = BasicConstraints::new().critical().ca().build().unwrap();
+                cert.append_extension(ca).unwrap();
+                cert.sign(&key, MessageDigest::sha256()).unwrap();
             }
         }
+        (cert.build(), key)
+    }
+
+    /// write writes a certificate and its key to PEM files in the temporary
+    /// directory, returning their paths.
+    fn write(file: &str, (cert, key): &(X509, PKey<Private>)) -> (String, String) {
+        let dir = std::env::temp_dir().join(format!("zws-tls-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = |ext: &str| -> PathBuf { dir.join(format!("{}.{}", file, ext)) };
+        fs::write(path("crt"), cert.to_pem().unwrap()).unwrap();
+        fs::write(path("key"), key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        let path = |ext| path(ext).to_string_lossy().into_owned();
+        (path("crt"), path("key"))
+    }
+
+    /// self_signed writes a self-signed certificate for names and its key to
+    /// PEM files in the temporary directory, returning their paths.
+    pub(crate) fn self_signed(file: &str, names: &[&str]) -> (String, String) {
+        write(file, &certificate(names, None))
+    }
+
+    /// connect starts a TLS session over stream asking for server_name,
+    /// without verifying the certificate.
+    pub(crate) fn connect<S>(stream: S, server_name: &str) -> SslStream<S>
+    where
+        S: Read + Write + fmt::Debug,
+    {
+        let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+        connector.set_verify(SslVerifyMode::NONE);
+        connector.build().connect(server_name, stream).unwrap()
+    }
+
+    /// presented returns the common name of the certificate the server
+    /// presented.
+    pub(crate) fn presented<S>(stream: &SslStream<S>) -> String {
+        let cert = stream.ssl().peer_certificate().unwrap();
+        let cn = cert.subject_name().entries_by_nid(Nid::COMMONNAME).next();
+        cn.unwrap().data().to_string().unwrap()
+    }
+
+    /// handshake connects to acceptor asking for server_name, returning the
+    /// common name of the certificate presented.
+    fn handshake(acceptor: SslAcceptor, server_name: &str) -> String {
+        let (client, server) = UnixStream::pair().unwrap();
+        let server = thread::spawn(move || acceptor.accept(server).map(|_| ()));
+        let stream = connect(client, server_name);
+        server.join().unwrap().unwrap();
+        presented(&stream)
+    }
+
+    #[test]
+    fn server_names() {
+        assert!(matches("example.com", "EXAMPLE.com"));
+        assert!(matches("*.example.com", "www.example.com"));
+        assert!(!matches("*.example.com", "example.com"));
+        assert!(!matches("*.example.com", "a.www.example.com"));
+        assert!(!matches("*.example.com", ".example.com"));
+
+        let certs = vec![
+            self_signed("default", &["default.test"]),
+            self_signed("a", &["a.test", "*.a.test"]),
+            self_signed("b", &["b.test"]),
+        ];
+        assert_eq!(names(&certs[1].0).unwrap(), ["a.test", "*.a.test"]);
+        let config = Config {
+            certs,
+            ..Config::default()
+        };
+        let acceptor = || acceptor(&config).unwrap();
+        assert_eq!(handshake(acceptor(), "www.a.test"), "a.test");
+        assert_eq!(handshake(acceptor(), "b.test"), "b.test");
+        assert_eq!(handshake(acceptor(), "unknown.test"), "default.test");
+    }
+
+    /// accept runs a handshake with acceptor, the client presenting cert if
+    /// any, returning the TlsInfo of the server, or None if it failed.
+    fn accept(acceptor: SslAcceptor, cert: Option<&(X509, PKey<Private>)>) -> Option<TlsInfo> {
+        let (client, server) = UnixStream::pair().unwrap();
+        let server = thread::spawn(move || acceptor.accept(server).ok().map(|s| info(s.ssl())));
+        let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+        connector.set_verify(SslVerifyMode::NONE);
+        if let Some((cert, key)) = cert {
+            connector.set_certificate(cert).unwrap();
+            connector.set_private_key(key).unwrap();
+        }
+        let _client = connector.build().connect("mtls.test", client);
+        server.join().unwrap()
+    }
+
+    #[test]
+    fn client_certificates() {
+        let ca = certificate(&["Test CA"], None);
+        let alice = certificate(&["alice", "alice.test"], Some(&ca));
+        let mallory = certificate(&["mallory"], None);
+        let mut config = Config {
+            certs: vec![self_signed("mtls", &["mtls.test"])],
+            client_ca: Some(write("client-ca", &ca).0),
+            client_verify: ClientVerify::Required,
+            ..Config::default()
+        };
+
+        let info = accept(acceptor(&config).unwrap(), Some(&alice)).unwrap();
+        let cert = info.client_cert.unwrap();
+        assert_eq!(cert.subject, "CN=alice");
+        assert_eq!(cert.common_name.as_deref(), Some("alice"));
+        assert_eq!(cert.sans, ["DNS:alice", "DNS:alice.test"]);
+        let digest = alice.0.digest(MessageDigest::sha256()).unwrap();
+        let hex: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+        assert_eq!(cert.fingerprint, hex);
+        assert!(accept(acceptor(&config).unwrap(), None).is_none());
+        assert!(accept(acceptor(&config).unwrap(), Some(&mallory)).is_none());
+
+        config.client_verify = ClientVerify::Optional;
+        let info = accept(acceptor(&config).unwrap(), None).unwrap();
+        assert_eq!(info.client_cert, None);
+        assert!(accept(acceptor(&config).unwrap(), Some(&mallory)).is_none());
+
+        config.client_verify = ClientVerify::None;
+        let info = accept(acceptor(&config).unwrap(), Some(&alice)).unwrap();
+        assert_eq!(info.client_cert, None);
+
+        config.client_verify = ClientVerify::Required;
+        config.client_ca = None;
+        assert!(acceptor(&config).is_err());
+    }
+
+    /// resume runs a handshake with acceptor from a client set up by
+    /// client, resuming session if any. It returns the client stream, or
+    /// None if either side failed.
+    fn resume<F>(
+        acceptor: SslAcceptor,
+        session: Option<&SslSession>,
+        client: F,
+    ) -> Option<SslStream<UnixStream>>
+    where
+        F: FnOnce(&mut SslConnectorBuilder),
+    {
+        let (client_end, server_end) = UnixStream::pair().unwrap();
+        let server = thread::spawn(move || acceptor.accept(server_end).is_ok());
+        let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+        connector.set_verify(SslVerifyMode::NONE);
+        client(&mut connector);
+        let mut configuration = connector.build().configure().unwrap();
+        if let Some(session) = session {
+            // SAFETY: the session comes from a connector of the same kind.
+            unsafe { configuration.set_session(session).unwrap() };
+        }
+        let stream = configuration.connect("policy.test", client_end).ok();
+        let accepted = server.join().unwrap();
+        stream.filter(|_| accepted)
+    }
+
+    #[test]
+    fn tls_policy() {
+        let certs = vec![self_signed("policy", &["policy.test"])];
+        let mut config = Config {
+            certs,
+            ..Config::default()
+        };
+        let tls1_2 = |c: &mut SslConnectorBuilder| {
+            c.set_max_proto_version(Some(SslVersion::TLS1_2)).unwrap()
+        };
+
+        let stream = resume(acceptor(&config).unwrap(), None, |_| {}).unwrap();
+        assert_eq!(stream.ssl().version_str(), "TLSv1.3");
+        config.profile = TlsProfile::Modern;
+        assert!(resume(acceptor(&config).unwrap(), None, tls1_2).is_none());
+
+        config.profile = TlsProfile::Intermediate;
+        config.max_version = Some(TlsVersion::Tls1_2);
+        config.ciphers = Some("ECDHE-RSA-AES256-GCM-SHA384".to_string());
+        let stream = resume(acceptor(&config).unwrap(), None, |_| {}).unwrap();
+        assert_eq!(stream.ssl().version_str(), "TLSv1.2");
+        let cipher = stream.ssl().current_cipher().unwrap().name();
+        assert_eq!(cipher, "ECDHE-RSA-AES256-GCM-SHA384");
+
+        config.curves = Some("P-384".to_string());
+        let x25519 = |c: &mut SslConnectorBuilder| c.set_groups_list("X25519").unwrap();
+        assert!(resume(acceptor(&config).unwrap(), None, x25519).is_none());
+        config.curves = None;
+
+        // Ticket keys outlive the acceptor, as on reload.
+        let stream = resume(acceptor(&config).unwrap(), None, |_| {}).unwrap();
+        let session = stream.ssl().session().unwrap().to_owned();
+        let stream = resume(acceptor(&config).unwrap(), Some(&session), |_| {}).unwrap();
+        assert!(stream.ssl().session_reused());
+        config.ticket_keys = Some(Keys::new(TICKET_KEY_ROTATION));
+        let stream = resume(acceptor(&config).unwrap(), Some(&session), |_| {}).unwrap();
+        assert!(!stream.ssl().session_reused());
+
+        let key_log = std::env::temp_dir().join(format!("zws-keylog-{}", std::process::id()));
+        let _ = fs::remove_file(&key_log);
+        config.key_log = Some(key_log.to_string_lossy().into_owned());
+        resume(acceptor(&config).unwrap(), None, |_| {}).unwrap();
+        let logged = fs::read_to_string(&key_log).unwrap();
+        assert!(logged.starts_with("CLIENT_RANDOM "));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&key_log).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        fs::remove_file(key_log).unwrap();
+    }
+
+    #[test]
+    fn watch_stops() {
+        let (cert, key) = self_signed("watch", &["watch.test"]);
+        let config = Config {
+            certs: vec![(cert, key)],
+            ..Config::default()
+        };
+        let (tx, rx) = mpsc::channel();
+        let watch = watch(&config, move || tx.send(()).is_ok()).unwrap();
+        assert_eq!(
+            rx.recv_timeout(Duration::from_millis(100)),
+            Err(RecvTimeoutError::Timeout)
+        );
+        // The thread drops changed, and its Sender, once it stops.
+        drop(watch);
+        assert_eq!(
+            rx.recv_timeout(WATCH_DELAY * 5),
+            Err(RecvTimeoutError::Disconnected)
+        );
     }
 }
@@ -0,0 +1,151 @@
+use std::collections::HashMap;
+use std::env;
+use std::ffi::OsString;
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use std::sync::Mutex;
+use std::thread;
+
+use crate::systemd;
+
+/// LISTEN_FDS is the environment variable handing the listening sockets to
+/// the new process, as addr=fd pairs separated by semicolons.
+const LISTEN_FDS: &str = "ZWS_LISTEN_FDS";
+
+/// READY_FD is the environment variable with the socket the new process
+/// writes to once it accepts connections.
+const READY_FD: &str = "ZWS_READY_FD";
+
+/// Inherited holds what the process inherited from the one it upgrades,
+/// or the sockets systemd passed to it.
+struct Inherited {
+    /// listeners are the listening sockets by address, taken once adopted.
+    listeners: HashMap<String, RawFd>,
+    ready: Option<RawFd>,
+}
+
+static INHERITED: Mutex<Option<Inherited>> = Mutex::new(None);
+
+/// with_inherited runs f on what the process inherited, parsing the
+/// environment the first time.
+fn with_inherited<T>(f: impl FnOnce(&mut Inherited) -> T) -> T {
+    let mut inherited = INHERITED.lock().unwrap();
+    let inherited = inherited.get_or_insert_with(|| Inherited {
+        listeners: parse_listen_fds(&env::var(LISTEN_FDS).unwrap_or_default())
+            .into_iter()
+            .chain(systemd::listen_fds())
+            .collect(),
+        ready: env::var(READY_FD).ok().and_then(|fd| fd.parse().ok()),
+    });
+    f(inherited)
+}
+
+/// parse_listen_fds parses the value of LISTEN_FDS.
+fn parse_listen_fds(value: &str) -> HashMap<String, RawFd> {
+    value
+        .split(';')
+        .filter_map(|pair| {
+            let (addr, fd) = pair.split_at(pair.rfind('=')?);
+            Some((addr.to_string(), fd[1..].parse().ok()?))
+        })
+        .collect()
+}
+
+/// inherited takes the listening socket for addr the process inherited, if
+/// any, so it is adopted only once.
+pub(crate) fn inherited(addr: &str) -> Option<RawFd> {
+    with_inherited(|inherited| inherited.listeners.remove(addr))
+}
+
+/// unused lists the addresses of the sockets inherited but not adopted.
+pub(crate) fn unused() -> Vec<String> {
+    with_inherited(|inherited| inherited.listeners.keys().cloned().collect())
+}
+
+/// ready tells the process being upgraded that this one accepts
+/// connections, so it can shut down.
+pub(crate) fn ready() {
+    if let Some(fd) = with_inherited(|inherited| inherited.ready.take()) {
+        // SAFETY: the fd was handed over for this alone.
+        let mut ready = unsafe { UnixStream::from_raw_fd(fd) };
+        if let Err(e) = ready.write_all(b"1") {
+            warn!("error telling the old process to shut down: {}", e);
+        }
+    }
+}
+
+/// spawn starts a new process running the same executable with the same
+/// arguments, handing it listeners, pairs of addresses and sockets. Once
+/// it accepts connections, or failed to start, done is called with whether
+/// it succeeded.
+pub(crate) fn spawn<F>(listeners: Vec<(String, RawFd)>, done: F) -> io::Result<()>
+where
+    F: FnOnce(bool) + Send + 'static,
+{
+    let mut args = env::args_os();
+    let program = args.next().unwrap_or_else(|| OsString::from("zws"));
+    let (mut ready, child_end) = UnixStream::pair()?;
+    let child_ready = child_end.as_raw_fd();
+
+    let fds: Vec<RawFd> = listeners.iter().map(|(_, fd)| *fd).collect();
+    let listen_fds: Vec<String> = listeners
+        .iter()
+        .map(|(addr, fd)| format!("{}={}", addr, fd))
+        .collect();
+    let mut command = Command::new(&program);
+    command
+        .args(args)
+        .env(LISTEN_FDS, listen_fds.join(";"))
+        .env(READY_FD, child_ready.to_string());
+    // SAFETY: fcntl is async-signal-safe.
+    unsafe {
+        command.pre_exec(move || {
+            for &fd in fds.iter().chain(Some(&child_ready)) {
+                if libc::fcntl(fd, libc::F_SETFD, 0) == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+    let mut child = command.spawn()?;
+    // Only the child keeps its end open, so the read below ends if it exits.
+    drop(child_end);
+    info!(
+        "Started {} with pid {}, waiting for it to accept connections.",
+        program.to_string_lossy(),
+        child.id()
+    );
+
+    thread::Builder::new()
+        .name("zws-upgrade".to_string())
+        .spawn(move || {
+            let mut byte = [0u8];
+            // The read fails once the child exits or closes the socket.
+            let ok = ready.read_exact(&mut byte).is_ok();
+            if !ok {
+                let status = child.try_wait().ok().flatten();
+                error!("new process failed to start: {:?}", status);
+            }
+            done(ok);
+        })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listen_fds() {
+        let fds = parse_listen_fds("127.0.0.1:8443=3;[::]:80=4;unix:/tmp/a=b.sock=5;bad=x;");
+        assert_eq!(fds.len(), 3);
+        assert_eq!(fds["127.0.0.1:8443"], 3);
+        assert_eq!(fds["[::]:80"], 4);
+        assert_eq!(fds["unix:/tmp/a=b.sock"], 5);
+        assert!(parse_listen_fds("").is_empty());
+    }
+}
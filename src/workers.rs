@@ -1,3 +1,4 @@
+use std::sync::{Arc, Mutex, MutexGuard};
 use std::thread;
 
 use crossbeam::{channel, Receiver, Sender};
@@ -19,9 +20,40 @@ struct Worker {
     handle: Option<thread::JoinHandle<()>>,
 }
 
+/// Shared is the state of a Pool its workers need to respawn themselves.
+struct Shared {
+    receiver: Receiver<Job>,
+    workers: Mutex<Vec<Worker>>,
+}
+
+impl Shared {
+    fn workers(&self) -> MutexGuard<'_, Vec<Worker>> {
+        self.workers.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Sentinel respawns its worker thread when dropped by a panic, so queued
+/// jobs keep running even if every worker dies.
+struct Sentinel {
+    id: usize,
+    shared: Arc<Shared>,
+}
+
+impl Drop for Sentinel {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            warn!("worker {} died, respawning it", self.id);
+            let worker = Worker::new(self.id, Arc::clone(&self.shared));
+            self.shared.workers()[self.id] = worker;
+        }
+    }
+}
+
 impl Worker {
-    fn new(id: usize, receiver: Receiver<Job>) -> Worker {
+    fn new(id: usize, shared: Arc<Shared>) -> Worker {
         let handle = thread::spawn(move || {
+            let receiver = shared.receiver.clone();
+            let _sentinel = Sentinel { id, shared };
             for job in receiver.iter() {
                 debug!("worker {} executing job", id);
                 job.call_box();
@@ -37,22 +69,28 @@ impl Worker {
 
 pub struct Pool {
     sender: Option<Sender<Job>>,
-    workers: Vec<Worker>,
+    shared: Arc<Shared>,
 }
 
 impl Pool {
+    /// new starts a Pool of size worker threads, at least 1.
     pub fn new(size: usize) -> Pool {
+        let size = size.max(1);
         let (sender, receiver) = channel::unbounded();
-        let mut workers = Vec::with_capacity(size);
+        let shared = Arc::new(Shared {
+            receiver,
+            workers: Mutex::new(Vec::with_capacity(size)),
+        });
 
         for id in 0..size {
-            workers.push(Worker::new(id, receiver.clone()));
+            let worker = Worker::new(id, Arc::clone(&shared));
+            shared.workers().push(worker);
         }
 
         debug!("Created thread pool with {} worker threads", size);
         Pool {
             sender: Some(sender),
-            workers: workers,
+            shared,
         }
     }
 
@@ -62,17 +100,96 @@ impl Pool {
     {
         self.sender.as_ref().unwrap().send(Box::new(f)).unwrap();
     }
+
+    /// detach drops the Pool without waiting for the jobs being run. Their
+    /// workers stop once they are done.
+    pub fn detach(self) {
+        for worker in self.shared.workers().iter_mut() {
+            worker.handle.take();
+        }
+    }
 }
 
 impl Drop for Pool {
     fn drop(&mut self) {
         debug!("Pool shutting down...");
         drop(self.sender.take().unwrap());
-        for worker in &mut self.workers {
-            if let Some(handle) = worker.handle.take() {
-                debug!("Waiting for worker {} to stop...", worker.id);
-                handle.join().unwrap();
+        // Join without holding the lock, which a dying worker takes to
+        // respawn itself, and until no worker is left.
+        loop {
+            let next = self
+                .shared
+                .workers()
+                .iter_mut()
+                .find_map(|worker| Some((worker.id, worker.handle.take()?)));
+            match next {
+                Some((id, handle)) => {
+                    debug!("Waiting for worker {} to stop...", id);
+                    if handle.join().is_err() {
+                        warn!("worker {} had panicked", id);
+                    }
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn respawns_dead_workers() {
+        let pool = Pool::new(2);
+        for _ in 0..2 {
+            pool.execute(|| panic!("boom"));
+        }
+        thread::sleep(Duration::from_millis(100));
+
+        let done = Arc::new(AtomicUsize::new(0));
+        for _ in 0..4 {
+            let done = Arc::clone(&done);
+            pool.execute(move || {
+                done.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        drop(pool);
+        assert_eq!(done.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn has_at_least_one_worker() {
+        let pool = Pool::new(0);
+        let done = Arc::new(AtomicUsize::new(0));
+        let job = Arc::clone(&done);
+        pool.execute(move || {
+            job.fetch_add(1, Ordering::SeqCst);
+        });
+        drop(pool);
+        assert_eq!(done.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn runs_queued_jobs_after_all_workers_die() {
+        let pool = Pool::new(1);
+        let done = Arc::new(AtomicUsize::new(0));
+        pool.execute(|| panic!("boom"));
+        let queued = Arc::clone(&done);
+        pool.execute(move || {
+            queued.fetch_add(1, Ordering::SeqCst);
+        });
+
+        for _ in 0..100 {
+            if done.load(Ordering::SeqCst) == 1 {
+                break;
             }
+            thread::sleep(Duration::from_millis(10));
         }
+        assert_eq!(done.load(Ordering::SeqCst), 1);
     }
 }